@@ -2,7 +2,7 @@
 extern crate std;
 
 macro_rules! print {
-    ($($arg:tt)*) => (let _ = ::core::fmt::write(&mut $crate::Printer, format_args!($($arg)*)););
+    ($($arg:tt)*) => ({ let _ = ::core::fmt::write(&mut ::std::io::stdout(), format_args!($($arg)*)); });
 }
 
 pub fn main() {
@@ -12,19 +12,32 @@ pub fn main() {
 
     print!("Hello from userland rust!\n");
 
-    for seconds in 0..=5 {
-        print!("{} seconds\n", seconds);
-        kernel_uapi::syscall::sleep_ms(1000, None);
+    if let Ok(entries) = std::fs::read_dir("/") {
+        let mut names: std::vec::Vec<std::string::String> = entries
+            .flatten()
+            .map(|entry| {
+                let suffix = if entry.is_dir() { "/" } else { "" };
+                std::format!("/{}{}", entry.name(), suffix)
+            })
+            .collect();
+        names.sort();
+        for name in names {
+            print!("{}\n", name);
+        }
     }
-}
-
-struct Printer;
 
-impl core::fmt::Write for Printer {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for c in s.as_bytes() {
-            kernel_uapi::syscall::put_char(*c, None);
+    match std::fs::File::open("/hello.txt") {
+        Ok(mut hello) => {
+            let mut buf = [0u8; 256];
+            let n = hello.read_exact_or_eof(&mut buf).unwrap_or(0);
+            let contents = core::str::from_utf8(&buf[..n]).unwrap_or("<invalid utf-8>");
+            print!("/hello.txt: {}\n", contents.trim_end());
         }
-        Ok(())
+        Err(e) => print!("Could not open /hello.txt: {:?}\n", e),
+    }
+
+    for seconds in 0..=5 {
+        print!("{} seconds\n", seconds);
+        kernel_uapi::syscall::sleep_ms(1000, None);
     }
 }
@@ -0,0 +1,15 @@
+//! Entry types of the auxiliary vector a program finds on its stack at startup, after `envp`.
+//! Each entry is a pair of `u64`s, a type and a value, and the vector ends with `AT_NULL`.
+
+/// Marks the end of the vector
+pub const AT_NULL: u64 = 0;
+/// Address of the program headers in memory
+pub const AT_PHDR: u64 = 3;
+/// Size of one program header
+pub const AT_PHENT: u64 = 4;
+/// Number of program headers
+pub const AT_PHNUM: u64 = 5;
+/// Size of a page in bytes
+pub const AT_PAGESZ: u64 = 6;
+/// Entry point of the program
+pub const AT_ENTRY: u64 = 9;
@@ -1,5 +1,6 @@
 #![no_std]
 
+pub mod auxv;
 pub mod syscall;
 
 #[cfg(feature = "panic_handler")]
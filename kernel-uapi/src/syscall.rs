@@ -10,6 +10,8 @@ cfg_if::cfg_if! {
                     inout("rsi") r.as_mut_ptr() => _,
                     out("rcx") _,
                     out("r11") _,
+                    // The kernel follows the C calling convention, vector registers included
+                    clobber_abi("C"),
                 };
                 r.assume_init()
             }
@@ -33,6 +35,23 @@ macro_rules! syscall_define {
             ),*}
         ),*}
 
+        impl Syscall {
+            /// Number of syscalls, one past the largest valid discriminant.
+            pub const COUNT: u32 = [$(stringify!($name)),*].len() as u32;
+
+            /// Reinterprets bytes copied from an untrusted caller as a `Syscall`. Every argument
+            /// is a plain integer or pointer, so only the discriminant needs checking.
+            pub fn from_bytes(bytes: [u8; core::mem::size_of::<Syscall>()]) -> Option<Syscall> {
+                let discriminant = u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                if discriminant >= Self::COUNT {
+                    return None;
+                }
+                Some(unsafe {
+                    core::mem::transmute::<[u8; core::mem::size_of::<Syscall>()], Syscall>(bytes)
+                })
+            }
+        }
+
         #[repr(C)]
         #[allow(non_camel_case_types)]
         pub union SyscallResultInner {$(
@@ -65,7 +84,7 @@ macro_rules! syscall_define {
         $(
             $(#[doc = $docs])*
             #[no_mangle]
-            pub extern "C" fn $name($($(#[doc = $field_docs])* $arg: $t,)* out: Option<&mut core::mem::MaybeUninit<$return>>) -> SyscallErrorCode {
+            pub extern "C" fn $name($($arg: $t,)* out: Option<&mut core::mem::MaybeUninit<$return>>) -> SyscallErrorCode {
                 match raw_syscall(Syscall::$name{$($arg),*}) {
                     SyscallResult::Ok(v) => unsafe {out.map(|out| out.write(v.$name)); SyscallErrorCode::Ok},
                     SyscallResult::Err(e) => e
@@ -80,16 +99,227 @@ syscall_define! {
     /// Print out "Ping!" to the console screen
     pub extern "C" fn ping() -> ();
     pub extern "C" fn put_char(c: u8) -> ();
+    /// Blocks until the next scancode arrives. The keyboard must be in `KBD_MODE_RAW`.
     pub extern "C" fn get_kbd_code() -> u8;
+    /// Switches between line-buffered keyboard input on stdin (`KBD_MODE_LINE`) and raw
+    /// scancodes from `get_kbd_code` (`KBD_MODE_RAW`). Only the foreground process may do this.
+    pub extern "C" fn set_kbd_mode(mode: u32) -> ();
+    /// Sleeps for at least `duration_ms` milliseconds
     pub extern "C" fn sleep_ms(duration_ms: u32) -> ();
+    /// Sleeps for at least `duration_ns` nanoseconds. A timer interrupt is armed for the deadline,
+    /// so it is usually over within microseconds of it
+    pub extern "C" fn nanosleep(duration_ns: u64) -> ();
 
     /// Exits the current process
     pub extern "C" fn exit(code: i8) -> ();
+
+    /// Opens the file at `path` and returns the lowest free file descriptor
+    pub extern "C" fn open(
+        /// UTF-8 path, not null-terminated
+        path: *const u8,
+        path_len: usize,
+        /// One of `O_RDONLY`, `O_WRONLY` or `O_RDWR`
+        flags: u32
+    ) -> u32;
+    /// Reads up to `len` bytes into `buf`. Returns the number of bytes read, zero at end of file.
+    pub extern "C" fn read(fd: u32, buf: *mut u8, len: usize) -> usize;
+    /// Writes up to `len` bytes from `buf`. Returns the number of bytes written.
+    pub extern "C" fn write(fd: u32, buf: *const u8, len: usize) -> usize;
+    /// Closes a file descriptor, making it available to `open` again
+    pub extern "C" fn close(fd: u32) -> ();
+    /// Moves the file offset of `fd`. Returns the new offset from the start of the file.
+    pub extern "C" fn lseek(
+        fd: u32,
+        offset: i64,
+        /// One of `SEEK_SET`, `SEEK_CUR` or `SEEK_END`
+        whence: u32
+    ) -> u64;
+    /// Reads the `index`th entry of the directory open as `fd`, writing its name into `name`.
+    /// The directory is listed when reading index 0, and later indices read from that listing.
+    pub extern "C" fn read_dir(
+        fd: u32,
+        index: u64,
+        /// Buffer for the entry name. `NAME_MAX` bytes is always enough.
+        name: *mut u8,
+        name_cap: usize
+    ) -> DirEntry;
+
+    /// Starts the program at `path` as a child of the current process. Returns the child's PID.
+    pub extern "C" fn spawn(
+        path: *const u8,
+        path_len: usize,
+        /// Null-terminated array of NUL-terminated argument strings, or null for no arguments
+        argv: *const *const u8,
+        /// Null-terminated array of NUL-terminated `KEY=VALUE` strings, or null for an empty
+        /// environment
+        envp: *const *const u8
+    ) -> u64;
+    /// Replaces the program of the current process with the one at `path`, keeping its PID and
+    /// open files. Only returns on error.
+    pub extern "C" fn exec(
+        path: *const u8,
+        path_len: usize,
+        /// Null-terminated array of NUL-terminated argument strings, or null for no arguments
+        argv: *const *const u8,
+        /// Null-terminated array of NUL-terminated `KEY=VALUE` strings, or null for an empty
+        /// environment
+        envp: *const *const u8
+    ) -> ();
+    /// Blocks until the child process `pid` exits and returns its exit code
+    pub extern "C" fn wait(pid: u64) -> i8;
+    /// Moves the end of the heap to `addr` and returns the new end. A null `addr` returns the
+    /// current end.
+    pub extern "C" fn brk(addr: *mut u8) -> *mut u8;
+    /// Maps `len` bytes of zeroed memory somewhere free and returns its page aligned address.
+    pub extern "C" fn mmap(
+        len: usize,
+        /// `PROT_*` flags
+        prot: u32
+    ) -> *mut u8;
+    /// Unmaps the memory from `mmap` in `addr..addr + len`. `addr` must be page aligned.
+    pub extern "C" fn munmap(addr: *mut u8, len: usize) -> ();
+    /// Changes the access rights of the memory from `mmap` in `addr..addr + len`, all of which
+    /// must be mapped. `addr` must be page aligned.
+    pub extern "C" fn mprotect(
+        addr: *mut u8,
+        len: usize,
+        /// `PROT_*` flags
+        prot: u32
+    ) -> ();
+    /// Creates a copy of the current process, which shares its memory copy-on-write and its open
+    /// files. Returns the child's PID in the parent, and 0 in the child.
+    pub extern "C" fn fork() -> u64;
+    /// Sets the scheduling priority of the current process to one of the `PRIORITY_*` levels.
+    /// Only init may raise its priority; other processes get `PermissionDenied`.
+    pub extern "C" fn set_priority(priority: u32) -> ();
+
+    /// Reads the settings of the terminal `fd` refers to
+    pub extern "C" fn tcgetattr(fd: u32, termios: *mut Termios) -> ();
+    /// Changes the settings of the terminal `fd` refers to. Input typed so far is kept.
+    pub extern "C" fn tcsetattr(fd: u32, termios: *const Termios) -> ();
+    /// Makes process group `pgid` the foreground one of the terminal `fd` refers to, which only
+    /// the foreground process group may do
+    pub extern "C" fn tcsetpgrp(fd: u32, pgid: u64) -> ();
+    /// Sends `signal`, one of the `SIG*` constants, to process `pid`, which must descend from the
+    /// current process or be in its process group
+    pub extern "C" fn kill(pid: u64, signal: u32) -> ();
+}
+
+/// Longest file name a single path component may have
+pub const NAME_MAX: usize = 255;
+
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileKind {
+    File,
+    Directory,
+}
+
+/// Result of `read_dir`
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DirEntry {
+    /// Length of the name written into the buffer. Zero once `index` is past the last entry.
+    pub name_len: usize,
+    pub kind: FileKind,
 }
 
+pub const STDIN_FILENO: u32 = 0;
+pub const STDOUT_FILENO: u32 = 1;
+pub const STDERR_FILENO: u32 = 2;
+
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+/// Mask for the access mode bits of `open` flags
+pub const O_ACCMODE: u32 = 3;
+
+pub const SEEK_SET: u32 = 0;
+pub const SEEK_CUR: u32 = 1;
+pub const SEEK_END: u32 = 2;
+
+pub const KBD_MODE_LINE: u32 = 0;
+pub const KBD_MODE_RAW: u32 = 1;
+
+/// Exit code of a process killed for accessing memory it may not
+pub const EXIT_SEGFAULT: i8 = -11;
+/// `wait` returns this when the child was stopped rather than exited
+pub const WAIT_STOPPED: i8 = i8::MIN;
+
+// Signals. A process they terminate exits with the signal's number, negated.
+
+/// Terminates the process. The terminal sends it on Ctrl-C.
+pub const SIGINT: u32 = 2;
+/// Terminates the process
+pub const SIGKILL: u32 = 9;
+/// Resumes the process if it is stopped
+pub const SIGCONT: u32 = 18;
+/// Stops the process until `SIGCONT`. The terminal sends it on Ctrl-Z.
+pub const SIGTSTP: u32 = 20;
+
+/// Terminal settings, read with `tcgetattr` and changed with `tcsetattr`
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Termios {
+    /// Input flags: `ICRNL`
+    pub iflag: u32,
+    /// Local flags: `ISIG`, `ICANON` and `ECHO`
+    pub lflag: u32,
+    /// Special characters, indexed by `VINTR` and the like. Zero disables one.
+    pub cc: [u8; NCCS],
+}
+
+/// Translate carriage returns in the input to newlines
+pub const ICRNL: u32 = 1;
+
+/// Send `SIGINT` and `SIGTSTP` to the foreground process group on `VINTR` and `VSUSP`
+pub const ISIG: u32 = 1;
+/// Canonical mode: input is edited a line at a time, and reads return whole lines. Otherwise
+/// reads return whatever bytes have arrived.
+pub const ICANON: u32 = 2;
+/// Echo input back to the terminal
+pub const ECHO: u32 = 4;
+
+pub const NCCS: usize = 8;
+pub const VINTR: usize = 0;
+pub const VSUSP: usize = 1;
+/// Erases the last character of the line, in canonical mode
+pub const VERASE: usize = 2;
+/// Erases the whole line, in canonical mode
+pub const VKILL: usize = 3;
+/// Ends the line without a newline, in canonical mode. On an empty line, reads return 0.
+pub const VEOF: usize = 4;
+
+pub const PROT_NONE: u32 = 0;
+pub const PROT_READ: u32 = 1;
+pub const PROT_WRITE: u32 = 2;
+pub const PROT_EXEC: u32 = 4;
+
+/// Scheduling priorities. Processes start out with `PRIORITY_NORMAL`, or their parent's priority
+/// when forked.
+pub const PRIORITY_HIGH: u32 = 0;
+pub const PRIORITY_NORMAL: u32 = 1;
+pub const PRIORITY_LOW: u32 = 2;
+
 #[repr(u32)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum SyscallErrorCode {
     Ok = 0,
     InvalidArgumentError,
+    NotFound,
+    BadFileDescriptor,
+    PermissionDenied,
+    TooManyOpenFiles,
+    IoError,
+    NotADirectory,
+    IsADirectory,
+    NoSuchProcess,
+    InvalidExecutable,
+    /// A pointer argument does not point to memory the process may access
+    BadAddress,
+    OutOfMemory,
+    /// A blocking syscall was cut short by a signal
+    Interrupted,
+    /// The file descriptor does not refer to a terminal
+    NotATerminal,
 }
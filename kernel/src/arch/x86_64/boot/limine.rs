@@ -28,6 +28,8 @@ static KERNEL_ADDRESS_REQUEST: limine::request::KernelAddressRequest =
 static KERNEL_FILE_REQUEST: limine::request::KernelFileRequest =
     limine::request::KernelFileRequest::new();
 
+static SMP_REQUEST: limine::request::SmpRequest = limine::request::SmpRequest::new();
+
 // With the HHDM feature on, 4-level paging, and KASLR enabled, our higher half looks like:
 //
 // 0xffff8000_00000000..=0xffff8fff_ffffffff -- HHDM is somewhere in here
@@ -74,28 +76,71 @@ fn _start() -> ! {
             .cast();
         &*ptr
     });
-    arch::x86_64::gdt::init();
+    unsafe { arch::x86_64::gdt::init_this_cpu(cpu::initial_cpu_id()) };
     arch::x86_64::interrupts::init_idt();
     arch::x86_64::syscall::init();
 
     unsafe { arch::x86_64::memory::init(get_phys_mem_offset(), get_mmap()) };
     crate::allocator::init_heap().unwrap();
+    cpu::fpu::init();
 
     crate::log::init(SERIAL_LOG_MAX, CONSOLE_LOG_MAX, 128);
+    unsafe { arch::x86_64::time::init() };
     let modules = get_modules();
 
     let framebuffer = unsafe { get_framebuffer() }
         .map(|fb| Box::new(fb) as Box<dyn Framebuffer + Send + Sync + 'static>);
     cpu::init_this_cpu();
     enumerate_acpi_tables();
+    start_application_processors();
     x86_64::instructions::interrupts::enable();
 
     crate::init::kernel_main(crate::init::InitServices {
         modules,
         framebuffer,
+        cmdline: get_cmdline(),
     });
 }
 
+/// Lets every other core loose on [`ap_main`].
+fn start_application_processors() {
+    let Some(smp) = SMP_REQUEST.get_response() else {
+        log::warn!("SMP request failed, running on one core");
+        return;
+    };
+    for ap in smp
+        .cpus()
+        .iter()
+        .filter(|c| c.lapic_id != smp.bsp_lapic_id())
+    {
+        if ap.lapic_id as usize >= cpu::MAX_CORES {
+            log::warn!("Not starting core with APIC ID {}, too high", ap.lapic_id);
+            continue;
+        }
+        ap.goto_address.write(ap_main);
+    }
+}
+
+/// Where application processors start, on a stack of their own from Limine.
+unsafe extern "C" fn ap_main(ap: &limine::smp::Cpu) -> ! {
+    x86_64::instructions::interrupts::disable();
+    enable_simd();
+    arch::x86_64::gdt::init_this_cpu(ap.lapic_id as usize);
+    arch::x86_64::interrupts::init_this_cpu();
+    arch::x86_64::syscall::init();
+    memory::enable_no_execute();
+    cpu::fpu::init_this_cpu();
+    cpu::init_this_cpu();
+    log::info!("Core {} up", ap.lapic_id);
+
+    // The first core sets up the executor once the rest of the kernel is ready
+    while crate::task::EXECUTOR.get().is_none() {
+        core::hint::spin_loop();
+    }
+    x86_64::instructions::interrupts::enable();
+    crate::task::run()
+}
+
 fn enable_simd() {
     unsafe {
         x86_64::registers::control::Cr0::update(|r| {
@@ -141,6 +186,16 @@ fn get_phys_mem_offset() -> VirtAddr {
     VirtAddr::new(phys_mem_start)
 }
 
+fn get_cmdline() -> &'static str {
+    let Some(file) = KERNEL_FILE_REQUEST.get_response().map(|r| r.file()) else {
+        return "";
+    };
+    core::str::from_utf8(file.cmdline()).unwrap_or_else(|_| {
+        log::warn!("Kernel command line is not UTF-8; ignoring it");
+        ""
+    })
+}
+
 fn get_modules() -> Vec<BootModule> {
     let limine_modules = MODULES_REQUEST
         .get_response()
@@ -201,6 +256,12 @@ fn enumerate_acpi_tables() {
     }
     .unwrap();
 
+    let platform = acpi::PlatformInfo::new(&tables).unwrap();
+    match &platform.interrupt_model {
+        acpi::InterruptModel::Apic(apic) => arch::x86_64::interrupts::ioapic::init(apic),
+        model => panic!("Unsupported interrupt model: {model:?}"),
+    }
+
     let pci = acpi::PciConfigRegions::new(&tables).unwrap();
     log::trace!("{pci:#X?}");
     enumerate_pci_devices(&pci);
@@ -17,8 +17,23 @@ impl ApicRegister {
     pub fn get_mut(&mut self) -> &mut u32 {
         &mut self.0[0]
     }
+
+    pub fn read(&self) -> u32 {
+        unsafe { core::ptr::read_volatile(&self.0[0]) }
+    }
+
+    pub fn write(&mut self, value: u32) {
+        unsafe { core::ptr::write_volatile(&mut self.0[0], value) }
+    }
 }
 
+/// Vector of the spurious interrupts the local APIC may raise. They need no end of interrupt.
+pub const SPURIOUS_VEC: u8 = 0xFF;
+
+const LVT_MASKED: u32 = 1 << 16;
+/// The timer counts down at the bus clock divided by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
 #[repr(C)]
 pub struct ApicRegisters {
     _reserved0: [ApicRegister; 2],                   // 000h ..= 010h
@@ -68,4 +83,51 @@ impl ApicRegisters {
         let apic: *mut ApicRegisters = phys_to_virt(apic_addr).as_mut_ptr();
         NonNull::new(apic).unwrap()
     }
+
+    /// Software-enables the local APIC, without which it delivers no interrupts at all.
+    pub fn enable(&mut self) {
+        const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+        self.sivr.write(APIC_SOFTWARE_ENABLE | SPURIOUS_VEC as u32);
+    }
+
+    /// Signals the end of an interrupt that came through the local APIC.
+    pub fn end_of_interrupt(&mut self) {
+        self.eoi.write(0);
+    }
+
+    /// Sends a non-maskable interrupt to the core whose local APIC ID is `apic_id`.
+    pub fn send_nmi(&mut self, apic_id: u8) {
+        const DELIVERY_PENDING: u32 = 1 << 12;
+        const DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+        while self.icr[0].read() & DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+        self.icr[1].write((apic_id as u32) << 24);
+        self.icr[0].write(DELIVERY_MODE_NMI);
+    }
+
+    /// Starts the timer counting down from `count`, raising interrupt `vector` once when it gets
+    /// to zero. Starting it again before then starts over from the new count.
+    pub fn start_one_shot_timer(&mut self, vector: u8, count: u32) {
+        self.timer_divide_config.write(TIMER_DIVIDE_BY_16);
+        self.timer_local_vte.write(vector as u32);
+        self.timer_initial_count.write(count);
+    }
+
+    /// Starts the timer counting down from `u32::MAX` without raising an interrupt, so that its
+    /// speed can be measured with [`timer_count`](Self::timer_count).
+    pub fn start_timer_masked(&mut self) {
+        self.timer_divide_config.write(TIMER_DIVIDE_BY_16);
+        self.timer_local_vte.write(LVT_MASKED);
+        self.timer_initial_count.write(u32::MAX);
+    }
+
+    pub fn stop_timer(&mut self) {
+        self.timer_initial_count.write(0);
+    }
+
+    /// What is left of the timer's count.
+    pub fn timer_count(&self) -> u32 {
+        self.timer_current_count.read()
+    }
 }
@@ -0,0 +1,183 @@
+//! Saving and restoring the x87/SSE/AVX state of processes, with XSAVE where the CPU supports it
+//! and FXSAVE otherwise.
+
+use alloc::{boxed::Box, vec};
+use conquer_once::spin::OnceCell;
+use core::arch::asm;
+
+/// Size of the FXSAVE area, which is also the legacy part of the XSAVE area.
+const LEGACY_AREA_SIZE: usize = 512;
+/// Offset of the XSAVE header, right after the legacy area.
+const XSAVE_HEADER: usize = LEGACY_AREA_SIZE;
+/// x87 control word after `FNINIT`: all exceptions masked, 64-bit precision.
+const DEFAULT_FCW: u16 = 0x037F;
+/// MXCSR after reset: all exceptions masked, round to nearest.
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+#[derive(Clone, Copy, Debug)]
+enum Mechanism {
+    Fxsave,
+    /// XSAVE with the given state components enabled in XCR0.
+    Xsave {
+        components: u64,
+    },
+}
+
+/// How states are saved, and how many bytes that takes.
+static MECHANISM: OnceCell<(Mechanism, usize)> = OnceCell::uninit();
+
+#[derive(Clone)]
+#[repr(C, align(64))]
+struct Block([u8; 64]);
+
+/// A saved copy of the extended register state.
+#[derive(Clone)]
+pub struct FpuState {
+    area: Box<[Block]>,
+}
+
+/// Enables XSAVE with every component the CPU offers that we know how to handle, or falls back
+/// to FXSAVE.
+pub fn init() {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+    use x86_64::registers::control::{Cr4, Cr4Flags};
+    use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+    let features = unsafe { __cpuid(1) }.ecx;
+    let has_xsave = features & (1 << 26) != 0;
+    let has_avx = features & (1 << 28) != 0;
+
+    let mechanism = if has_xsave {
+        unsafe { Cr4::update(|f| f.insert(Cr4Flags::OSXSAVE)) };
+        let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+        if has_avx {
+            components |= XCr0Flags::AVX;
+        }
+        unsafe { XCr0::write(components) };
+        // EBX reports the size needed for the components currently enabled in XCR0
+        let size = unsafe { __cpuid_count(0xD, 0) }.ebx as usize;
+        (
+            Mechanism::Xsave {
+                components: components.bits(),
+            },
+            size,
+        )
+    } else {
+        (Mechanism::Fxsave, LEGACY_AREA_SIZE)
+    };
+    log::info!(
+        "Saving FPU state with {:?}, {} bytes",
+        mechanism.0,
+        mechanism.1
+    );
+    MECHANISM.init_once(|| mechanism);
+}
+
+/// Enables the same state saving on this core as [`init`] did on the first one.
+pub fn init_this_cpu() {
+    use x86_64::registers::control::{Cr4, Cr4Flags};
+    use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+    if let Mechanism::Xsave { components } = mechanism().0 {
+        unsafe {
+            Cr4::update(|f| f.insert(Cr4Flags::OSXSAVE));
+            XCr0::write(XCr0Flags::from_bits_truncate(components));
+        }
+    }
+}
+
+fn mechanism() -> (Mechanism, usize) {
+    *MECHANISM.get().expect("FPU state saving not initialized")
+}
+
+impl FpuState {
+    /// A clean state, as after `FNINIT`, with all registers zeroed.
+    pub fn new() -> Self {
+        let (mechanism, size) = mechanism();
+        let mut state = FpuState {
+            area: vec![Block([0; 64]); size.div_ceil(64)].into_boxed_slice(),
+        };
+        let bytes = state.as_bytes_mut();
+        bytes[0..2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        bytes[24..28].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        if let Mechanism::Xsave { .. } = mechanism {
+            // XSTATE_BV: take x87 and SSE from the legacy area, reset everything else
+            bytes[XSAVE_HEADER..XSAVE_HEADER + 8].copy_from_slice(&0b11u64.to_le_bytes());
+        }
+        state
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        let len = self.area.len() * 64;
+        unsafe { core::slice::from_raw_parts_mut(self.area.as_mut_ptr().cast(), len) }
+    }
+
+    /// Stores the current extended state of this CPU.
+    pub fn save(&mut self) {
+        let ptr = self.area.as_mut_ptr();
+        unsafe {
+            match mechanism().0 {
+                Mechanism::Fxsave => asm!("fxsave64 [{}]", in(reg) ptr, options(nostack)),
+                Mechanism::Xsave { components } => asm!(
+                    "xsave64 [{}]",
+                    in(reg) ptr,
+                    in("eax") components as u32,
+                    in("edx") (components >> 32) as u32,
+                    options(nostack),
+                ),
+            }
+        }
+    }
+
+    /// Loads this state into the CPU. The vector registers are clobbered as far as the compiler
+    /// is concerned.
+    ///
+    /// # Safety
+    /// The caller must not rely on the rest of the extended state, such as MXCSR, staying the same.
+    pub unsafe fn restore(&self) {
+        let ptr = self.area.as_ptr();
+        match mechanism().0 {
+            Mechanism::Fxsave => asm!(
+                "fxrstor64 [{}]",
+                in(reg) ptr,
+                clobber_abi("C"),
+                options(nostack),
+            ),
+            Mechanism::Xsave { components } => asm!(
+                "xrstor64 [{}]",
+                in(reg) ptr,
+                in("eax") components as u32,
+                in("edx") (components >> 32) as u32,
+                clobber_abi("C"),
+                options(nostack),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mxcsr() -> u32 {
+        let mut value = 0u32;
+        unsafe { asm!("stmxcsr [{}]", in(reg) &mut value, options(nostack)) };
+        value
+    }
+
+    #[test_case]
+    fn save_restore_mxcsr() {
+        let original = FpuState::new();
+        let mut saved = FpuState::new();
+        unsafe {
+            // Round towards zero
+            asm!("ldmxcsr [{}]", in(reg) &(DEFAULT_MXCSR | 0x6000), options(nostack));
+            saved.save();
+            original.restore();
+            assert_eq!(mxcsr(), DEFAULT_MXCSR);
+            saved.restore();
+            assert_eq!(mxcsr(), DEFAULT_MXCSR | 0x6000);
+            original.restore();
+        }
+    }
+}
@@ -1,26 +1,36 @@
 pub mod apic;
 mod context;
+pub mod fpu;
 mod registers;
 
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 pub use registers::Registers;
 
+use super::memory::KernelStack;
 use crate::process::{Process, ProcessState};
 
 pub use self::context::Context;
+pub use self::fpu::FpuState;
 
-const MAX_CORES: usize = 8;
+pub const MAX_CORES: usize = 8;
 const NONE_CPU: Option<Cpu> = None; // workaround because Cpu is non-Copy
 static mut CPUS: [Option<Cpu>; MAX_CORES] = [NONE_CPU; MAX_CORES];
+/// Bit `n` is set once core `n` has been initialized.
+static ONLINE_CPUS: AtomicU64 = AtomicU64::new(0);
 
 /// Contains all of the processor-specific structures.
 /// Each CPU core must only ever be able to access its own `Cpu` struct.
 pub struct Cpu {
     id: usize,
+    /// Whether this is the core the kernel booted on.
+    boot: bool,
     /// The process currently being run, if we are currently in a process.
     process: Option<NonNull<Process>>,
     scheduler_ctx: *mut Context,
+    /// Stack of a process that left for good while running on it, freed once we are off it.
+    retired_stack: Option<KernelStack>,
 }
 
 impl Cpu {
@@ -28,15 +38,31 @@ impl Cpu {
         self.id
     }
 
-    pub fn run_process(&mut self, proc: &mut Process, waker: core::task::Waker) {
+    pub fn is_boot_cpu(&self) -> bool {
+        self.boot
+    }
+
+    pub fn run_process(&mut self, proc: &mut Process) {
         log::trace!("Running process {}", proc.pid.as_u64());
         proc.space.load();
+        unsafe {
+            // Interrupts and syscalls from the process land on its own kernel stack
+            let top = proc.kernel_stack.top();
+            super::gdt::set_kernel_stack(self.id, top);
+            super::syscall::set_kernel_stack(self.id, top);
+        }
         let load = proc.context;
-        proc.state = ProcessState::Running(waker);
+        proc.state = ProcessState::Running;
         self.process = Some(NonNull::from(&*proc));
-        unsafe { Context::switch(&mut self.scheduler_ctx, load) }
+        unsafe {
+            proc.fpu.restore();
+            Context::switch(&mut self.scheduler_ctx, load)
+        }
         log::trace!("Return from process {}", proc.pid.as_u64());
+        // The process may go on to run and exit on another core, which frees its page tables
+        super::memory::load_kernel_space();
         self.scheduler_ctx = core::ptr::null_mut();
+        drop(self.retired_stack.take());
     }
 
     /// Returns an error if the CPU is already holding a process.
@@ -48,13 +74,31 @@ impl Cpu {
         Ok(())
     }
 
+    /// The process currently being run, without taking it off this CPU.
+    pub fn current_process(&mut self) -> Option<&'static mut Process> {
+        self.process.map(|mut ptr| unsafe { ptr.as_mut() })
+    }
+
     pub fn try_take_process(&mut self) -> Option<&'static mut Process> {
         self.process.take().map(|mut ptr| unsafe { ptr.as_mut() })
     }
 
     pub fn return_from_process(&mut self, proc: &mut Process) {
+        // Kernel code doesn't expect its extended state to survive the switch anyway, so saving
+        // here catches whatever the process left behind
+        proc.fpu.save();
         unsafe { Context::switch(&mut proc.context, self.scheduler_ctx) }
     }
+
+    /// Switches back to the scheduler without saving the current context, for when the process
+    /// must never resume from here. `stack`, the kernel stack we are running on, is freed once
+    /// the switch is done.
+    pub fn leave_process(&mut self, stack: KernelStack) -> ! {
+        self.retired_stack = Some(stack);
+        let mut discarded = core::ptr::null_mut();
+        unsafe { Context::switch(&mut discarded, self.scheduler_ctx) }
+        unreachable!("Resumed a context that was left")
+    }
 }
 
 fn current_cpu_id() -> usize {
@@ -62,6 +106,19 @@ fn current_cpu_id() -> usize {
     (*regs.apic_id.get() as usize) >> 24
 }
 
+/// The ID of the running core as reported by CPUID, for use before memory is set up and the local
+/// APIC can be reached.
+pub fn initial_cpu_id() -> usize {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    (cpuid.ebx >> 24) as usize
+}
+
+/// IDs of the cores that have been initialized with [`init_this_cpu`].
+pub fn online_cpus() -> impl Iterator<Item = usize> {
+    let online = ONLINE_CPUS.load(Ordering::Acquire);
+    (0..MAX_CORES).filter(move |id| online & (1 << id) != 0)
+}
+
 /// Get access to the [`Cpu`] representing the currently running core.
 /// # Panics
 /// Panics if the current CPU has not yet been initialized with [`init_this_cpu`], or if interrupts
@@ -86,11 +143,17 @@ pub fn init_this_cpu() {
 
     let cpu = Cpu {
         id,
+        boot: ONLINE_CPUS.load(Ordering::Acquire) == 0,
         process: None,
         scheduler_ctx: core::ptr::null_mut(),
+        retired_stack: None,
     };
+    super::syscall::init_this_cpu(id);
+    unsafe { apic::ApicRegisters::get().as_mut() }.enable();
+    super::time::init_this_cpu();
 
     unsafe {
         CPUS[id] = Some(cpu);
     }
+    ONLINE_CPUS.fetch_or(1 << id, Ordering::Release);
 }
@@ -1,5 +1,5 @@
 /// General-purpose registers
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 #[repr(C)]
 pub struct Registers {
     pub r15: u64,
@@ -1,54 +1,53 @@
-use core::ptr::addr_of;
+use core::ptr::{addr_of, addr_of_mut};
 
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use super::cpu::MAX_CORES;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 1;
+const DOUBLE_FAULT_STACK_SIZE: usize = 16 * 1024; // jesus christ how much memory does panic! need?
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 16 * 1024; // jesus christ how much memory does panic! need?
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
-            stack_start + STACK_SIZE
-        };
-        tss.privilege_stack_table[0] = {
-            const STACK_SIZE: usize = 16 * 1024;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
-            stack_start + STACK_SIZE
-        };
-        tss
-    };
+/// One per core, indexed by the core's ID. Not behind a lock, as the CPU reads it behind our back
+/// anyway. The ring 0 stack is switched to that of each process as it is run.
+static mut TSS: [TaskStateSegment; MAX_CORES] = [const { TaskStateSegment::new() }; MAX_CORES];
+/// Every core needs a GDT of its own to point at its own TSS. They are otherwise identical, so
+/// [`SELECTORS`] apply to all of them.
+static mut GDT: [GlobalDescriptorTable; MAX_CORES] =
+    [const { GlobalDescriptorTable::new() }; MAX_CORES];
+static mut DOUBLE_FAULT_STACKS: [[u8; DOUBLE_FAULT_STACK_SIZE]; MAX_CORES] =
+    [[0; DOUBLE_FAULT_STACK_SIZE]; MAX_CORES];
 
-    static ref _GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        use x86_64::structures::gdt::DescriptorFlags as Flags;
-        let data_selector = gdt.add_entry(Descriptor::UserSegment(
-            Flags::USER_SEGMENT.bits() | Flags::PRESENT.bits() | Flags::WRITABLE.bits(),
-        ));
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
-        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                data_selector,
-                tss_selector,
-                user_code_selector,
-                user_data_selector,
-            },
-        )
-    };
+/// Builds a GDT that uses `tss` as its task state segment.
+///
+/// # Safety
+/// `tss` must point to a TSS that lives forever.
+unsafe fn build_gdt(tss: *const TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    use x86_64::structures::gdt::DescriptorFlags as Flags;
+    let data_selector = gdt.add_entry(Descriptor::UserSegment(
+        Flags::USER_SEGMENT.bits() | Flags::PRESENT.bits() | Flags::WRITABLE.bits(),
+    ));
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment_unchecked(tss));
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            tss_selector,
+            user_code_selector,
+            user_data_selector,
+        },
+    )
+}
 
-    pub static ref GDT: &'static GlobalDescriptorTable = &_GDT.0;
-    pub static ref SELECTORS: &'static Selectors = &_GDT.1;
+lazy_static! {
+    pub static ref SELECTORS: Selectors = unsafe { build_gdt(addr_of!(TSS[0])) }.1;
 }
 
 pub struct Selectors {
@@ -59,16 +58,33 @@ pub struct Selectors {
     pub user_data_selector: SegmentSelector,
 }
 
-pub fn init() {
+/// Loads the GDT and TSS of core `cpu_id`.
+///
+/// # Safety
+/// Must be called once on each core, by that core, with its ID.
+pub unsafe fn init_this_cpu(cpu_id: usize) {
     use x86_64::instructions::segmentation::Segment;
     use x86_64::instructions::tables::load_tss;
     use x86_64::registers::segmentation::{CS, SS};
 
-    GDT.load();
+    (*addr_of_mut!(TSS[cpu_id])).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        let stack_start = VirtAddr::from_ptr(addr_of!(DOUBLE_FAULT_STACKS[cpu_id]));
+        stack_start + DOUBLE_FAULT_STACK_SIZE
+    };
+
+    let gdt = &mut *addr_of_mut!(GDT[cpu_id]);
+    *gdt = build_gdt(addr_of!(TSS[cpu_id])).0;
+    gdt.load();
+
+    CS::set_reg(SELECTORS.code_selector);
+    SS::set_reg(SELECTORS.data_selector);
+    load_tss(SELECTORS.tss_selector);
+}
 
-    unsafe {
-        CS::set_reg(SELECTORS.code_selector);
-        SS::set_reg(SELECTORS.data_selector);
-        load_tss(SELECTORS.tss_selector);
-    }
+/// Sets the stack core `cpu_id` switches to when an interrupt arrives in user mode.
+///
+/// # Safety
+/// `top` must be the top of a mapped stack that stays alive until the next call.
+pub(super) unsafe fn set_kernel_stack(cpu_id: usize, top: VirtAddr) {
+    (*addr_of_mut!(TSS[cpu_id])).privilege_stack_table[0] = top;
 }
@@ -0,0 +1,136 @@
+//! Routing of external interrupts through the I/O APICs the ACPI MADT describes.
+
+use acpi::platform::interrupt::{Apic, Polarity, TriggerMode};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::arch::memory::{phys_to_virt, PhysAddr};
+
+/// Registers are accessed indirectly: select one by writing its index here, then access it
+/// through the window.
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const IOAPICVER: u32 = 0x01;
+/// The redirection table, two registers per input.
+const IOREDTBL: u32 = 0x10;
+
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+
+struct IoApic {
+    regs: *mut u32,
+    /// The global system interrupt of the first input.
+    gsi_base: u32,
+    inputs: u32,
+}
+
+unsafe impl Send for IoApic {}
+
+impl IoApic {
+    fn read(&mut self, reg: u32) -> u32 {
+        unsafe {
+            self.regs.byte_add(IOREGSEL).write_volatile(reg);
+            self.regs.byte_add(IOWIN).read_volatile()
+        }
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        unsafe {
+            self.regs.byte_add(IOREGSEL).write_volatile(reg);
+            self.regs.byte_add(IOWIN).write_volatile(value);
+        }
+    }
+
+    fn set_redirection(&mut self, input: u32, entry: u64) {
+        let reg = IOREDTBL + input * 2;
+        // Masked while half written
+        self.write(reg, MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.inputs).contains(&gsi)
+    }
+}
+
+/// Where an ISA IRQ arrives, and how it signals.
+#[derive(Clone, Copy)]
+struct IsaRoute {
+    gsi: u32,
+    flags: u64,
+}
+
+struct IoApics {
+    apics: Vec<IoApic>,
+    isa_routes: [IsaRoute; 16],
+}
+
+static IO_APICS: Mutex<Option<IoApics>> = Mutex::new(None);
+
+/// Finds the I/O APICs in `model`, with all of their inputs masked.
+pub fn init(model: &Apic) {
+    // Unless overridden, ISA IRQs are identity-mapped, edge-triggered and active high
+    let mut isa_routes: [IsaRoute; 16] = core::array::from_fn(|irq| IsaRoute {
+        gsi: irq as u32,
+        flags: 0,
+    });
+    for o in &model.interrupt_source_overrides {
+        let mut flags = 0;
+        if let Polarity::ActiveLow = o.polarity {
+            flags |= ACTIVE_LOW;
+        }
+        if let TriggerMode::Level = o.trigger_mode {
+            flags |= LEVEL_TRIGGERED;
+        }
+        isa_routes[o.isa_source as usize] = IsaRoute {
+            gsi: o.global_system_interrupt,
+            flags,
+        };
+    }
+
+    let apics = model
+        .io_apics
+        .iter()
+        .map(|a| {
+            let mut apic = IoApic {
+                regs: phys_to_virt(PhysAddr::new(a.address as u64)).as_mut_ptr(),
+                gsi_base: a.global_system_interrupt_base,
+                inputs: 0,
+            };
+            apic.inputs = ((apic.read(IOAPICVER) >> 16) & 0xFF) + 1;
+            for input in 0..apic.inputs {
+                apic.set_redirection(input, MASKED);
+            }
+            log::debug!(
+                "I/O APIC {} at {:#x}: GSIs {}..{}",
+                a.id,
+                a.address,
+                apic.gsi_base,
+                apic.gsi_base + apic.inputs
+            );
+            apic
+        })
+        .collect();
+
+    *IO_APICS.lock() = Some(IoApics { apics, isa_routes });
+}
+
+/// Delivers ISA IRQ `irq` as interrupt `vector` to the core with local APIC ID `apic_id`.
+///
+/// # Panics
+/// Panics if [`init`] has not been called, or no I/O APIC handles the IRQ.
+pub fn route_isa_irq(irq: u8, vector: u8, apic_id: u8) {
+    let mut io_apics = IO_APICS.lock();
+    let io_apics = io_apics.as_mut().expect("I/O APICs not initialized");
+    let route = io_apics.isa_routes[irq as usize];
+    let apic = io_apics
+        .apics
+        .iter_mut()
+        .find(|a| a.handles(route.gsi))
+        .unwrap_or_else(|| panic!("No I/O APIC handles GSI {}", route.gsi));
+    let input = route.gsi - apic.gsi_base;
+    apic.set_redirection(input, route.flags | vector as u64 | (apic_id as u64) << 56);
+}
@@ -1,18 +1,29 @@
+pub mod ioapic;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use crate::process::ProcessState;
 
-use super::cpu::{this_cpu, Registers};
+use super::cpu::{apic, online_cpus, this_cpu, Registers};
 use pic8259::ChainedPics;
 use x86_64::structures::idt::*;
 
+// The legacy PICs are only remapped out of the way of exceptions, and then masked for good
 const PIC_1_OFFSET: u8 = 32;
 const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
+static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-const TIMER_VEC: u8 = PIC_1_OFFSET;
-const KEYBOARD_VEC: u8 = PIC_1_OFFSET + 1;
-// const MOUSE_VEC: u8 = PIC_2_OFFSET + 4;
+/// ISA IRQs routed through the I/O APIC arrive from this vector on.
+const ISA_IRQ_OFFSET: u8 = PIC_2_OFFSET + 8;
+const KEYBOARD_IRQ: u8 = 1;
+const KEYBOARD_VEC: u8 = ISA_IRQ_OFFSET + KEYBOARD_IRQ;
+const COM1_IRQ: u8 = 4;
+const COM1_VEC: u8 = ISA_IRQ_OFFSET + COM1_IRQ;
+// const MOUSE_VEC: u8 = ISA_IRQ_OFFSET + 12;
+/// The local APIC timer of every core.
+pub(super) const TIMER_VEC: u8 = 0xF0;
 
 #[derive(Debug)]
 #[repr(C)]
@@ -98,6 +109,10 @@ extern "x86-interrupt" fn page_fault_handler(
     use crate::{arch::loop_forever, print, println};
     use x86_64::registers::control::Cr2;
     use x86_64::structures::idt::PageFaultErrorCode;
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        user_page_fault(stack_frame, error_code);
+        return;
+    }
     println!("EXCEPTION: PAGE FAULT");
     if crate::panic::unwind::is_kernel_ip(stack_frame.instruction_pointer.as_u64() as usize) {
         unsafe {
@@ -116,24 +131,104 @@ extern "x86-interrupt" fn page_fault_handler(
     loop_forever();
 }
 
+/// Maps in the page a process faulted on if it is due to be mapped on first touch, or kills the
+/// process otherwise.
+fn user_page_fault(
+    stack_frame: InterruptStackFrame,
+    error_code: x86_64::structures::idt::PageFaultErrorCode,
+) {
+    use x86_64::structures::idt::PageFaultErrorCode;
+    let addr = x86_64::registers::control::Cr2::read();
+    let cpu = this_cpu();
+    let proc = cpu
+        .current_process()
+        .expect("User page fault without a process");
+    let handled = proc.space.handle_fault(
+        addr,
+        error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+        error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+    );
+    if handled {
+        return;
+    }
+
+    log::warn!(
+        "Process {}: segmentation fault accessing {:#x} at {:#x} ({:?})",
+        proc.pid.as_u64(),
+        addr.as_u64(),
+        stack_frame.instruction_pointer.as_u64(),
+        error_code
+    );
+    cpu.try_take_process();
+    proc.state = ProcessState::Exited(kernel_uapi::syscall::EXIT_SEGFAULT);
+    cpu.return_from_process(proc);
+    unreachable!("Resumed a process killed by a segmentation fault");
+}
+
+/// Set once a core has started stopping the others, which halt on the NMI it sends them.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Halts every other core for good, so that the caller has the machine to itself. Returns `false`
+/// if another core got there first, in which case this one is about to be halted too.
+pub fn stop_other_cores() -> bool {
+    x86_64::instructions::interrupts::disable();
+    if STOPPING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    let this = this_cpu().id();
+    let apic = unsafe { apic::ApicRegisters::get().as_mut() };
+    for id in online_cpus().filter(|&id| id != this) {
+        apic.send_nmi(id as u8);
+    }
+    true
+}
+
+extern "x86-interrupt" fn nmi_handler(_stack_frame: InterruptStackFrame) {
+    // NMIs are blocked until we return, so nothing wakes us up again
+    if STOPPING.load(Ordering::SeqCst) {
+        super::loop_forever();
+    }
+}
+
 extern "x86-interrupt" fn gp_fault_handler(isf: InterruptStackFrame, error_code: u64) {
     panic!("EXCEPTION: GENERAL PROTECTION FAULT\nError code: {error_code}\n{isf:#?}");
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    crate::task::timer::tick_timer();
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let ticked = super::time::on_timer_interrupt();
+    // Cores check on kernel timers when they were woken for one, and the boot core on every tick
+    if !ticked || this_cpu().is_boot_cpu() {
+        crate::task::timer::tick_timer();
+    }
+    // Before we might switch away, as it may be a while until we get back here
+    end_of_interrupt();
+    if ticked {
+        scheduler_tick(stack_frame);
+    }
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
 
+/// Counts a timer tick against the running process, and preempts it if its time slice is up or
+/// a signal says so. Kernel code is never preempted, so that it can't be switched away from while
+/// holding a lock.
+fn scheduler_tick(stack_frame: InterruptStackFrame) {
     x86_64::instructions::interrupts::without_interrupts(|| {
+        let slice_used_up = crate::process::scheduler::tick();
+        let from_user_mode = stack_frame.code_segment & 0b11 == 3;
+        if !from_user_mode {
+            return;
+        }
+        crate::process::signal::act_on_pending();
+        if !slice_used_up {
+            return;
+        }
         let cpu = this_cpu();
         if let Some(proc) = cpu.try_take_process() {
             proc.state = ProcessState::Runnable;
             cpu.return_from_process(proc)
         }
     });
-
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(TIMER_VEC);
-    }
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
@@ -144,9 +239,17 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(KEYBOARD_VEC);
-    }
+    end_of_interrupt();
+}
+
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::serial::receive_pending();
+
+    end_of_interrupt();
+}
+
+fn end_of_interrupt() {
+    unsafe { apic::ApicRegisters::get().as_mut() }.end_of_interrupt();
 }
 
 static IDT: conquer_once::spin::OnceCell<InterruptDescriptorTable> =
@@ -162,23 +265,45 @@ pub fn init_idt() {
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(super::gdt::DOUBLE_FAULT_IST_INDEX);
         }
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt.general_protection_fault
             .set_handler_fn(gp_fault_handler);
         idt[TIMER_VEC as usize].set_handler_fn(timer_interrupt_handler);
         idt[KEYBOARD_VEC as usize].set_handler_fn(keyboard_interrupt_handler);
+        idt[COM1_VEC as usize].set_handler_fn(serial_interrupt_handler);
+        idt[apic::SPURIOUS_VEC as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     })
     .expect("Tried to initialize IDT twice");
-    IDT.get().unwrap().load();
+    init_this_cpu();
 
     unsafe {
         let mut pics = PICS.lock();
         pics.initialize();
-        pics.write_masks(0xFF, 0xFF);
+        pics.disable();
     };
 }
 
+/// Starts delivering keyboard interrupts, to the calling core.
+pub fn enable_keyboard() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        ioapic::route_isa_irq(KEYBOARD_IRQ, KEYBOARD_VEC, this_cpu().id() as u8)
+    })
+}
+
+/// Starts delivering interrupts for input on the COM1 serial port, to the calling core.
+pub fn enable_serial() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        ioapic::route_isa_irq(COM1_IRQ, COM1_VEC, this_cpu().id() as u8)
+    })
+}
+
+/// Loads the IDT set up by [`init_idt`] on the running core.
+pub fn init_this_cpu() {
+    IDT.get().expect("IDT not initialized").load();
+}
+
 #[test_case]
 fn test_breakpoint() {
     x86_64::instructions::interrupts::int3();
@@ -1,5 +1,5 @@
 mod buddy;
 // mod mmap;
 
-pub use buddy::BuddyAllocatorManager;
+pub use buddy::{BuddyAllocatorManager, FrameFlags, FrameInfo, FrameOwner};
 // pub use mmap::BootInfoFrameAllocator;
@@ -12,10 +12,64 @@ use crate::arch::memory::mmap::{MemoryKind, MemoryRegion};
 
 const PAGE_SIZE: usize = 4096;
 
+/// What a 4KiB frame is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameOwner {
+    Free,
+    /// Holds the allocator's own bitmap and frame metadata.
+    Allocator,
+    /// Kernel data, including page tables.
+    Kernel,
+    KernelStack,
+    /// Mapped into user space, possibly by several processes at once.
+    User,
+}
+
+/// Properties of a 4KiB frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameFlags(u8);
+
+impl FrameFlags {
+    pub const EMPTY: FrameFlags = FrameFlags(0);
+    /// Part of a 2MiB or 1GiB allocation, which is not reference counted.
+    pub const HUGE: FrameFlags = FrameFlags(1 << 0);
+
+    pub fn contains(self, other: FrameFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Metadata the allocator keeps for every 4KiB frame it manages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct FrameInfo {
+    /// How many references to an allocated 4KiB frame there are. Zero when free.
+    pub ref_count: u16,
+    pub flags: FrameFlags,
+    pub owner: FrameOwner,
+}
+
+impl FrameInfo {
+    const FREE: FrameInfo = FrameInfo {
+        ref_count: 0,
+        flags: FrameFlags::EMPTY,
+        owner: FrameOwner::Free,
+    };
+}
+
+const HUGE_FRAME: FrameInfo = FrameInfo {
+    ref_count: 1,
+    flags: FrameFlags::HUGE,
+    owner: FrameOwner::Kernel,
+};
+
 /// A physical memory allocator implemented using a buddy allocator in each available memory region.
+///
+/// Every 4KiB frame has a [`FrameInfo`]. 4KiB allocations are reference counted: they start out
+/// with one reference, and are only freed once every reference has been given back through
+/// `deallocate_frame`.
 pub struct BuddyAllocatorManager<const ENTRIES: usize> {
-    /// Total available memory.
-    remaining: usize,
     entries: [Option<BuddyAllocator>; ENTRIES],
 }
 
@@ -26,7 +80,6 @@ impl<const N: usize> BuddyAllocatorManager<N> {
     ) -> Self {
         let mut entries = core::array::from_fn(|_| None);
         let mut i = 0;
-        let mut remaining = 0;
         for region in memory_map {
             match region.kind {
                 MemoryKind::Available => {}
@@ -37,16 +90,48 @@ impl<const N: usize> BuddyAllocatorManager<N> {
 
             let e = BuddyAllocator::new(phys_start, virt_start, region.len);
 
-            remaining += e.remaining;
             entries[i] = Some(e);
             i += 1;
         }
 
-        BuddyAllocatorManager { remaining, entries }
+        BuddyAllocatorManager { entries }
     }
 
+    /// Total available memory.
     pub fn remaining(&self) -> usize {
-        self.remaining
+        self.entries.iter().flatten().map(|e| e.remaining).sum()
+    }
+
+    fn entry_for(&mut self, frame: PhysFrame<Size4KiB>) -> &mut BuddyAllocator {
+        self.entries
+            .iter_mut()
+            .flatten()
+            .find(|e| e.contains_frame(frame))
+            .expect("frame is not managed by any allocator")
+    }
+
+    /// Allocates a 4KiB frame on behalf of `owner`.
+    pub fn allocate_frame_for(&mut self, owner: FrameOwner) -> Option<PhysFrame<Size4KiB>> {
+        let frame = FrameAllocator::<Size4KiB>::allocate_frame(self)?;
+        self.entry_for(frame).info_mut(frame).owner = owner;
+        Some(frame)
+    }
+
+    /// Takes another reference to an allocated 4KiB frame.
+    pub fn add_ref(&mut self, frame: PhysFrame<Size4KiB>) {
+        let info = self.entry_for(frame).info_mut(frame);
+        assert!(
+            info.ref_count > 0 && !info.flags.contains(FrameFlags::HUGE),
+            "attempt to share a frame that is not a 4KiB allocation"
+        );
+        info.ref_count = info
+            .ref_count
+            .checked_add(1)
+            .expect("frame reference count overflow");
+    }
+
+    pub fn frame_info(&mut self, frame: PhysFrame<Size4KiB>) -> FrameInfo {
+        *self.entry_for(frame).info_mut(frame)
     }
 }
 
@@ -55,13 +140,10 @@ where
     BuddyAllocator: FrameAllocator<S>,
 {
     fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
-        for e in self.entries.iter_mut().filter_map(Option::as_mut) {
-            if let Some(frame) = e.allocate_frame() {
-                self.remaining -= frame.size() as usize;
-                return Some(frame);
-            }
-        }
-        None
+        self.entries
+            .iter_mut()
+            .filter_map(Option::as_mut)
+            .find_map(|e| e.allocate_frame())
     }
 }
 
@@ -73,7 +155,7 @@ where
         for e in self.entries.iter_mut().filter_map(Option::as_mut) {
             if e.contains_frame(frame) {
                 e.deallocate_frame(frame);
-                self.remaining += frame.size() as usize;
+                return;
             }
         }
         panic!("attempt to deallocate page that is not managed by any allocator")
@@ -93,6 +175,8 @@ struct BuddyAllocator {
     remaining: usize,
     /// Bitmap of in-use memory in the region
     bitmap: BuddyBitmap<'static>,
+    /// Metadata for each 4KiB frame of the region, placed right after the bitmap.
+    frames: &'static mut [FrameInfo],
 }
 
 impl BuddyAllocator {
@@ -118,25 +202,48 @@ impl BuddyAllocator {
             layers,
             &mut bitmap_mem[..BuddyBitmap::bits_required_for_n_layers(layers)],
         );
-        // mark the bitmap as used
-        let bitmap_pages = bitmap_len.next_multiple_of(PAGE_SIZE) / PAGE_SIZE;
-        bitmap.dealloc_range(bitmap_pages..pages, 0).unwrap();
+        let frames_start = bitmap_len.next_multiple_of(core::mem::align_of::<FrameInfo>());
+        let frames = core::slice::from_raw_parts_mut(
+            virt_start.add(frames_start).cast::<FrameInfo>(),
+            pages,
+        );
+        // mark the bitmap and frame metadata as used
+        let metadata_len = frames_start + core::mem::size_of_val(frames);
+        let metadata_pages = metadata_len.next_multiple_of(PAGE_SIZE) / PAGE_SIZE;
+        bitmap.dealloc_range(metadata_pages..pages, 0).unwrap();
+        frames.fill(FrameInfo::FREE);
+        frames[..metadata_pages].fill(FrameInfo {
+            ref_count: 1,
+            flags: FrameFlags::EMPTY,
+            owner: FrameOwner::Allocator,
+        });
 
         BuddyAllocator {
             phys_start,
             region_size: size_bytes,
-            remaining: size_bytes - PAGE_SIZE*bitmap_pages,
+            remaining: size_bytes - PAGE_SIZE * metadata_pages,
             bitmap,
+            frames,
         }
     }
 
+    fn info_mut(&mut self, frame: PhysFrame<Size4KiB>) -> &mut FrameInfo {
+        let idx = (frame.start_address() - self.phys_start) as usize / PAGE_SIZE;
+        &mut self.frames[idx]
+    }
+
+    /// Sets the metadata of the `frames` 4KiB frames starting at index `idx`.
+    fn mark(&mut self, idx: usize, frames: usize, info: FrameInfo) {
+        self.frames[idx..idx + frames].fill(info);
+    }
+
     /// Returns true iff the provided physical frame lies entirely within the
     /// region of this allocator.
     fn contains_frame<S: PageSize>(&self, frame: PhysFrame<S>) -> bool {
         let start = frame.start_address();
         let end = start + frame.size();
 
-        let region = self.phys_start..(self.phys_start + self.region_size as u64);
+        let region = self.phys_start..=(self.phys_start + self.region_size as u64);
         region.contains(&start) && region.contains(&end)
     }
 }
@@ -146,6 +253,11 @@ unsafe impl FrameAllocator<Size4KiB> for BuddyAllocator {
         let Ok(idx) = self.bitmap.alloc_range(1, 0) else {return None};
         let start = self.phys_start + idx.start * 4096;
         self.remaining -= 4096;
+        self.frames[idx.start] = FrameInfo {
+            ref_count: 1,
+            flags: FrameFlags::EMPTY,
+            owner: FrameOwner::Kernel,
+        };
         Some(PhysFrame::from_start_address(start).unwrap())
     }
 }
@@ -155,6 +267,7 @@ unsafe impl FrameAllocator<Size2MiB> for BuddyAllocator {
         let Ok(idx) = self.bitmap.alloc_range(1<<9, 0) else {return None};
         let start = self.phys_start + idx.start * 4096;
         self.remaining -= 4096 * 512;
+        self.mark(idx.start, 512, HUGE_FRAME);
         Some(PhysFrame::from_start_address(start).unwrap())
     }
 }
@@ -164,6 +277,7 @@ unsafe impl FrameAllocator<Size1GiB> for BuddyAllocator {
         let Ok(idx) = self.bitmap.alloc_range(1<<18, 0) else {return None};
         let start = self.phys_start + idx.start * 4096;
         self.remaining -= 4096 * 512 * 512;
+        self.mark(idx.start, 512 * 512, HUGE_FRAME);
         Some(PhysFrame::from_start_address(start).unwrap())
     }
 }
@@ -174,6 +288,14 @@ impl FrameDeallocator<Size4KiB> for BuddyAllocator {
         assert!(frame.start_address() - self.phys_start < self.region_size as u64);
 
         let idx = (frame.start_address() - self.phys_start) as usize / 4096;
+        // Only free the frame once nothing refers to it any more
+        let info = &mut self.frames[idx];
+        assert!(info.ref_count > 0, "attempt to free a free frame");
+        info.ref_count -= 1;
+        if info.ref_count > 0 {
+            return;
+        }
+        *info = FrameInfo::FREE;
         self.bitmap.dealloc_bit(idx, 0).unwrap();
         self.remaining += 4096;
     }
@@ -186,6 +308,7 @@ impl FrameDeallocator<Size2MiB> for BuddyAllocator {
 
         let idx = (frame.start_address() - self.phys_start) as usize / (4096 * 512);
         self.bitmap.dealloc_bit(idx, 9).unwrap();
+        self.mark(idx << 9, 512, FrameInfo::FREE);
         self.remaining += 4096 * 512;
     }
 }
@@ -197,6 +320,7 @@ impl FrameDeallocator<Size1GiB> for BuddyAllocator {
 
         let idx = (frame.start_address() - self.phys_start) as usize / (4096 * 512 * 512);
         self.bitmap.dealloc_bit(idx, 18).unwrap();
+        self.mark(idx << 18, 512 * 512, FrameInfo::FREE);
         self.remaining += 4096 * 512 * 512;
     }
 }
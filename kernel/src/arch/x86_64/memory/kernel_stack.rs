@@ -0,0 +1,117 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::structures::paging::Mapper;
+
+use super::{allocate_frame_for, deallocate_frame, map_page, FrameOwner, Page, VirtAddr, MAPPER};
+
+/// Kernel stacks live right after the heap, under the same level 4 entry, so every address space
+/// sees them.
+const KERNEL_STACKS_START: u64 = 0xFFFF_E040_0000_0000;
+pub const KERNEL_STACK_SIZE: usize = 64 * 1024;
+const PAGE_SIZE: u64 = 4096;
+/// Each slot holds an unmapped guard page followed by the stack itself.
+const SLOT_SIZE: u64 = PAGE_SIZE + KERNEL_STACK_SIZE as u64;
+const MAX_KERNEL_STACKS: usize = 4096;
+
+struct Slots {
+    /// Slots below this have been handed out at least once.
+    next: usize,
+    free: Vec<usize>,
+}
+
+static SLOTS: Mutex<Slots> = Mutex::new(Slots {
+    next: 0,
+    free: Vec::new(),
+});
+
+/// A kernel stack with a guard page below it, so overflowing it faults instead of silently
+/// corrupting whatever lies beneath. Unmapped and freed on drop.
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Returns `None` if we are out of memory or stack slots.
+    pub fn new() -> Option<Self> {
+        let slot = {
+            let mut slots = SLOTS.lock();
+            match slots.free.pop() {
+                Some(slot) => slot,
+                None if slots.next < MAX_KERNEL_STACKS => {
+                    slots.next += 1;
+                    slots.next - 1
+                }
+                None => return None,
+            }
+        };
+        let mut stack = KernelStack { slot };
+        for (i, page) in stack.pages().enumerate() {
+            let Some(frame) = allocate_frame_for(FrameOwner::KernelStack) else {
+                // Only unmap what was mapped, then give the slot back
+                stack.unmap(i);
+                core::mem::forget(stack);
+                SLOTS.lock().free.push(slot);
+                return None;
+            };
+            unsafe { map_page(page, frame) };
+        }
+        stack.as_mut_slice().fill(0);
+        Some(stack)
+    }
+
+    fn bottom(&self) -> VirtAddr {
+        VirtAddr::new(KERNEL_STACKS_START + self.slot as u64 * SLOT_SIZE + PAGE_SIZE)
+    }
+
+    /// The initial stack pointer: one past the highest address of the stack.
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + KERNEL_STACK_SIZE
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let first = Page::containing_address(self.bottom());
+        (0..KERNEL_STACK_SIZE as u64 / PAGE_SIZE).map(move |i| first + i)
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.bottom().as_mut_ptr(), KERNEL_STACK_SIZE) }
+    }
+
+    /// Unmaps and frees the first `n` pages of the stack.
+    fn unmap(&mut self, n: usize) {
+        let mut mapper = MAPPER.get().unwrap().lock();
+        for page in self.pages().take(n) {
+            let (frame, flush) = mapper
+                .unmap(page)
+                .expect("Kernel stack page was not mapped");
+            flush.flush();
+            unsafe { deallocate_frame(frame) };
+        }
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        self.unmap(KERNEL_STACK_SIZE / PAGE_SIZE as usize);
+        SLOTS.lock().free.push(self.slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn slots_are_reused() {
+        let mut a = KernelStack::new().unwrap();
+        let b = KernelStack::new().unwrap();
+        assert!(a.top() <= b.bottom() - PAGE_SIZE || b.top() <= a.bottom() - PAGE_SIZE);
+        a.as_mut_slice()[KERNEL_STACK_SIZE - 1] = 0xAA;
+
+        let slot = a.slot;
+        drop(a);
+        let c = KernelStack::new().unwrap();
+        assert_eq!(c.slot, slot);
+        assert_eq!(c.top().as_u64() % 16, 0);
+    }
+}
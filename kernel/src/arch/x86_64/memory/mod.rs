@@ -1,8 +1,8 @@
 use conquer_once::spin::OnceCell;
 use spin::Mutex;
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, OffsetPageTable, Page as X86Page, PageSize, PageTable, PageTableFlags,
-    PhysFrame as X86PhysFrame, Size4KiB,
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page as X86Page, PageSize,
+    PageTable, PageTableFlags, PhysFrame as X86PhysFrame, Size4KiB,
 };
 
 use self::mmap::MemoryRegion;
@@ -13,9 +13,13 @@ pub type PhysFrame = X86PhysFrame<Size4KiB>;
 pub type Page = X86Page<Size4KiB>;
 
 mod frame_allocator;
+mod kernel_stack;
 pub(super) mod mmap;
 pub mod space;
 
+pub use frame_allocator::{FrameFlags, FrameInfo, FrameOwner};
+pub use kernel_stack::{KernelStack, KERNEL_STACK_SIZE};
+
 const FALLOC_ENTRIES: usize = 16;
 
 pub static FRAME_ALLOCATOR: OnceCell<
@@ -30,6 +34,8 @@ pub static MAPPER: OnceCell<Mutex<OffsetPageTable>> = OnceCell::uninit();
 /// `memory_map` must be a valid memory map that does not include any in-use memory pages.
 pub(super) unsafe fn init(phys_mem_offset: VirtAddr, memory_map: &'static [MemoryRegion]) {
     PHYS_MEM_OFFSET = phys_mem_offset;
+    KERNEL_L4_FRAME = x86_64::registers::control::Cr3::read().0;
+    enable_no_execute();
     let lvl_4_page_table = get_page_table();
     MAPPER.init_once(|| Mutex::new(OffsetPageTable::new(lvl_4_page_table, phys_mem_offset)));
 
@@ -53,6 +59,18 @@ pub(super) unsafe fn init(phys_mem_offset: VirtAddr, memory_map: &'static [Memor
     FRAME_ALLOCATOR.init_once(|| Mutex::new(falloc));
 }
 
+/// Lets page tables mark memory as non-executable, if the CPU supports it. Each core has to do
+/// this for itself.
+pub fn enable_no_execute() {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+    let has_nx = unsafe { core::arch::x86_64::__cpuid(0x8000_0001) }.edx & (1 << 20) != 0;
+    if has_nx {
+        unsafe { Efer::update(|f| f.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    } else {
+        log::warn!("CPU does not support no-execute pages");
+    }
+}
+
 unsafe fn get_page_table() -> &'static mut PageTable {
     let cr3 = x86_64::registers::control::Cr3::read().0.start_address();
     let cr3_virt = phys_to_virt(cr3);
@@ -69,7 +87,58 @@ where
     FRAME_ALLOCATOR.get().unwrap().lock().allocate_frame()
 }
 
-pub fn deallocate_frame(_f: PhysFrame) {}
+/// Allocates a 4KiB frame, recording `owner` in its [`FrameInfo`]. Frames from
+/// [`allocate_frame`] belong to [`FrameOwner::Kernel`].
+pub fn allocate_frame_for(owner: FrameOwner) -> Option<PhysFrame> {
+    FRAME_ALLOCATOR
+        .get()
+        .unwrap()
+        .lock()
+        .allocate_frame_for(owner)
+}
+
+/// Gives back a reference to a frame obtained from [`allocate_frame`]. 4KiB frames return to
+/// the frame allocator once every reference taken with [`share_frame`] has been given back too.
+///
+/// # Safety
+/// The caller must not use the frame through this reference any more.
+pub unsafe fn deallocate_frame<S>(frame: X86PhysFrame<S>)
+where
+    S: PageSize,
+    frame_allocator::BuddyAllocatorManager<FALLOC_ENTRIES>:
+        x86_64::structures::paging::FrameDeallocator<S>,
+{
+    FRAME_ALLOCATOR
+        .get()
+        .unwrap()
+        .lock()
+        .deallocate_frame(frame)
+}
+
+/// Takes another reference to an allocated frame, so that it stays allocated until
+/// [`deallocate_frame`] has been called once more.
+pub fn share_frame(frame: PhysFrame) {
+    FRAME_ALLOCATOR.get().unwrap().lock().add_ref(frame)
+}
+
+/// How many references there are to an allocated frame.
+pub fn frame_ref_count(frame: PhysFrame) -> u16 {
+    frame_info(frame).ref_count
+}
+
+pub fn frame_info(frame: PhysFrame) -> FrameInfo {
+    FRAME_ALLOCATOR.get().unwrap().lock().frame_info(frame)
+}
+
+/// Switches to the page table the kernel booted with, which maps nothing in the lower half.
+pub fn load_kernel_space() {
+    unsafe {
+        x86_64::registers::control::Cr3::write(
+            KERNEL_L4_FRAME,
+            x86_64::registers::control::Cr3Flags::empty(),
+        )
+    }
+}
 
 /// # Safety
 /// See the [`x86_64::structures::paging::Mapper::map_to`] docs.
@@ -93,6 +162,8 @@ where
 }
 
 static mut PHYS_MEM_OFFSET: VirtAddr = VirtAddr::zero();
+static mut KERNEL_L4_FRAME: PhysFrame =
+    unsafe { PhysFrame::from_start_address_unchecked(PhysAddr::zero()) };
 
 pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
     unsafe { PHYS_MEM_OFFSET + phys.as_u64() }
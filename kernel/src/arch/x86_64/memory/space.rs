@@ -1,14 +1,83 @@
-use x86_64::structures::paging::{OffsetPageTable, PageTable, PhysFrame, Size4KiB};
+use alloc::collections::BTreeMap;
+use x86_64::structures::paging::{
+    OffsetPageTable, PageTable, PageTableFlags, PageTableIndex, PhysFrame, Size1GiB, Size2MiB,
+    Size4KiB,
+};
 
-use super::{allocate_frame, phys_to_virt, PhysAddr, MAPPER};
+use super::{
+    allocate_frame, allocate_frame_for, deallocate_frame, frame_ref_count, phys_to_virt,
+    FrameOwner, Page, PhysAddr, VirtAddr, MAPPER,
+};
+
+/// Page zero is never mapped, so that null pointers fault.
+pub const USER_SPACE_START: u64 = 0x1000;
+/// First address past the lower half, which is all that user programs may touch.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+/// The program and its `brk` heap live below this.
+pub const HEAP_END: u64 = 0x0000_0800_0000_0000;
+/// Range [`Space::map_anonymous`] picks addresses from.
+const MMAP_START: u64 = 0x0000_2000_0000_0000;
+const MMAP_END: u64 = 0x0000_7000_0000_0000;
+const PAGE_SIZE: u64 = 4096;
+/// Marks pages that are writable, but whose frame may be shared with another space after
+/// [`Space::fork`]. They are mapped read-only, and get a frame of their own on the first write.
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+/// Flags for the page tables leading to user pages; the last level decides the access rights.
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Page table flags for user memory with the given access rights. x86_64 cannot take read access
+/// away from writable or executable pages.
+///
+/// Inaccessible pages are not present, so that any access faults. Those that were touched before
+/// losing access keep their frame in the page table entry, for when they get it back.
+pub fn user_page_flags(read: bool, write: bool, exec: bool) -> PageTableFlags {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+    let mut flags = PageTableFlags::empty();
+    if read || write || exec {
+        flags |= PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    }
+    if write {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !exec && Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapError {
+    /// There are no frames left to back the memory.
+    OutOfMemory,
+    /// The range is misaligned, out of bounds or not available.
+    InvalidRange,
+}
+
+/// Anonymous memory handed out by [`Space::map_anonymous`]. Its pages are only mapped once they
+/// are first touched.
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    end: u64,
+    flags: PageTableFlags,
+}
 
 /// x86_64 address space.
 ///
 /// Addresses at or above 0xffff800000000000 belongs to global kernel space.
 /// Everything below belongs to this struct alone, and the corresponding page table directories will
-/// be freed once thie struct is dropped.
+/// be freed once this struct is dropped, along with every frame mapped there.
 pub struct Space {
     cr3: PhysAddr,
+    /// Anonymous mappings by start address. Page aligned and never overlapping.
+    regions: BTreeMap<u64, Region>,
+    heap_start: u64,
+    /// The program break: the end of the heap, which is backed up to the next page boundary.
+    brk: u64,
+    /// The stack may grow down to here, one page above its guard page.
+    stack_limit: u64,
+    stack_top: u64,
 }
 
 impl Space {
@@ -17,6 +86,11 @@ impl Space {
 
         let mut s = Space {
             cr3: page_table_frame.start_address(),
+            regions: BTreeMap::new(),
+            heap_start: 0,
+            brk: 0,
+            stack_limit: 0,
+            stack_top: 0,
         };
 
         // Copy all higher-half (kernel) page mappings
@@ -39,6 +113,427 @@ impl Space {
         unsafe { OffsetPageTable::new(page_table, super::PHYS_MEM_OFFSET) }
     }
 
+    /// Whether all of `start..start + len` lies in the lower half and is mapped user-accessible,
+    /// and also writable if `write` is set.
+    pub fn is_user_accessible(&mut self, start: u64, len: usize, write: bool) -> bool {
+        use x86_64::structures::paging::{mapper::TranslateResult, Translate};
+
+        if len == 0 {
+            return true;
+        }
+        let Some(end) = start.checked_add(len as u64 - 1) else {
+            return false;
+        };
+        if end >= USER_SPACE_END {
+            return false;
+        }
+
+        let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if write {
+            required |= PageTableFlags::WRITABLE;
+        }
+        let pt = self.page_table();
+        let pages = Page::range_inclusive(
+            Page::containing_address(VirtAddr::new(start)),
+            Page::containing_address(VirtAddr::new(end)),
+        );
+        pages.into_iter().all(|page| {
+            matches!(
+                pt.translate(page.start_address()),
+                TranslateResult::Mapped { flags, .. } if flags.contains(required)
+            )
+        })
+    }
+
+    /// Like [`Space::is_user_accessible`], but first maps any pages in the range that are due to be
+    /// mapped on first touch, so the kernel can access them on the user's behalf.
+    pub fn make_user_accessible(&mut self, start: u64, len: usize, write: bool) -> bool {
+        use x86_64::structures::paging::{mapper::TranslateResult, Translate};
+
+        if len == 0 {
+            return true;
+        }
+        let Some(end) = start.checked_add(len as u64) else {
+            return false;
+        };
+        if end > USER_SPACE_END {
+            return false;
+        }
+        for page in pages(start & !(PAGE_SIZE - 1), align_up(end)) {
+            let addr = page.start_address();
+            let needs_fault = match self.page_table().translate(addr) {
+                TranslateResult::NotMapped => true,
+                TranslateResult::Mapped { flags, .. } => write && flags.contains(COPY_ON_WRITE),
+                TranslateResult::InvalidFrameAddress(_) => false,
+            };
+            if needs_fault && !self.handle_fault(addr, write, false) {
+                return false;
+            }
+        }
+        self.is_user_accessible(start, len, write)
+    }
+
+    /// Reserves `top - size..top` for a stack that is mapped as it grows down. Its lowest page
+    /// is never mapped, so overflowing the stack faults.
+    pub fn set_stack(&mut self, top: u64, size: u64) {
+        self.stack_limit = top - size + PAGE_SIZE;
+        self.stack_top = top;
+    }
+
+    /// The permissions the page containing `addr` gets when it is first touched, if it is part
+    /// of the heap, the stack or an anonymous mapping.
+    fn lazy_flags(&self, addr: u64) -> Option<PageTableFlags> {
+        let rw = || user_page_flags(true, true, false);
+        if (self.heap_start..align_up(self.brk)).contains(&addr)
+            || (self.stack_limit..self.stack_top).contains(&addr)
+        {
+            return Some(rw());
+        }
+        let (_, region) = self.regions.range(..=addr).next_back()?;
+        (addr < region.end).then_some(region.flags)
+    }
+
+    /// Handles a page fault at `addr` from user mode by mapping a zeroed page, if the address is
+    /// due to be mapped on first touch and the access is allowed, or by copying a copy-on-write
+    /// page that is written to. Returns whether the access can be retried.
+    pub fn handle_fault(&mut self, addr: VirtAddr, write: bool, exec: bool) -> bool {
+        use x86_64::structures::paging::{
+            mapper::{MappedFrame, TranslateResult},
+            Translate,
+        };
+
+        let page = Page::containing_address(addr);
+        if let TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } = self.page_table().translate(page.start_address())
+        {
+            if write && flags.contains(COPY_ON_WRITE) {
+                return self.copy_on_write(page, frame, flags).is_ok();
+            }
+        }
+
+        let Some(flags) = self.lazy_flags(addr.as_u64()) else {
+            return false;
+        };
+        let allowed = flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            && (!write || flags.contains(PageTableFlags::WRITABLE))
+            && (!exec || !flags.contains(PageTableFlags::NO_EXECUTE));
+        if !allowed {
+            return false;
+        }
+        // A fault on a present page is a protection violation, which mapping can't fix
+        if !matches!(
+            self.page_table().translate(page.start_address()),
+            TranslateResult::NotMapped
+        ) {
+            return false;
+        }
+        self.map_zeroed_page(page, flags).is_ok()
+    }
+
+    /// Starts the heap, empty, at the page aligned `start`. The loader calls this with the end of
+    /// the program.
+    pub fn set_heap_start(&mut self, start: u64) {
+        self.heap_start = start;
+        self.brk = start;
+    }
+
+    /// Moves the end of the heap to `new_brk` and returns it, freeing pages the heap shrinks away
+    /// from. A `new_brk` of 0 only returns the current end.
+    pub fn brk(&mut self, new_brk: u64) -> Result<u64, MapError> {
+        if new_brk == 0 {
+            return Ok(self.brk);
+        }
+        if new_brk < self.heap_start || new_brk > HEAP_END {
+            return Err(MapError::InvalidRange);
+        }
+        let old_end = align_up(self.brk);
+        let new_end = align_up(new_brk);
+        if new_end < old_end {
+            self.unmap_range(new_end, old_end);
+        }
+        self.brk = new_brk;
+        Ok(new_brk)
+    }
+
+    /// Reserves `len` bytes of zeroed memory with `flags` somewhere free, and returns its address.
+    pub fn map_anonymous(&mut self, len: usize, flags: PageTableFlags) -> Result<u64, MapError> {
+        let size = (len as u64)
+            .checked_next_multiple_of(PAGE_SIZE)
+            .filter(|&size| size > 0)
+            .ok_or(MapError::InvalidRange)?;
+        // First fit
+        let mut start = MMAP_START;
+        for (&region_start, region) in self.regions.range(MMAP_START..) {
+            if region_start - start >= size {
+                break;
+            }
+            start = region.end;
+        }
+        if MMAP_END - start < size {
+            return Err(MapError::OutOfMemory);
+        }
+        self.regions.insert(
+            start,
+            Region {
+                end: start + size,
+                flags,
+            },
+        );
+        Ok(start)
+    }
+
+    /// Unmaps and frees whatever anonymous memory lies in `addr..addr + len`.
+    pub fn unmap_anonymous(&mut self, addr: u64, len: usize) -> Result<(), MapError> {
+        let end = anonymous_range(addr, len)?;
+        self.split_region_at(addr);
+        self.split_region_at(end);
+        let starts: alloc::vec::Vec<u64> = self.regions.range(addr..end).map(|(&s, _)| s).collect();
+        for start in starts {
+            let region = self.regions.remove(&start).unwrap();
+            self.unmap_range(start, region.end);
+        }
+        Ok(())
+    }
+
+    /// Changes the access rights of the anonymous memory in `addr..addr + len` to `flags`. All of
+    /// the range must have been handed out by [`Space::map_anonymous`].
+    pub fn protect_anonymous(
+        &mut self,
+        addr: u64,
+        len: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        use x86_64::structures::paging::Mapper;
+
+        let end = anonymous_range(addr, len)?;
+        // Check that the regions cover the whole range before changing anything
+        let mut covered = addr;
+        if let Some((_, region)) = self.regions.range(..=addr).next_back() {
+            covered = covered.max(region.end.min(end));
+        }
+        for (&start, region) in self.regions.range(addr..end) {
+            if start > covered {
+                break;
+            }
+            covered = covered.max(region.end);
+        }
+        if covered < end {
+            return Err(MapError::InvalidRange);
+        }
+
+        self.split_region_at(addr);
+        self.split_region_at(end);
+        for (_, region) in self.regions.range_mut(addr..end) {
+            region.flags = flags;
+        }
+        let mut page_table = self.page_table();
+        for page in pages(addr, end) {
+            // Pages that were never touched pick up the new flags when they are
+            let Ok(frame) = page_table.translate_page(page) else {
+                continue;
+            };
+            let mut page_flags = flags;
+            // Writing to a frame that is still shared must copy it first
+            if flags.contains(PageTableFlags::WRITABLE) && frame_ref_count(frame) > 1 {
+                page_flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            }
+            unsafe { page_table.update_flags(page, page_flags) }
+                .unwrap()
+                .flush();
+        }
+        Ok(())
+    }
+
+    /// Creates a copy of this space. Both share every mapped frame, and writable pages are made
+    /// copy-on-write in both, so that neither sees the other's writes.
+    pub fn fork(&mut self) -> Result<Space, MapError> {
+        use x86_64::structures::paging::{FrameDeallocator, Mapper};
+
+        let mut child = Space::new();
+        child.regions = self.regions.clone();
+        child.heap_start = self.heap_start;
+        child.brk = self.brk;
+        child.stack_limit = self.stack_limit;
+        child.stack_top = self.stack_top;
+
+        let mut child_table = child.page_table();
+        let mut fa = super::FRAME_ALLOCATOR.get().unwrap().lock();
+        let l4 = unsafe { table_at_mut(self.cr3) };
+        for (i4, l4e) in l4.iter().enumerate().take(256) {
+            if !l4e.flags().contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let l3 = unsafe { table_at_mut(l4e.addr()) };
+            for (i3, l3e) in l3.iter().enumerate() {
+                // User memory is only ever mapped with 4KiB pages
+                if !l3e.flags().contains(PageTableFlags::PRESENT) {
+                    continue;
+                }
+                let l2 = unsafe { table_at_mut(l3e.addr()) };
+                for (i2, l2e) in l2.iter().enumerate() {
+                    if !l2e.flags().contains(PageTableFlags::PRESENT) {
+                        continue;
+                    }
+                    let l1 = unsafe { table_at_mut(l2e.addr()) };
+                    for (i1, l1e) in l1.iter_mut().enumerate() {
+                        // Inaccessible pages are shared too, though not present
+                        if l1e.is_unused() {
+                            continue;
+                        }
+                        let mut flags = l1e.flags();
+                        if flags.contains(PageTableFlags::WRITABLE) {
+                            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                            l1e.set_flags(flags);
+                        }
+                        let frame = PhysFrame::<Size4KiB>::containing_address(l1e.addr());
+                        let page = Page::from_page_table_indices(
+                            PageTableIndex::new(i4 as u16),
+                            PageTableIndex::new(i3 as u16),
+                            PageTableIndex::new(i2 as u16),
+                            PageTableIndex::new(i1 as u16),
+                        );
+                        fa.add_ref(frame);
+                        let mapped = unsafe {
+                            child_table.map_to_with_table_flags(
+                                page,
+                                frame,
+                                flags,
+                                USER_TABLE_FLAGS,
+                                &mut *fa,
+                            )
+                        };
+                        if let Ok(flush) = mapped {
+                            // The child is not loaded anywhere
+                            flush.ignore();
+                        } else {
+                            unsafe { fa.deallocate_frame(frame) };
+                            x86_64::instructions::tlb::flush_all();
+                            return Err(MapError::OutOfMemory);
+                        }
+                    }
+                }
+            }
+        }
+        // Our writable pages just became read-only
+        x86_64::instructions::tlb::flush_all();
+        Ok(child)
+    }
+
+    /// Gives `page`, which is mapped copy-on-write to `frame`, a frame of its own that it can
+    /// write to. The copy is skipped if no other space shares the frame any more.
+    fn copy_on_write(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        use x86_64::structures::paging::Mapper;
+
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        let mut page_table = self.page_table();
+        if frame_ref_count(frame) == 1 {
+            unsafe { page_table.update_flags(page, flags) }
+                .unwrap()
+                .flush();
+            return Ok(());
+        }
+
+        let copy = allocate_frame_for(FrameOwner::User).ok_or(MapError::OutOfMemory)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                PAGE_SIZE as usize,
+            );
+            page_table.unmap(page).unwrap().1.flush();
+            let mut fa = super::FRAME_ALLOCATOR.get().unwrap().lock();
+            // The tables are still there, so this can't fail
+            page_table
+                .map_to_with_table_flags(page, copy, flags, USER_TABLE_FLAGS, &mut *fa)
+                .unwrap()
+                .flush();
+        }
+        unsafe { deallocate_frame(frame) };
+        Ok(())
+    }
+
+    /// Splits the region containing `addr`, if any, so that one starts right at `addr`.
+    fn split_region_at(&mut self, addr: u64) {
+        let Some((&start, region)) = self.regions.range_mut(..addr).next_back() else {
+            return;
+        };
+        if region.end <= addr {
+            return;
+        }
+        let tail = Region {
+            end: region.end,
+            flags: region.flags,
+        };
+        region.end = addr;
+        debug_assert!(start < addr);
+        self.regions.insert(addr, tail);
+    }
+
+    /// Maps a fresh zeroed frame at `page`, which must not be mapped yet.
+    fn map_zeroed_page(&mut self, page: Page, flags: PageTableFlags) -> Result<(), MapError> {
+        use x86_64::structures::paging::Mapper;
+
+        let frame = allocate_frame_for(FrameOwner::User).ok_or(MapError::OutOfMemory)?;
+        unsafe {
+            let ptr = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+            core::slice::from_raw_parts_mut(ptr, PAGE_SIZE as usize).fill(0);
+        }
+        let result = {
+            let mut fa = super::FRAME_ALLOCATOR.get().unwrap().lock();
+            unsafe {
+                self.page_table().map_to_with_table_flags(
+                    page,
+                    frame,
+                    flags,
+                    USER_TABLE_FLAGS,
+                    &mut *fa,
+                )
+            }
+        };
+        match result {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(_) => {
+                unsafe { deallocate_frame(frame) };
+                Err(MapError::InvalidRange)
+            }
+        }
+    }
+
+    /// Unmaps `start..end` and frees the frames that were mapped there.
+    fn unmap_range(&mut self, start: u64, end: u64) {
+        use x86_64::structures::paging::{mapper::TranslateResult, Mapper, Translate};
+
+        let mut page_table = self.page_table();
+        for page in pages(start, end) {
+            // `unmap` only takes present pages, which inaccessible ones are not. They can't be in
+            // the TLB, so there is nothing to flush.
+            if let TranslateResult::Mapped { flags, .. } =
+                page_table.translate(page.start_address())
+            {
+                if !flags.contains(PageTableFlags::PRESENT) {
+                    unsafe { page_table.update_flags(page, flags | PageTableFlags::PRESENT) }
+                        .unwrap()
+                        .ignore();
+                }
+            }
+            if let Ok((frame, flush)) = page_table.unmap(page) {
+                flush.flush();
+                unsafe { deallocate_frame(frame) };
+            }
+        }
+    }
+
     pub fn load(&mut self) {
         unsafe {
             x86_64::registers::control::Cr3::write(
@@ -48,3 +543,248 @@ impl Space {
         }
     }
 }
+
+impl Drop for Space {
+    fn drop(&mut self) {
+        // Never pull the page tables out from under ourselves
+        if x86_64::registers::control::Cr3::read().0.start_address() == self.cr3 {
+            super::load_kernel_space();
+        }
+
+        let l4 = unsafe { table_at(self.cr3) };
+        for l4e in l4.iter().take(256) {
+            if !l4e.flags().contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let l3 = unsafe { table_at(l4e.addr()) };
+            for l3e in l3.iter() {
+                let flags = l3e.flags();
+                if !flags.contains(PageTableFlags::PRESENT) {
+                    continue;
+                }
+                if flags.contains(PageTableFlags::HUGE_PAGE) {
+                    unsafe {
+                        deallocate_frame(PhysFrame::<Size1GiB>::containing_address(l3e.addr()))
+                    };
+                    continue;
+                }
+                let l2 = unsafe { table_at(l3e.addr()) };
+                for l2e in l2.iter() {
+                    let flags = l2e.flags();
+                    if !flags.contains(PageTableFlags::PRESENT) {
+                        continue;
+                    }
+                    if flags.contains(PageTableFlags::HUGE_PAGE) {
+                        unsafe {
+                            deallocate_frame(PhysFrame::<Size2MiB>::containing_address(l2e.addr()))
+                        };
+                        continue;
+                    }
+                    let l1 = unsafe { table_at(l2e.addr()) };
+                    for l1e in l1.iter() {
+                        // Inaccessible pages hold a frame without being present
+                        if !l1e.is_unused() {
+                            unsafe {
+                                deallocate_frame(PhysFrame::<Size4KiB>::containing_address(
+                                    l1e.addr(),
+                                ))
+                            };
+                        }
+                    }
+                    unsafe { free_table(l2e.addr()) };
+                }
+                unsafe { free_table(l3e.addr()) };
+            }
+            unsafe { free_table(l4e.addr()) };
+        }
+        unsafe { free_table(self.cr3) };
+    }
+}
+
+fn align_up(addr: u64) -> u64 {
+    addr.next_multiple_of(PAGE_SIZE)
+}
+
+/// The pages in `start..end`, both page aligned.
+fn pages(start: u64, end: u64) -> impl Iterator<Item = Page> {
+    Page::range(
+        Page::containing_address(VirtAddr::new(start)),
+        Page::containing_address(VirtAddr::new(end)),
+    )
+}
+
+/// Checks an `addr..addr + len` range passed to `munmap` or `mprotect`, and returns its end
+/// rounded up to a page.
+fn anonymous_range(addr: u64, len: usize) -> Result<u64, MapError> {
+    if addr % PAGE_SIZE != 0 || len == 0 {
+        return Err(MapError::InvalidRange);
+    }
+    addr.checked_add(len as u64)
+        .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE))
+        .filter(|&end| end <= USER_SPACE_END)
+        .ok_or(MapError::InvalidRange)
+}
+
+/// # Safety
+/// `addr` must be the address of a page table that is not modified for the lifetime of the
+/// returned reference.
+unsafe fn table_at<'a>(addr: PhysAddr) -> &'a PageTable {
+    &*phys_to_virt(addr).as_ptr::<PageTable>()
+}
+
+/// # Safety
+/// `addr` must be the address of a page table that is not otherwise accessed for the lifetime of
+/// the returned reference.
+unsafe fn table_at_mut<'a>(addr: PhysAddr) -> &'a mut PageTable {
+    &mut *phys_to_virt(addr).as_mut_ptr::<PageTable>()
+}
+
+/// # Safety
+/// The page table at `addr` must no longer be referenced by any other table or by CR3.
+unsafe fn free_table(addr: PhysAddr) {
+    deallocate_frame(PhysFrame::<Size4KiB>::containing_address(addr));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn user_access_checks() {
+        let mut space = Space::new();
+        assert!(space.is_user_accessible(0x1000, 0, true));
+        assert!(!space.is_user_accessible(0x1000, 1, false));
+        assert!(!space.is_user_accessible(USER_SPACE_END - 1, 2, false));
+        assert!(!space.is_user_accessible(u64::MAX, 1, false));
+        // The kernel is mapped, but never for users
+        let kernel = Space::new as usize as u64;
+        assert!(!space.is_user_accessible(kernel, 1, false));
+    }
+
+    #[test_case]
+    fn anonymous_regions() {
+        let mut space = Space::new();
+        let rw = user_page_flags(true, true, false);
+        let a = space.map_anonymous(3 * 4096, rw).unwrap();
+        let b = space.map_anonymous(1, rw).unwrap();
+        assert_eq!(b, a + 3 * 4096);
+        // Nothing is mapped until touched
+        assert!(!space.is_user_accessible(a, 1, false));
+        assert!(space.make_user_accessible(a, 4 * 4096, true));
+        assert!(space.is_user_accessible(a, 4 * 4096, true));
+
+        // Punch a hole in the middle, which the next mapping of that size fills
+        space.unmap_anonymous(a + 4096, 4096).unwrap();
+        assert!(!space.make_user_accessible(a + 4096, 1, false));
+        assert_eq!(space.map_anonymous(4096, rw), Ok(a + 4096));
+
+        let ro = user_page_flags(true, false, false);
+        space.protect_anonymous(a, 2 * 4096, ro).unwrap();
+        assert!(space.make_user_accessible(a, 2 * 4096, false));
+        assert!(!space.make_user_accessible(a + 4096, 1, true));
+        assert!(space.make_user_accessible(a + 2 * 4096, 1, true));
+        assert!(space.protect_anonymous(b + 4096, 4096, ro).is_err());
+    }
+
+    #[test_case]
+    fn stack_grows_down_to_guard() {
+        let mut space = Space::new();
+        let top = 0x1000_0000_0000;
+        space.set_stack(top, 4 * 4096);
+        assert!(space.handle_fault(VirtAddr::new(top - 1), true, false));
+        assert!(space.handle_fault(VirtAddr::new(top - 3 * 4096), true, false));
+        // Not executable, and the lowest page is the guard
+        assert!(!space.handle_fault(VirtAddr::new(top - 2 * 4096), false, true));
+        assert!(!space.handle_fault(VirtAddr::new(top - 4 * 4096), true, false));
+        assert!(space.is_user_accessible(top - 3 * 4096, 4096, true));
+    }
+
+    fn frame_at(space: &mut Space, addr: u64) -> PhysFrame {
+        use x86_64::structures::paging::Mapper;
+
+        let page = Page::containing_address(VirtAddr::new(addr));
+        space.page_table().translate_page(page).unwrap()
+    }
+
+    #[test_case]
+    fn fork_copies_on_write() {
+        let mut parent = Space::new();
+        let rw = user_page_flags(true, true, false);
+        let a = parent.map_anonymous(2 * 4096, rw).unwrap();
+        assert!(parent.make_user_accessible(a, 2 * 4096, true));
+        let mut child = parent.fork().unwrap();
+        let shared = frame_at(&mut parent, a);
+        assert_eq!(frame_at(&mut child, a), shared);
+        assert_eq!(frame_ref_count(shared), 2);
+        assert!(!parent.is_user_accessible(a, 1, true));
+
+        // The first write gets its own frame, after which the other side is the only owner left
+        assert!(child.make_user_accessible(a, 1, true));
+        assert_ne!(frame_at(&mut child, a), shared);
+        assert!(parent.make_user_accessible(a, 1, true));
+        assert_eq!(frame_at(&mut parent, a), shared);
+
+        let second = frame_at(&mut child, a + 4096);
+        drop(child);
+        assert_eq!(frame_ref_count(second), 1);
+    }
+
+    #[test_case]
+    fn drop_frees_frames_and_tables() {
+        use super::super::frame_info;
+
+        let mut space = Space::new();
+        let a = space
+            .map_anonymous(4096, user_page_flags(true, true, false))
+            .unwrap();
+        assert!(space.make_user_accessible(a, 1, true));
+        let frame = frame_at(&mut space, a);
+        let l4 = PhysFrame::containing_address(space.cr3);
+        assert_eq!(frame_info(frame).owner, FrameOwner::User);
+        assert_eq!(frame_info(l4).owner, FrameOwner::Kernel);
+
+        drop(space);
+        assert_eq!(frame_info(frame).owner, FrameOwner::Free);
+        assert_eq!(frame_info(l4).ref_count, 0);
+    }
+
+    #[test_case]
+    fn inaccessible_pages() {
+        use super::super::frame_info;
+
+        let mut space = Space::new();
+        let none = user_page_flags(false, false, false);
+        let rw = user_page_flags(true, true, false);
+        let a = space.map_anonymous(4096, none).unwrap();
+        assert!(!space.handle_fault(VirtAddr::new(a), false, false));
+
+        // A touched page keeps its frame while inaccessible, in forks too
+        space.protect_anonymous(a, 4096, rw).unwrap();
+        assert!(space.make_user_accessible(a, 1, true));
+        let frame = frame_at(&mut space, a);
+        space.protect_anonymous(a, 4096, none).unwrap();
+        assert!(!space.make_user_accessible(a, 1, false));
+        drop(space.fork().unwrap());
+        assert_eq!(frame_ref_count(frame), 1);
+        space.protect_anonymous(a, 4096, rw).unwrap();
+        assert!(space.is_user_accessible(a, 1, true));
+        assert_eq!(frame_at(&mut space, a), frame);
+
+        space.protect_anonymous(a, 4096, none).unwrap();
+        space.unmap_anonymous(a, 4096).unwrap();
+        assert_eq!(frame_info(frame).owner, FrameOwner::Free);
+    }
+
+    #[test_case]
+    fn brk_grows_and_shrinks() {
+        let mut space = Space::new();
+        space.set_heap_start(0x1000_0000);
+        assert_eq!(space.brk(0), Ok(0x1000_0000));
+        assert_eq!(space.brk(0x1000_2800), Ok(0x1000_2800));
+        assert!(space.make_user_accessible(0x1000_0000, 0x3000, true));
+        assert_eq!(space.brk(0x1000_1000), Ok(0x1000_1000));
+        assert!(!space.is_user_accessible(0x1000_1000, 1, false));
+        assert!(!space.make_user_accessible(0x1000_1000, 1, false));
+        assert!(space.brk(0x1000_0000).is_err());
+    }
+}
@@ -3,7 +3,8 @@ pub mod gdt;
 mod init;
 pub mod interrupts;
 pub mod memory;
-mod syscall;
+pub mod syscall;
+pub mod time;
 
 pub fn loop_forever() -> ! {
     loop {
@@ -1,40 +1,146 @@
-use core::{arch::asm, ptr::addr_of};
+use core::{arch::asm, mem::offset_of, ptr::addr_of_mut};
 
-use crate::util::Align16;
-use kernel_uapi::syscall::{Syscall, SyscallResult};
+use super::cpu::{Context, Registers, MAX_CORES};
+use super::memory::KernelStack;
 
-const STACK_LEN: usize = 32 * 1024;
-static mut STACK: Align16<[u8; STACK_LEN]> = Align16([0; STACK_LEN]);
-static mut SYSCALL_RSP: *const u8 = unsafe { addr_of!(STACK.0[0]).add(STACK_LEN) };
-static mut RETURN_RSP: *const u8 = core::ptr::null();
+/// Per-core state the syscall entry reaches through `gs`, before it has a stack to work with.
+#[repr(C)]
+struct SyscallStacks {
+    /// Top of the kernel stack of the process running on this core.
+    kernel_rsp: u64,
+    /// Scratch space for the user stack pointer while switching stacks.
+    user_rsp: u64,
+}
 
-#[naked]
-unsafe extern "C" fn _syscall_handler() {
-    extern "C" fn syscall_handler(syscall: &mut Syscall, out: *mut SyscallResult) {
-        unsafe { *out = crate::syscall::syscall_handler(syscall) }
+static mut SYSCALL_STACKS: [SyscallStacks; MAX_CORES] = [const {
+    SyscallStacks {
+        kernel_rsp: 0,
+        user_rsp: 0,
     }
+}; MAX_CORES];
 
+/// What the syscall entry saves at the top of the kernel stack: everything needed to return to
+/// user mode, including the callee-saved registers, so that a copy of it can return too.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct SyscallFrame {
+    /// The pointer the result is written to.
+    out: u64,
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbp: u64,
+    rbx: u64,
+    rflags: u64,
+    rip: u64,
+    rsp: u64,
+}
+
+const _: () = assert!(core::mem::size_of::<SyscallFrame>() % 16 == 0);
+
+#[naked]
+unsafe extern "C" fn _syscall_handler() {
+    // Interrupts are masked on entry, and must stay so until we are off the user stack
     asm! {
-        "mov [{ret_rsp} + rip], rsp",
-        "mov rsp, [{sys_rsp} + rip]",
+        "swapgs",
+        "mov gs:[{user_rsp}], rsp",
+        "mov rsp, gs:[{kernel_rsp}]",
+        "push qword ptr gs:[{user_rsp}]",
+        "swapgs",
+        "sti",
         "push rcx",
         "push r11",
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "push rsi",
         "call {syscall_handler}",
+        "jmp {syscall_return}",
+        kernel_rsp = const offset_of!(SyscallStacks, kernel_rsp),
+        user_rsp = const offset_of!(SyscallStacks, user_rsp),
+        syscall_handler = sym crate::syscall::syscall_handler,
+        syscall_return = sym syscall_return,
+        options(noreturn)
+    }
+}
+
+/// Returns to user mode through the [`SyscallFrame`] at the stack pointer.
+#[naked]
+unsafe extern "C" fn syscall_return() {
+    asm! {
+        "cli",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
         "pop r11",
         "pop rcx",
-        "mov [{sys_rsp} + rip], rsp",
-        "mov rsp, [{ret_rsp} + rip]",
+        "pop rsp",
         "sysretq",
-        sys_rsp = sym SYSCALL_RSP,
-        ret_rsp = sym RETURN_RSP,
-        syscall_handler = sym syscall_handler,
         options(noreturn)
     }
 }
 
+/// Where a forked process starts: it finishes the parent's syscall with its own result, then
+/// returns to user mode through its copy of the parent's [`SyscallFrame`].
+#[naked]
+unsafe extern "C" fn fork_return() {
+    asm! {
+        "mov rdi, [rsp]",
+        "call {fork_child_result}",
+        "jmp {syscall_return}",
+        fork_child_result = sym crate::syscall::fork_child_result,
+        syscall_return = sym syscall_return,
+        options(noreturn)
+    }
+}
+
+/// Prepares `stack` to return from the syscall in progress on `parent`, the kernel stack of the
+/// calling process, as a process created by `fork`. Returns the context to switch to.
+///
+/// # Safety
+/// A syscall must be in progress on `parent`.
+pub unsafe fn fork_stack(parent: &KernelStack, stack: &mut KernelStack) -> *mut Context {
+    let frame_size = core::mem::size_of::<SyscallFrame>();
+    let frame = parent.top().as_ptr::<SyscallFrame>().sub(1).read();
+
+    let top = stack.top();
+    let frame_ptr = top.as_mut_ptr::<SyscallFrame>().sub(1);
+    frame_ptr.write(frame);
+    let context_ptr = (top - frame_size).as_mut_ptr::<Context>().sub(1);
+    context_ptr.write(Context {
+        registers: Registers::default(),
+        rip: fork_return as usize as u64,
+    });
+    context_ptr
+}
+
+/// Points `gs` at the syscall stacks of this core, for use after `swapgs`.
+pub(super) fn init_this_cpu(cpu_id: usize) {
+    use x86_64::registers::model_specific::KernelGsBase;
+    let stacks = unsafe { addr_of_mut!(SYSCALL_STACKS[cpu_id]) };
+    KernelGsBase::write(x86_64::VirtAddr::from_ptr(stacks));
+}
+
+/// Sets the stack syscalls on core `cpu_id` run on.
+///
+/// # Safety
+/// `top` must be the top of a mapped stack that stays alive until the next call.
+pub(super) unsafe fn set_kernel_stack(cpu_id: usize, top: x86_64::VirtAddr) {
+    SYSCALL_STACKS[cpu_id].kernel_rsp = top.as_u64();
+}
+
 pub fn init() {
     use x86_64::{
-        registers::model_specific::{Efer, EferFlags, LStar, Star},
+        registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        registers::rflags::RFlags,
         VirtAddr,
     };
     // Enable the SYSCALL/SYSRET instructions
@@ -43,6 +149,8 @@ pub fn init() {
     }
     // Load the syscall function pointer into IA32_LSTAR
     LStar::write(VirtAddr::new(_syscall_handler as *const () as usize as u64));
+    // Enter with interrupts off, so none can arrive while we are still on the user stack
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
 
     use super::gdt::SELECTORS;
     let kernel_cs = SELECTORS.code_selector;
@@ -0,0 +1,185 @@
+//! Clock sources: the TSC, calibrated against the PIT at boot, provides a nanosecond clock, and the
+//! local APIC timer of each core, calibrated against the TSC, drives its timer interrupt.
+//!
+//! The APIC timers run in one-shot mode, armed for the next timer tick or for an earlier deadline
+//! from [`wake_by`], so that timers don't have to wait for a tick to fire.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use super::cpu::apic::ApicRegisters;
+use super::cpu::{this_cpu, MAX_CORES};
+
+/// Input frequency of the PIT.
+const PIT_HZ: u64 = 1_193_182;
+/// Rate we would like the timer interrupt to fire at.
+const TARGET_TICK_HZ: u64 = 1000;
+
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Controls the gate of PIT channel 2 and exposes its output.
+const PIT_CHANNEL_2_GATE: u16 = 0x61;
+
+/// How long we spend measuring the TSC against the PIT.
+const CALIBRATION_MS: u64 = 10;
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// Rate the local APIC timers count down at. Taken to be the same on all cores.
+static APIC_TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+/// Initial count of the APIC timer for one timer tick.
+static APIC_TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// When the next timer tick of each core is due, on the [`now_ns`] clock.
+static NEXT_TICK: [AtomicU64; MAX_CORES] = [const { AtomicU64::new(0) }; MAX_CORES];
+/// When the timer interrupt of each core is armed to fire, or zero until it is started. Only
+/// touched by the core itself, with interrupts disabled.
+static ARMED: [AtomicU64; MAX_CORES] = [const { AtomicU64::new(0) }; MAX_CORES];
+
+/// Calibrates the TSC and the local APIC timer. The timer interrupt only starts with
+/// [`init_this_cpu`].
+///
+/// # Safety
+/// Must be called once, with interrupts disabled, before anything uses the timer, and after the
+/// local APIC can be reached.
+pub(super) unsafe fn init() {
+    let tsc_hz = calibrate_tsc();
+    TSC_HZ.store(tsc_hz, Ordering::Relaxed);
+    let apic_timer_hz = calibrate_apic_timer();
+    APIC_TIMER_HZ.store(apic_timer_hz, Ordering::Relaxed);
+    let count = (apic_timer_hz + TARGET_TICK_HZ / 2) / TARGET_TICK_HZ;
+    APIC_TIMER_COUNT.store(count.clamp(1, u32::MAX as u64) as u32, Ordering::Relaxed);
+    log::info!(
+        "Timer tick: {} ns, TSC: {} MHz, APIC timer: {} MHz",
+        tick_period_ns(),
+        tsc_hz / 1_000_000,
+        apic_timer_hz / 1_000_000
+    );
+    if !has_invariant_tsc() {
+        log::warn!("TSC is not invariant, nanosecond timestamps may drift");
+    }
+}
+
+/// Counts TSC cycles during a one-shot countdown on PIT channel 2.
+unsafe fn calibrate_tsc() -> u64 {
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel_2 = Port::<u8>::new(PIT_CHANNEL_2);
+    let mut gate = Port::<u8>::new(PIT_CHANNEL_2_GATE);
+
+    let count = PIT_HZ * CALIBRATION_MS / 1000;
+    let old_gate = gate.read();
+    // Raise the gate, but keep the speaker off
+    gate.write((old_gate & !0x02) | 0x01);
+    // Channel 2, lobyte/hibyte, interrupt on terminal count
+    command.write(0b1011_0000);
+    channel_2.write(count as u8);
+    channel_2.write((count >> 8) as u8);
+
+    let start = rdtsc();
+    while gate.read() & 0x20 == 0 {
+        core::hint::spin_loop();
+    }
+    let end = rdtsc();
+    gate.write(old_gate);
+
+    (end - start) * 1000 / CALIBRATION_MS
+}
+
+/// Counts down the local APIC timer for a while as measured by the TSC.
+unsafe fn calibrate_apic_timer() -> u64 {
+    let apic = ApicRegisters::get().as_mut();
+    apic.start_timer_masked();
+    let start = now_ns();
+    while now_ns() - start < CALIBRATION_MS * 1_000_000 {
+        core::hint::spin_loop();
+    }
+    let counted = u32::MAX - apic.timer_count();
+    apic.stop_timer();
+
+    counted as u64 * 1000 / CALIBRATION_MS
+}
+
+/// Starts the timer interrupt on this core.
+pub(super) fn init_this_cpu() {
+    assert_ne!(
+        APIC_TIMER_COUNT.load(Ordering::Relaxed),
+        0,
+        "APIC timer has not been calibrated"
+    );
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let cpu = this_cpu().id();
+        let now = now_ns();
+        NEXT_TICK[cpu].store(now + tick_period_ns(), Ordering::Relaxed);
+        arm(cpu, now + tick_period_ns(), now);
+    })
+}
+
+/// Arms the timer interrupt of core `cpu`, which must be the calling one, to fire at
+/// `deadline_ns`.
+fn arm(cpu: usize, deadline_ns: u64, now: u64) {
+    ARMED[cpu].store(deadline_ns, Ordering::Relaxed);
+    let apic_timer_hz = APIC_TIMER_HZ.load(Ordering::Relaxed) as u128;
+    let ns = deadline_ns.saturating_sub(now) as u128;
+    // Rounded up, so that it doesn't fire before the deadline
+    let count = (ns * apic_timer_hz).div_ceil(1_000_000_000);
+    let apic = unsafe { ApicRegisters::get().as_mut() };
+    apic.start_one_shot_timer(
+        super::interrupts::TIMER_VEC,
+        count.clamp(1, u32::MAX as u128) as u32,
+    );
+}
+
+/// Makes sure the timer interrupt fires on this core by `deadline_ns`, even if that is before
+/// its next tick.
+pub fn wake_by(deadline_ns: u64) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let cpu = this_cpu().id();
+        if deadline_ns < ARMED[cpu].load(Ordering::Relaxed) {
+            arm(cpu, deadline_ns, now_ns());
+        }
+    })
+}
+
+/// Arms the timer interrupt of this core again, for its next tick. Returns whether this
+/// interrupt is for a tick, rather than for a deadline from [`wake_by`]. Called from the timer
+/// interrupt.
+pub(super) fn on_timer_interrupt() -> bool {
+    let cpu = this_cpu().id();
+    let now = now_ns();
+    let period = tick_period_ns();
+    let mut next_tick = NEXT_TICK[cpu].load(Ordering::Relaxed);
+    // The APIC timer and the TSC don't quite agree, so a tick may come in a little early
+    let ticked = now >= next_tick.saturating_sub(period / 16);
+    if ticked {
+        // Ticks that were missed are not made up for
+        next_tick = (next_tick + period).max(now + period / 2);
+        NEXT_TICK[cpu].store(next_tick, Ordering::Relaxed);
+    }
+    arm(cpu, next_tick, now);
+    ticked
+}
+
+fn has_invariant_tsc() -> bool {
+    let max_extended = unsafe { core::arch::x86_64::__cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0007
+        && unsafe { core::arch::x86_64::__cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// The actual time between two timer interrupts, which is not quite 1 ms as the APIC timer
+/// cannot divide its clock evenly.
+pub fn tick_period_ns() -> u64 {
+    let apic_timer_hz = APIC_TIMER_HZ.load(Ordering::Relaxed);
+    assert_ne!(apic_timer_hz, 0, "APIC timer has not been calibrated");
+    APIC_TIMER_COUNT.load(Ordering::Relaxed) as u64 * 1_000_000_000 / apic_timer_hz
+}
+
+/// Nanoseconds since some point during boot.
+pub fn now_ns() -> u64 {
+    let tsc_hz = TSC_HZ.load(Ordering::Relaxed);
+    assert_ne!(tsc_hz, 0, "TSC has not been calibrated");
+    (rdtsc() as u128 * 1_000_000_000 / tsc_hz as u128) as u64
+}
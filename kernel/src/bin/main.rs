@@ -3,6 +3,7 @@
 
 use alloc::{boxed::Box, string::String};
 use kernel::video::{
+    back_buffer::BackBuffer,
     framebuffer::{GfxRectangle, Pixel},
     Framebuffer,
 };
@@ -16,7 +17,15 @@ kernel::kernel_main!(main);
 fn main(init_services: kernel::init::InitServices) -> ! {
     info!("Initializing console...");
 
-    if let Some(mut fb) = init_services.framebuffer {
+    if let Some(fb) = init_services.framebuffer {
+        // Draw in RAM if there is room for it, and only copy what changed to the screen
+        let mut fb = match BackBuffer::new(fb) {
+            Ok(buffered) => Box::new(buffered) as Box<dyn Framebuffer + Send>,
+            Err(fb) => {
+                log::warn!("No memory for a framebuffer back buffer; drawing straight to it");
+                fb as Box<dyn Framebuffer + Send>
+            }
+        };
         let width = fb.info().width;
         let height = fb.info().height;
         let bg_rect = GfxRectangle::with(width, height, |x, y| {
@@ -31,26 +40,26 @@ fn main(init_services: kernel::init::InitServices) -> ! {
             // )
         });
         fb.blit(&bg_rect, (0, 0));
+        fb.flush();
 
-        let mut console = kernel::video::console::Console::new(fb as Box<dyn Framebuffer + Send>);
+        let console = kernel::video::console::Console::new(fb);
+        kernel::video::vt::init(console);
 
-        for r in 0..16 {
-            for c in 0..32 {
-                console.write_glyph(r * 32 + c);
+        kernel::video::vt::with_console(kernel::video::vt::LOG_VT, |console| {
+            for r in 0..16 {
+                for c in 0..32 {
+                    console.write_glyph(r * 32 + c);
+                }
+                console.newline();
             }
-            console.newline();
-        }
-
-        *kernel::video::console::CONSOLE.lock() = Some(console);
+        });
     }
 
     info!("Console ready");
 
-    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
-        let mut pics = kernel::arch::interrupts::PICS.lock();
-        pics.write_masks(0xFC, 0xFF);
-    });
-    info!("Timer & keyboard interrupts unmasked");
+    kernel::arch::interrupts::enable_keyboard();
+    kernel::arch::interrupts::enable_serial();
+    info!("Keyboard and serial interrupts unmasked");
 
     info!("Hello world!");
     info!(
@@ -59,55 +68,67 @@ fn main(init_services: kernel::init::InitServices) -> ! {
     );
 
     info!("Loaded boot modules: {:#?}", init_services.modules);
-    let initrd = init_services
+    apply_kernel_parameters(init_services.cmdline);
+    let initrd: &'static [u8] = init_services
         .modules
-        .iter()
+        .into_iter()
         .find(|m| m.name == "initrd")
-        .expect("Boot module `initrd` not found.");
-
-    let mut fs = kernel::file::ustar::get_all_entries(initrd.data);
-    for entry in fs.iter() {
-        info!("File: {}, Size: {}", entry.file_name(), entry.file_size());
+        .expect("Boot module `initrd` not found.")
+        .data;
+    kernel::file::vfs::mount(
+        "/",
+        alloc::sync::Arc::new(kernel::file::ustar::UstarFs::new(initrd)),
+    )
+    .expect("Failed to mount the initrd");
+
+    for entry in kernel::file::vfs::read_dir("/").unwrap() {
+        info!("File: {}, Type: {:?}", entry.name, entry.file_type);
     }
-    if let Some(hello) = fs.iter_mut().find(|f| f.file_name() == "hello.txt") {
-        let s = {
-            use core2::io::Read;
-            let mut buf = alloc::vec![0u8; hello.file_size()];
-            hello.read(&mut buf).unwrap();
-            alloc::string::String::from_utf8_lossy(&buf).into_owned()
-        };
-        info!("hello.txt contents:\n{}", s)
+    if let Ok(hello) = kernel::file::vfs::read_file("/hello.txt") {
+        info!("hello.txt contents:\n{}", String::from_utf8_lossy(&hello))
     }
 
     kernel::task::init_executor();
 
-    if let Some(init) = fs.iter_mut().find(|f| f.file_name() == "init") {
-        let init_elf = {
-            use core2::io::Read;
-            let mut buf = alloc::vec![0u8; init.file_size()];
-            init.read(&mut buf).unwrap();
-            buf
-        };
-
-        init_process(&init_elf).unwrap();
+    if let Err(e) = init_process("/init") {
+        log::error!("Failed to start init: {e:?}");
     }
 
     kernel::task::run()
 }
 
-fn init_process(init_elf: &[u8]) -> Result<(), String> {
-    let p = kernel::process::create_process_from_elf(init_elf)?;
+/// Applies the `key=value` parameters on the kernel command line.
+fn apply_kernel_parameters(cmdline: &str) {
+    for param in cmdline.split_whitespace() {
+        match param.split_once('=') {
+            Some(("time_slices", value)) => {
+                match kernel::process::scheduler::parse_time_slices(value) {
+                    Some(slices) => kernel::process::scheduler::set_time_slices(slices),
+                    None => log::warn!("Ignoring {param}: expected a tick count per level"),
+                }
+            }
+            _ => log::warn!("Ignoring unknown kernel parameter {param}"),
+        }
+    }
+}
+
+fn init_process(path: &str) -> Result<(), kernel::process::SpawnError> {
+    let tty = &kernel::tty::TTYS[kernel::tty::SERIAL_TTY];
+    let pid = kernel::process::spawn(path, alloc::vec![path.into()], alloc::vec![], None, tty)?;
+    let exited = kernel::process::wait(pid).unwrap();
+    tty.set_foreground(Some(pid));
 
     let mut exec = kernel::task::EXECUTOR.get().unwrap().lock();
-    exec.spawn(async {
-        p.await;
-        log::info!("Init process exited");
+    exec.spawn("init reaper", async {
+        let code = exited.await;
+        log::info!("Init process exited with code {code}");
         unsafe {
             // QEMU poweroff shortcut
             x86_64::instructions::port::Port::new(0x604).write(0x2000u16);
         }
         panic!("Failed to power off after init process exit");
     });
-    exec.spawn(kernel::task::keyboard::print_keypresses());
+    exec.spawn("keyboard", kernel::task::keyboard::route_input());
+    exec.spawn("serial input", kernel::serial::route_input());
     Ok(())
 }
@@ -0,0 +1,48 @@
+use core2::io::{ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write};
+
+use crate::tty::Tty;
+
+/// A [terminal](crate::tty) as a file, used for the standard streams of processes.
+///
+/// Output goes to the console of its virtual terminal. Reads block the calling process until it is
+/// in the foreground and there is input, a whole line of it in canonical mode.
+pub struct ConsoleFile {
+    tty: &'static Tty,
+}
+
+impl ConsoleFile {
+    pub fn new(tty: &'static Tty) -> Self {
+        ConsoleFile { tty }
+    }
+}
+
+impl Read for ConsoleFile {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.tty
+            .read_current(buf)
+            .map_err(|_| ErrorKind::Interrupted.into())
+    }
+}
+
+impl Write for ConsoleFile {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.tty.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ConsoleFile {
+    fn seek(&mut self, _pos: SeekFrom) -> IoResult<u64> {
+        Err(ErrorKind::InvalidInput.into())
+    }
+}
+
+impl super::File for ConsoleFile {
+    fn tty(&self) -> Option<&'static Tty> {
+        Some(self.tty)
+    }
+}
@@ -0,0 +1,83 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{console::ConsoleFile, FileHandle};
+use crate::tty::Tty;
+
+/// Highest number of files a single process may have open at once.
+const MAX_OPEN_FILES: usize = 64;
+
+/// An open file as seen from one file descriptor.
+#[derive(Clone)]
+pub struct FileDescriptor {
+    pub file: FileHandle,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+/// Maps a process's file descriptor numbers to open files.
+#[derive(Clone)]
+pub struct FileDescriptorTable {
+    entries: Vec<Option<FileDescriptor>>,
+}
+
+impl FileDescriptorTable {
+    pub fn new() -> Self {
+        FileDescriptorTable {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Creates a table with stdin, stdout and stderr attached to terminal `tty`.
+    pub fn with_stdio(tty: &'static Tty) -> Self {
+        let console: FileHandle = Arc::new(Mutex::new(ConsoleFile::new(tty)));
+        let mut table = Self::new();
+        for (readable, writable) in [(true, false), (false, true), (false, true)] {
+            table.insert(FileDescriptor {
+                file: console.clone(),
+                readable,
+                writable,
+            });
+        }
+        table
+    }
+
+    /// Stores `desc` in the lowest free slot and returns its number, or `None` if the table is full.
+    pub fn insert(&mut self, desc: FileDescriptor) -> Option<u32> {
+        let fd = match self.entries.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.entries.len() < MAX_OPEN_FILES => {
+                self.entries.push(None);
+                self.entries.len() - 1
+            }
+            None => return None,
+        };
+        self.entries[fd] = Some(desc);
+        Some(fd as u32)
+    }
+
+    pub fn get(&self, fd: u32) -> Option<&FileDescriptor> {
+        self.entries.get(fd as usize)?.as_ref()
+    }
+
+    pub fn remove(&mut self, fd: u32) -> Option<FileDescriptor> {
+        self.entries.get_mut(fd as usize)?.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn lowest_free_descriptor() {
+        let mut table = FileDescriptorTable::with_stdio(&crate::tty::TTYS[0]);
+        let desc = table.get(1).unwrap().clone();
+        assert_eq!(table.insert(desc.clone()), Some(3));
+        assert!(table.remove(1).is_some());
+        assert!(table.get(1).is_none());
+        assert_eq!(table.insert(desc.clone()), Some(1));
+        assert_eq!(table.insert(desc), Some(4));
+    }
+}
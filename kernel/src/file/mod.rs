@@ -1 +1,97 @@
-pub mod ustar;
\ No newline at end of file
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core2::io::{ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write};
+use spin::Mutex;
+
+use self::vfs::{DirEntry, FileType, FsError, Inode};
+
+pub mod console;
+pub mod fd;
+pub mod ustar;
+pub mod vfs;
+
+/// Anything a file descriptor can refer to.
+pub trait File: Read + Write + Seek + Send {
+    /// The `index`th entry of the directory this file refers to, or `None` past the last one.
+    fn read_dir(&mut self, _index: u64) -> Result<Option<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// The terminal this file refers to, if it is one.
+    fn tty(&self) -> Option<&'static crate::tty::Tty> {
+        None
+    }
+}
+
+/// A shared handle to an open file. Descriptors duplicated from the same `open` share an offset.
+pub type FileHandle = Arc<Mutex<dyn File>>;
+
+/// Opens the file or directory at `path` in the VFS.
+pub fn open(path: &str, writable: bool) -> Result<FileHandle, FsError> {
+    let inode = vfs::resolve(path)?.inode;
+    if writable && inode.metadata().file_type == FileType::Directory {
+        return Err(FsError::IsADirectory);
+    }
+
+    Ok(Arc::new(Mutex::new(OpenFile {
+        inode,
+        offset: 0,
+        entries: None,
+    })))
+}
+
+/// An inode opened through the VFS, with its own file offset.
+pub struct OpenFile {
+    inode: Arc<dyn Inode>,
+    offset: u64,
+    /// The directory listing being read through, taken when reading its first entry so that
+    /// every later one doesn't list the directory all over again.
+    entries: Option<Vec<DirEntry>>,
+}
+
+impl Read for OpenFile {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let n = self.inode.read_at(self.offset, buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for OpenFile {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let n = self.inode.write_at(self.offset, buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl Seek for OpenFile {
+    fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(n) => (0, n as i64),
+            SeekFrom::Current(n) => (self.offset, n),
+            SeekFrom::End(n) => (self.inode.metadata().size, n),
+        };
+        self.offset = base
+            .checked_add_signed(delta)
+            .ok_or(ErrorKind::InvalidInput)?;
+        Ok(self.offset)
+    }
+}
+
+impl File for OpenFile {
+    fn read_dir(&mut self, index: u64) -> Result<Option<DirEntry>, FsError> {
+        let entries = match &mut self.entries {
+            Some(entries) if index != 0 => entries,
+            entries => entries.insert(self.inode.read_dir()?),
+        };
+        Ok(usize::try_from(index)
+            .ok()
+            .and_then(|i| entries.get(i))
+            .cloned())
+    }
+}
@@ -1,5 +1,11 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core2::io::{Read, Result as IoResult};
 
+use super::vfs::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+
 #[repr(C)]
 #[repr(align(1))]
 pub struct RawUstarHeader {
@@ -146,7 +152,7 @@ impl<'a> UstarFile<'a> {
         oct_to_u32(&self.raw_header.file_size).unwrap() as usize
     }
 
-    pub fn data(&self) -> &[u8] {
+    pub fn data(&self) -> &'a [u8] {
         self.data.get_ref()
     }
 }
@@ -175,6 +181,141 @@ pub fn get_all_entries(mut data: &[u8]) -> alloc::vec::Vec<UstarFile> {
     v
 }
 
+/// A read-only filesystem over a USTAR archive in memory.
+pub struct UstarFs {
+    root: Arc<UstarDirectory>,
+}
+
+impl UstarFs {
+    pub fn new(archive: &'static [u8]) -> Self {
+        let mut root = DirBuilder::default();
+        for entry in get_all_entries(archive) {
+            let name = entry.file_name();
+            let mut components: Vec<&str> = name
+                .split('/')
+                .filter(|c| !c.is_empty() && *c != ".")
+                .collect();
+            let Some(last) = components.pop() else {
+                continue;
+            };
+            let mut dir = &mut root;
+            for c in components {
+                dir = dir.subdir(c);
+            }
+            if entry.is_directory() {
+                dir.subdir(last);
+            } else if entry.is_file() {
+                dir.children.insert(last.into(), Node::File(entry.data()));
+            }
+        }
+
+        UstarFs { root: root.build() }
+    }
+}
+
+impl FileSystem for UstarFs {
+    fn name(&self) -> &'static str {
+        "ustar"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Mutable directory tree used while reading the archive, since entries can come in any order.
+#[derive(Default)]
+struct DirBuilder {
+    children: BTreeMap<String, Node>,
+}
+
+enum Node {
+    Dir(DirBuilder),
+    File(&'static [u8]),
+}
+
+impl DirBuilder {
+    fn subdir(&mut self, name: &str) -> &mut DirBuilder {
+        let node = self
+            .children
+            .entry(name.into())
+            .or_insert_with(|| Node::Dir(DirBuilder::default()));
+        if let Node::File(_) = node {
+            log::warn!("USTAR: `{name}` is both a file and a directory");
+            *node = Node::Dir(DirBuilder::default());
+        }
+        match node {
+            Node::Dir(d) => d,
+            Node::File(_) => unreachable!(),
+        }
+    }
+
+    fn build(self) -> Arc<UstarDirectory> {
+        let entries = self
+            .children
+            .into_iter()
+            .map(|(name, node)| {
+                let inode: Arc<dyn Inode> = match node {
+                    Node::Dir(d) => d.build(),
+                    Node::File(data) => Arc::new(UstarRegularFile { data }),
+                };
+                (name, inode)
+            })
+            .collect();
+        Arc::new(UstarDirectory { entries })
+    }
+}
+
+struct UstarDirectory {
+    entries: BTreeMap<String, Arc<dyn Inode>>,
+}
+
+impl Inode for UstarDirectory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.entries.get(name).cloned().ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|(name, inode)| DirEntry {
+                name: name.clone(),
+                file_type: inode.metadata().file_type,
+            })
+            .collect())
+    }
+}
+
+struct UstarRegularFile {
+    data: &'static [u8],
+}
+
+impl Inode for UstarRegularFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::File,
+            size: self.data.len() as u64,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let Some(rest) = self.data.get(offset as usize..) else {
+            return Ok(0);
+        };
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        Ok(n)
+    }
+}
+
 fn oct_to_u32(oct: &[u8]) -> Result<u32, UstarFormatError> {
     let mut n = 0u32;
     for &d in oct {
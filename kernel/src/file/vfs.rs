@@ -0,0 +1,242 @@
+//! The virtual filesystem: a single namespace made of mounted [`FileSystem`]s.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    ReadOnly,
+    InvalidPath,
+    AlreadyMounted,
+}
+
+impl From<FsError> for core2::io::Error {
+    fn from(e: FsError) -> Self {
+        use core2::io::ErrorKind;
+        match e {
+            FsError::NotFound => ErrorKind::NotFound,
+            FsError::ReadOnly => ErrorKind::PermissionDenied,
+            FsError::AlreadyMounted => ErrorKind::AlreadyExists,
+            FsError::NotADirectory | FsError::IsADirectory | FsError::InvalidPath => {
+                ErrorKind::InvalidInput
+            }
+        }
+        .into()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub file_type: FileType,
+    /// Length in bytes. Zero for directories.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+/// A file or directory inside a [`FileSystem`].
+///
+/// Everything except [`Inode::metadata`] has a default implementation that fails, so read-only
+/// filesystems only need to provide what they support.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Reads from the file starting at `offset`. Returns zero at end of file.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Finds the child called `name` in this directory. `.` and `..` are handled by the VFS and
+    /// never passed in.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    /// Short name of the filesystem type, for diagnostics.
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+/// Mounted filesystems, keyed by the normalized absolute path they are mounted on.
+static MOUNTS: Mutex<BTreeMap<String, Arc<dyn FileSystem>>> = Mutex::new(BTreeMap::new());
+
+/// Mounts `fs` at `path`. Anything but the first mount on `/` must land on an existing directory.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = if path == "/" {
+        String::from("/")
+    } else {
+        let target = resolve(path)?;
+        if target.inode.metadata().file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        target.path
+    };
+
+    let mut mounts = MOUNTS.lock();
+    if mounts.contains_key(&path) {
+        return Err(FsError::AlreadyMounted);
+    }
+    log::info!("Mounted {} on {}", fs.name(), path);
+    mounts.insert(path, fs);
+    Ok(())
+}
+
+fn mounted_at(path: &str) -> Option<Arc<dyn Inode>> {
+    MOUNTS.lock().get(path).map(|fs| fs.root())
+}
+
+/// An inode together with the normalized absolute path it was reached by.
+pub struct Dentry {
+    pub path: String,
+    pub inode: Arc<dyn Inode>,
+}
+
+/// Resolves an absolute path to an inode, following mount points and `.`/`..` components.
+pub fn resolve(path: &str) -> Result<Dentry, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let root = mounted_at("/").ok_or(FsError::NotFound)?;
+
+    // Every directory walked through so far, so `..` can step back out of mounted filesystems.
+    let mut stack: Vec<(&str, Arc<dyn Inode>)> = Vec::new();
+    let mut current = root.clone();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                current = match stack.pop() {
+                    Some((_, parent)) => parent,
+                    None => root.clone(),
+                };
+            }
+            name => {
+                let child = current.lookup(name)?;
+                stack.push((name, core::mem::replace(&mut current, child)));
+                let child_path = join(stack.iter().map(|(name, _)| *name));
+                if let Some(mounted_root) = mounted_at(&child_path) {
+                    current = mounted_root;
+                }
+            }
+        }
+    }
+
+    Ok(Dentry {
+        path: join(stack.iter().map(|(name, _)| *name)),
+        inode: current,
+    })
+}
+
+fn join<'a>(components: impl Iterator<Item = &'a str>) -> String {
+    let mut path = String::new();
+    for c in components {
+        path.push('/');
+        path.push_str(c);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+/// Lists the entries of the directory at `path`.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    resolve(path)?.inode.read_dir()
+}
+
+/// Reads the whole file at `path` into memory.
+pub fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let inode = resolve(path)?.inode;
+    let mut buf = alloc::vec![0u8; inode.metadata().size as usize];
+    let mut filled = 0;
+    while filled < buf.len() {
+        match inode.read_at(filled as u64, &mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestDir(BTreeMap<&'static str, Arc<dyn Inode>>);
+
+    impl Inode for TestDir {
+        fn metadata(&self) -> Metadata {
+            Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+            }
+        }
+
+        fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+            self.0.get(name).cloned().ok_or(FsError::NotFound)
+        }
+    }
+
+    struct TestFs(Arc<dyn Inode>);
+
+    impl FileSystem for TestFs {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn root(&self) -> Arc<dyn Inode> {
+            self.0.clone()
+        }
+    }
+
+    fn dir(entries: &[(&'static str, Arc<dyn Inode>)]) -> Arc<dyn Inode> {
+        Arc::new(TestDir(entries.iter().cloned().collect()))
+    }
+
+    #[test_case]
+    fn resolve_dots_and_mounts() {
+        let root = dir(&[("a", dir(&[])), ("mnt", dir(&[]))]);
+        mount("/", Arc::new(TestFs(root))).unwrap();
+        mount("/mnt", Arc::new(TestFs(dir(&[("b", dir(&[]))])))).unwrap();
+
+        assert_eq!(resolve("/").unwrap().path, "/");
+        assert_eq!(resolve("/a/../a/./").unwrap().path, "/a");
+        assert_eq!(resolve("/..").unwrap().path, "/");
+        assert_eq!(resolve("/mnt/b").unwrap().path, "/mnt/b");
+        assert_eq!(resolve("/mnt/b/../../a").unwrap().path, "/a");
+        assert_eq!(resolve("/a/b").err(), Some(FsError::NotFound));
+        assert_eq!(resolve("a").err(), Some(FsError::InvalidPath));
+        assert_eq!(
+            mount("/mnt", Arc::new(TestFs(dir(&[])))),
+            Err(FsError::AlreadyMounted)
+        );
+    }
+}
@@ -7,6 +7,8 @@ use crate::video::Framebuffer;
 pub struct InitServices {
     pub modules: Vec<BootModule>,
     pub framebuffer: Option<Box<dyn Framebuffer + Send + Sync>>,
+    /// The kernel command line: `key=value` parameters, separated by spaces.
+    pub cmdline: &'static str,
 }
 
 extern "Rust" {
@@ -31,6 +31,7 @@ pub mod serial;
 pub mod syscall;
 pub mod task;
 pub mod test;
+pub mod tty;
 pub mod util;
 pub mod video;
 
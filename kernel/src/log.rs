@@ -88,6 +88,6 @@ pub fn flush() {
 pub async fn flush_routine() {
     loop {
         flush();
-        crate::task::timer::wait_n_ticks(1).await;
+        crate::task::timer::sleep(core::time::Duration::from_millis(1)).await;
     }
 }
@@ -14,6 +14,10 @@ pub unsafe fn set_hook(hook: fn(&PanicInfo) -> !) {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // Other cores could be writing to the console and serial port we are about to take over
+    if !crate::arch::interrupts::stop_other_cores() {
+        crate::arch::loop_forever();
+    }
     crate::log::set_auto_flush(true);
     log::error!("PANIC!");
     log::error!("{:?}", info);
@@ -33,11 +37,13 @@ fn panic(info: &PanicInfo) -> ! {
 fn default_panic_handler(info: &PanicInfo) -> ! {
     // The kernel will never return from a panic anyways, and printing panic
     // information takes priority over being in a usable state afterwards.
-    // FIXME: When we implement multiprocessing, we need to signal all other threads to stop execution first.
+    // The other cores have been stopped, so whatever they held stays locked otherwise.
     unsafe {
-        crate::video::console::CONSOLE.force_unlock();
+        crate::video::vt::force_unlock();
         crate::serial::SERIAL1.force_unlock();
     }
+    // Where the panic message is printed
+    crate::video::vt::switch_to(crate::video::vt::LOG_VT);
 
     log::error!("{}", info);
     log::error!("{}", info);
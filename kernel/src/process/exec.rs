@@ -2,146 +2,382 @@ use super::Process;
 use crate::{
     arch::{
         cpu::{Context, Registers},
-        memory::{phys_to_virt, Page, VirtAddr},
+        memory::{
+            allocate_frame_for, phys_to_virt,
+            space::{user_page_flags, Space, HEAP_END, USER_SPACE_START},
+            FrameOwner, KernelStack, Page, VirtAddr, FRAME_ALLOCATOR,
+        },
     },
-    process::{ProcessState, ProcessId},
+    process::{ProcessId, ProcessState},
 };
-use alloc::vec;
+use alloc::{format, string::String, vec::Vec};
 use core::{arch::asm, convert::TryInto};
 use goblin::elf64::{
     header::{Header, SIZEOF_EHDR},
-    program_header::{ProgramHeader, PT_LOAD, SIZEOF_PHDR},
+    program_header::{ProgramHeader, PF_R, PF_W, PF_X, PT_LOAD, PT_PHDR, SIZEOF_PHDR},
 };
+use x86_64::structures::paging::PageTableFlags;
 
 const STACK_TOP: VirtAddr = VirtAddr::new_truncate(0x1000_0000_0000);
+/// Most the user stack may grow to, including its guard page.
+const STACK_SIZE: u64 = 8 * 1024 * 1024;
+/// Most memory the segments of a program may take up together. They are mapped as they are
+/// loaded, so this bounds what one `exec` can allocate.
+const MAX_LOAD_SIZE: u64 = 256 * 1024 * 1024;
 
-pub fn create_process_from_elf(data: &[u8]) -> Result<Process, alloc::string::String> {
-    let header: Header = unsafe {
-        // Safety: There is no invalid state of `Header`, and the `try_into` will make sure the array
-        // is correctly sized.
-        core::mem::transmute_copy::<[u8; SIZEOF_EHDR], Header>(
-            &data[..SIZEOF_EHDR].try_into().unwrap(),
-        )
-    };
+/// Everything needed to start running a freshly loaded program.
+struct Image {
+    space: Space,
+    kernel_stack: KernelStack,
+    context: *mut Context,
+}
+
+pub fn create_process_from_elf(
+    data: &[u8],
+    args: Vec<String>,
+    env: Vec<String>,
+    tty: &'static crate::tty::Tty,
+) -> Result<Process, String> {
+    let image = load_elf(data, &args, &env)?;
+    Ok(Process {
+        pid: ProcessId::new_unique(),
+        kernel_stack: image.kernel_stack,
+        state: ProcessState::Runnable,
+        space: image.space,
+        fpu: crate::arch::cpu::FpuState::new(),
+        files: crate::file::fd::FileDescriptorTable::with_stdio(tty),
+        args,
+        env,
+        context: image.context,
+        sched: super::scheduler::SchedInfo::new(super::scheduler::Priority::Normal),
+        blocked_on: None,
+    })
+}
+
+/// Replaces the program running in `p` with the ELF in `data`, keeping its PID and open files.
+/// The new program starts from its entry point, with `args` and `env`, the next time `p` is run.
+///
+/// Returns the old kernel stack, which the caller may still be running on. If `p` is the current
+/// process, the caller must not return to it with
+/// [`Cpu::return_from_process`](crate::arch::cpu::Cpu::return_from_process), as that would save
+/// over the fresh context.
+pub fn replace_image(
+    p: &mut Process,
+    data: &[u8],
+    args: Vec<String>,
+    env: Vec<String>,
+) -> Result<KernelStack, String> {
+    let image = load_elf(data, &args, &env)?;
+    let old_space = core::mem::replace(&mut p.space, image.space);
+    p.space.load();
+    drop(old_space);
+    p.context = image.context;
+    p.fpu = crate::arch::cpu::FpuState::new();
+    p.args = args;
+    p.env = env;
+    Ok(core::mem::replace(&mut p.kernel_stack, image.kernel_stack))
+}
+
+fn load_elf(data: &[u8], args: &[String], env: &[String]) -> Result<Image, String> {
+    let header_bytes: &[u8; SIZEOF_EHDR] = data
+        .get(..SIZEOF_EHDR)
+        .and_then(|b| b.try_into().ok())
+        .ok_or("ELF64 Format Error: File too short for a header")?;
+    // Safety: There is no invalid state of `Header`
+    let header: Header =
+        unsafe { core::mem::transmute_copy::<[u8; SIZEOF_EHDR], Header>(header_bytes) };
     if &header.e_ident[0..4] != b"\x7FELF" {
         return Err("ELF64 Format Error: Magic number mismatch".into());
     }
     if header.e_ident[4] != 2 {
         return Err("ELF64 Format Error: 32-bit ELF file recieved".into());
     }
+    if header.e_phnum > 0 && header.e_phentsize as usize != SIZEOF_PHDR {
+        return Err("ELF64 Format Error: Unexpected program header size".into());
+    }
 
     let program_headers = {
         use plain::Plain;
-        ProgramHeader::slice_from_bytes_len(
-            &data[SIZEOF_EHDR..][..header.e_phnum as usize * SIZEOF_PHDR],
-            header.e_phnum as usize,
-        )
-        .unwrap()
+        let len = header.e_phnum as usize * SIZEOF_PHDR;
+        let bytes = file_range(data, header.e_phoff, len as u64)
+            .ok_or("ELF64 Format Error: Program headers out of bounds")?;
+        ProgramHeader::slice_from_bytes_len(bytes, header.e_phnum as usize)
+            .map_err(|_| "ELF64 Format Error: Misaligned program headers")?
     };
 
-    let load_segments = program_headers.iter().filter_map(|ph| {
-        if ph.p_type != PT_LOAD {
-            return None;
+    let mut auxv = Vec::new();
+    let mut load_segments = Vec::new();
+    let mut load_size = 0u64;
+    for ph in program_headers.iter().filter(|ph| ph.p_type == PT_LOAD) {
+        if ph.p_filesz > ph.p_memsz {
+            return Err("ELF64 Format Error: Segment larger in the file than in memory".into());
         }
-        assert!(
-            ph.p_vaddr < 0xffff_8000_0000_0000,
-            "Cannot load into kernel memory"
-        );
-        Some(LoadSegment {
-            data: &data[ph.p_offset as usize..][..ph.p_filesz as usize],
+        load_size = load_size.saturating_add(ph.p_memsz);
+        if load_size > MAX_LOAD_SIZE {
+            return Err(format!("Segments take up more than {MAX_LOAD_SIZE} bytes"));
+        }
+        let seg_data = file_range(data, ph.p_offset, ph.p_filesz)
+            .ok_or("ELF64 Format Error: Segment out of bounds")?;
+        match ph.p_vaddr.checked_add(ph.p_memsz) {
+            Some(end) if ph.p_vaddr >= USER_SPACE_START && end <= HEAP_END => {}
+            _ => return Err("Segment outside the program area".into()),
+        }
+        if ph.p_align > 1
+            && (!ph.p_align.is_power_of_two()
+                || ph.p_vaddr % ph.p_align != ph.p_offset % ph.p_align)
+        {
+            return Err("ELF64 Format Error: Misaligned segment".into());
+        }
+        load_segments.push(LoadSegment {
+            data: seg_data,
             va: VirtAddr::new(ph.p_vaddr),
-        })
-    });
+            mem_size: ph.p_memsz,
+            flags: segment_flags(ph.p_flags),
+        });
+    }
+    if header.e_entry >= HEAP_END {
+        return Err("ELF64 Format Error: Entry point outside the program area".into());
+    }
 
-    Ok(create_process(load_segments, VirtAddr::new(header.e_entry)))
+    {
+        use kernel_uapi::auxv::*;
+        if let Some(phdr) = program_headers.iter().find(|ph| ph.p_type == PT_PHDR) {
+            auxv.push((AT_PHDR, phdr.p_vaddr));
+            auxv.push((AT_PHENT, SIZEOF_PHDR as u64));
+            auxv.push((AT_PHNUM, header.e_phnum as u64));
+        }
+        auxv.push((AT_PAGESZ, 4096));
+        auxv.push((AT_ENTRY, header.e_entry));
+    }
+
+    create_image(
+        load_segments,
+        VirtAddr::new(header.e_entry),
+        &auxv,
+        args,
+        env,
+    )
+}
+
+/// The `len` bytes at `offset` into `data`, if they are all there.
+fn file_range(data: &[u8], offset: u64, len: u64) -> Option<&[u8]> {
+    let start = usize::try_from(offset).ok()?;
+    let end = start.checked_add(usize::try_from(len).ok()?)?;
+    data.get(start..end)
+}
+
+/// Page permissions for a segment with the ELF permissions `p_flags`.
+fn segment_flags(p_flags: u32) -> PageTableFlags {
+    user_page_flags(
+        p_flags & PF_R != 0,
+        p_flags & PF_W != 0,
+        p_flags & PF_X != 0,
+    )
 }
 
 #[derive(Debug)]
 struct LoadSegment<'a> {
+    /// The part of the segment stored in the file. The rest, up to `mem_size`, is zeroed.
     data: &'a [u8],
     va: VirtAddr,
+    mem_size: u64,
+    flags: PageTableFlags,
 }
 
-fn create_process<'a, I>(load_segments: I, entry: VirtAddr) -> Process
-where
-    I: Iterator<Item = LoadSegment<'a>>,
-{
-    let mut space = crate::arch::memory::space::Space::new();
+/// Permissions for `page`: the union of those of every segment it is part of, as they all have
+/// to be able to use it.
+fn page_flags(segments: &[LoadSegment], page: Page) -> PageTableFlags {
+    let page_start = page.start_address().as_u64();
+    segments
+        .iter()
+        .filter(|seg| {
+            let start = seg.va.as_u64();
+            seg.mem_size != 0 && start < page_start + 4096 && start + seg.mem_size > page_start
+        })
+        .map(|seg| seg.flags)
+        .reduce(|a, b| {
+            // Executable if either segment is, writable if either is
+            ((a | b) - PageTableFlags::NO_EXECUTE) | (a & b & PageTableFlags::NO_EXECUTE)
+        })
+        .unwrap_or(PageTableFlags::empty())
+}
 
-    // Copy the code into memory
-    for seg in load_segments {
-        let code = seg.data;
-        let start = seg.va;
+/// Maps `page` into `space` with `flags` if it isn't yet, and returns its contents. Fresh pages
+/// are zeroed.
+fn map_user_page(
+    space: &mut Space,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<&'static mut [u8], String> {
+    use x86_64::structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        Mapper, PageSize, Size4KiB, Translate,
+    };
+    let parent_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut page_table = space.page_table();
+    let frame = match page_table.translate(page.start_address()) {
+        TranslateResult::NotMapped => {
+            let frame = allocate_frame_for(FrameOwner::User).ok_or("Out of memory")?;
+            let slice = unsafe { frame_slice(frame) };
+            slice.fill(0);
+            let mut fa = FRAME_ALLOCATOR.get().unwrap().lock();
+            unsafe {
+                page_table.map_to_with_table_flags(page, frame, flags, parent_flags, &mut *fa)
+            }
+            .map_err(|e| format!("Could not map {page:?}: {e:?}"))?
+            .ignore();
+            return Ok(slice);
+        }
+        // Shared with a segment loaded earlier, which mapped it for both
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            ..
+        } => frame,
+        other => return Err(format!("Could not map {page:?}: {other:?}")),
+    };
+    debug_assert_eq!(frame.size(), Size4KiB::SIZE);
+    Ok(unsafe { frame_slice(frame) })
+}
 
-        log::info!("LOAD@{start:X?} LEN:{}", code.len());
+/// # Safety
+/// The frame must not be in use by anything else, as it is handed out with any lifetime.
+unsafe fn frame_slice(frame: crate::arch::memory::PhysFrame) -> &'static mut [u8] {
+    let ptr = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    core::slice::from_raw_parts_mut(ptr, 4096)
+}
 
-        for page_start in
-            (start.align_down(4096u64)..(start + code.len()).align_up(4096u64)).step_by(4096)
-        {
-            let to_page = Page::from_start_address(page_start).unwrap();
-            let code_offset = page_start.as_u64().saturating_sub(start.as_u64()) as usize;
-            // If the segment is non-page aligned, this is how many zeroes we need before the segment.
-            let target_offset = start.as_u64().saturating_sub(page_start.as_u64()) as usize;
-            let target_len = (code.len() - code_offset).min(4096 - target_offset);
-
-            log::trace!(
-                "ps: {:X}  co: {code_offset}  to: {target_offset}  tl: {target_len}",
-                page_start.as_u64()
-            );
-
-            use x86_64::structures::paging::{
-                mapper::{MappedFrame, TranslateResult},
-                Mapper, PageTableFlags, Translate,
-            };
-            let flags: PageTableFlags = PageTableFlags::PRESENT
-                | PageTableFlags::USER_ACCESSIBLE
-                | PageTableFlags::WRITABLE;
-            let to_page_slice = match space.page_table().translate(to_page.start_address()) {
-                TranslateResult::NotMapped => unsafe {
-                    let load_frame = crate::arch::memory::allocate_frame().expect("Out of memory");
-                    let mut fa = crate::arch::memory::FRAME_ALLOCATOR.get().unwrap().lock();
-                    space
-                        .page_table()
-                        .map_to(to_page, load_frame, flags, &mut *fa)
-                        .unwrap()
-                        .ignore();
-
-                    let to_page_slice = core::slice::from_raw_parts_mut(
-                        crate::arch::memory::phys_to_virt(load_frame.start_address()).as_u64()
-                            as *mut u8,
-                        load_frame.size() as usize,
-                    );
-                    to_page_slice.fill(0);
-                    to_page_slice
-                },
-                TranslateResult::Mapped {
-                    frame: MappedFrame::Size4KiB(frame),
-                    flags,
-                    ..
-                } if flags == flags => unsafe {
-                    core::slice::from_raw_parts_mut(
-                        crate::arch::memory::phys_to_virt(frame.start_address()).as_u64()
-                            as *mut u8,
-                        frame.size() as usize,
-                    )
-                },
-                err => panic!("{:?}", err),
-            };
-
-            to_page_slice[target_offset..target_offset + target_len]
-                .copy_from_slice(&code[code_offset..code_offset + target_len]);
+/// Maps the pages `seg` covers into `space`, and fills them with its data followed by zeroes.
+/// `segments` are all those loaded alongside it, whose permissions its pages may need too.
+fn load_segment(
+    space: &mut Space,
+    seg: &LoadSegment,
+    segments: &[LoadSegment],
+) -> Result<(), String> {
+    if seg.mem_size == 0 {
+        return Ok(());
+    }
+    let start = seg.va.as_u64();
+    let end = start + seg.mem_size;
+    let pages = Page::range_inclusive(
+        Page::containing_address(seg.va),
+        Page::containing_address(VirtAddr::new(end - 1)),
+    );
+    for page in pages {
+        let page_slice = map_user_page(space, page, page_flags(segments, page))?;
+
+        // The part of the segment within this page, and how much of it comes from the file
+        let page_start = page.start_address().as_u64();
+        let from = start.max(page_start);
+        let to = end.min(page_start + 4096);
+        let dst = &mut page_slice[(from - page_start) as usize..(to - page_start) as usize];
+        let src = seg.data.get((from - start) as usize..).unwrap_or(&[]);
+        let file_len = src.len().min(dst.len());
+
+        log::trace!("ps: {page_start:X}  from: {from:X}  to: {to:X}  file: {file_len}");
+
+        dst[..file_len].copy_from_slice(&src[..file_len]);
+        // Zero the rest, in case an earlier segment left something in this page
+        dst[file_len..].fill(0);
+    }
+    Ok(())
+}
+
+/// Lays out `argc`, `argv`, `envp` and the auxiliary vector right below `top`, the way the
+/// System V ABI expects to find them at the entry point. Returns the bytes that go at
+/// `sp..top`, and the 16-byte aligned `sp` the program should start with.
+fn initial_stack(top: u64, args: &[String], env: &[String], auxv: &[(u64, u64)]) -> (Vec<u8>, u64) {
+    // The strings go at the very top, the pointers to them below
+    let strings_len: usize = args.iter().chain(env).map(|s| s.len() + 1).sum();
+    let strings_start = top - strings_len as u64;
+    let words = 1 + (args.len() + 1) + (env.len() + 1) + 2 * (auxv.len() + 1);
+    let sp = ((strings_start & !0xF) - words as u64 * 8) & !0xF;
+
+    let mut stack = alloc::vec![0u8; (top - sp) as usize];
+    let mut word_offset = 0;
+    let mut push = |word: u64| {
+        stack[word_offset..word_offset + 8].copy_from_slice(&word.to_le_bytes());
+        word_offset += 8;
+    };
+    push(args.len() as u64);
+    let mut string_addr = strings_start;
+    for strings in [args, env] {
+        for s in strings {
+            push(string_addr);
+            string_addr += s.len() as u64 + 1;
         }
+        push(0);
+    }
+    for &(key, value) in auxv {
+        push(key);
+        push(value);
+    }
+    push(kernel_uapi::auxv::AT_NULL);
+    push(0);
+
+    let mut string_offset = (strings_start - sp) as usize;
+    for s in args.iter().chain(env) {
+        stack[string_offset..string_offset + s.len()].copy_from_slice(s.as_bytes());
+        // Followed by the NUL the buffer is already filled with
+        string_offset += s.len() + 1;
+    }
+    (stack, sp)
+}
+
+fn create_image(
+    load_segments: Vec<LoadSegment>,
+    entry: VirtAddr,
+    auxv: &[(u64, u64)],
+    args: &[String],
+    env: &[String],
+) -> Result<Image, String> {
+    let mut space = Space::new();
+
+    // Copy the code into memory, with the heap starting right after it
+    let mut program_end = 0;
+    for seg in &load_segments {
+        log::info!(
+            "LOAD@{:X?} LEN:{} MEM:{} {:?}",
+            seg.va,
+            seg.data.len(),
+            seg.mem_size,
+            seg.flags
+        );
+        load_segment(&mut space, seg, &load_segments)?;
+        program_end = program_end.max(seg.va.as_u64() + seg.mem_size);
+    }
+    space.set_heap_start(program_end.next_multiple_of(4096));
+
+    // We need to create a stack for the user, starting out with the program's arguments. The
+    // rest of it is mapped as it grows.
+    let (stack_data, user_sp) = initial_stack(STACK_TOP.as_u64(), args, env, auxv);
+    if stack_data.len() as u64 > STACK_SIZE / 2 {
+        return Err("Arguments do not fit on the stack".into());
     }
+    space.set_stack(STACK_TOP.as_u64(), STACK_SIZE);
+    let user_sp = VirtAddr::new(user_sp);
+    let stack_segment = LoadSegment {
+        data: &stack_data,
+        va: user_sp,
+        mem_size: stack_data.len() as u64,
+        flags: user_page_flags(true, true, false),
+    };
+    load_segment(
+        &mut space,
+        &stack_segment,
+        core::slice::from_ref(&stack_segment),
+    )?;
 
-    let mut kernel_stack = vec![0u8; 1024];
-    let mut sp = kernel_stack.len();
+    let mut kernel_stack = KernelStack::new().ok_or("Out of memory for a kernel stack")?;
+    let stack = kernel_stack.as_mut_slice();
+    let mut sp = stack.len();
     // Put an interrupt stack frame at the top of the stack so we can `iret` into user mode
     let isf = x86_64::structures::idt::InterruptStackFrameValue {
         instruction_pointer: entry,
         cpu_flags: 1 << 9, // IF enabled
         code_segment: crate::arch::gdt::SELECTORS.user_code_selector.0 as u64,
         stack_segment: crate::arch::gdt::SELECTORS.user_data_selector.0 as u64,
-        stack_pointer: STACK_TOP,
+        stack_pointer: user_sp,
     };
     let isf_bytes = unsafe {
         core::mem::transmute::<
@@ -151,7 +387,7 @@ where
     };
     let isf_len = isf_bytes.len();
     sp -= isf_len;
-    kernel_stack[sp..sp + isf_len].copy_from_slice(&isf_bytes);
+    stack[sp..sp + isf_len].copy_from_slice(&isf_bytes);
 
     /// Empty function that just `iret`s
     #[naked]
@@ -183,45 +419,107 @@ where
         unsafe { core::mem::transmute::<_, [u8; core::mem::size_of::<Context>()]>(context) };
     let ctx_len = core::mem::size_of::<Context>();
     sp -= ctx_len;
-    kernel_stack[sp..sp + ctx_len].copy_from_slice(&context_bytes);
-    let context = (&mut kernel_stack[sp] as *mut u8).cast::<Context>();
+    stack[sp..sp + ctx_len].copy_from_slice(&context_bytes);
+    let context = (&mut stack[sp] as *mut u8).cast::<Context>();
 
-    // We need to create a stack for the user
-    const STACK_FRAMES: usize = 4;
-    for i in 0..STACK_FRAMES {
-        let frame = crate::arch::memory::allocate_frame().expect("Out of memory");
-        {
-            // Zero out the stack
-            let frame_slice = unsafe {
-                let ptr = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
-                core::slice::from_raw_parts_mut(ptr, 4096)
-            };
-            frame_slice.fill(0);
-        }
-        let target_page = Page::from_start_address(STACK_TOP - (i + 1) * 4096).unwrap();
-        unsafe {
-            use x86_64::structures::paging::{Mapper, PageTableFlags};
-            let mut fa = crate::arch::memory::FRAME_ALLOCATOR.get().unwrap().lock();
-            space
-                .page_table()
-                .map_to(
-                    target_page,
-                    frame,
-                    PageTableFlags::PRESENT
-                        | PageTableFlags::USER_ACCESSIBLE
-                        | PageTableFlags::WRITABLE,
-                    &mut *fa,
-                )
-                .unwrap()
-                .ignore()
-        }
-    }
-
-    Process {
-        pid: ProcessId::new_unique(),
-        kernel_stack,
-        state: ProcessState::Runnable,
+    Ok(Image {
         space,
+        kernel_stack,
         context,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn rejects_truncated_files() {
+        let mut elf = [0u8; SIZEOF_EHDR];
+        assert!(load_elf(&elf[..16], &[], &[]).is_err());
+
+        // A valid ident, with one program header that lies past the end of the file
+        elf[..5].copy_from_slice(b"\x7FELF\x02");
+        elf[0x20..0x28].copy_from_slice(&(SIZEOF_EHDR as u64).to_le_bytes()); // e_phoff
+        elf[0x36..0x38].copy_from_slice(&(SIZEOF_PHDR as u16).to_le_bytes()); // e_phentsize
+        elf[0x38..0x3A].copy_from_slice(&1u16.to_le_bytes()); // e_phnum
+        assert!(load_elf(&elf, &[], &[]).is_err());
+    }
+
+    #[test_case]
+    fn rejects_bad_segments() {
+        #[repr(align(8))]
+        struct Elf([u8; SIZEOF_EHDR + SIZEOF_PHDR]);
+
+        let load = |vaddr: u64, file_size: u64, mem_size: u64| {
+            let mut elf = Elf([0; SIZEOF_EHDR + SIZEOF_PHDR]);
+            let (header, ph) = elf.0.split_at_mut(SIZEOF_EHDR);
+            header[..5].copy_from_slice(b"\x7FELF\x02");
+            header[0x20..0x28].copy_from_slice(&(SIZEOF_EHDR as u64).to_le_bytes()); // e_phoff
+            header[0x36..0x38].copy_from_slice(&(SIZEOF_PHDR as u16).to_le_bytes()); // e_phentsize
+            header[0x38..0x3A].copy_from_slice(&1u16.to_le_bytes()); // e_phnum
+            ph[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            ph[0x10..0x18].copy_from_slice(&vaddr.to_le_bytes());
+            ph[0x20..0x28].copy_from_slice(&file_size.to_le_bytes());
+            ph[0x28..0x30].copy_from_slice(&mem_size.to_le_bytes());
+            load_elf(&elf.0, &[], &[]).err().unwrap()
+        };
+        assert!(load(0x40_0000, 0x10, 0x8).contains("larger in the file"));
+        assert!(load(0xFFFF_8000_0000_0000, 0, 0x1000).contains("outside the program area"));
+        assert!(load(0x40_0000, 0, MAX_LOAD_SIZE + 1).contains("more than"));
+        assert!(load(0, 0, 0x1000).contains("outside the program area"));
+    }
+
+    #[test_case]
+    fn shared_page_flags() {
+        let segment = |va: u64, mem_size: u64, flags: PageTableFlags| LoadSegment {
+            data: &[],
+            va: VirtAddr::new(va),
+            mem_size,
+            flags,
+        };
+        let code = user_page_flags(true, false, true);
+        let data = user_page_flags(true, true, false);
+        let segments = [
+            segment(0x40_0000, 0x1800, code),
+            segment(0x40_1800, 0x800, data),
+            // No access at all, in the page the data is in
+            segment(0x40_1000, 0x10, user_page_flags(false, false, false)),
+        ];
+        let page = |va: u64| Page::containing_address(VirtAddr::new(va));
+        assert_eq!(page_flags(&segments, page(0x40_0000)), code);
+        assert_eq!(
+            page_flags(&segments, page(0x40_1000)),
+            user_page_flags(true, true, true)
+        );
+        assert_eq!(
+            page_flags(&segments, page(0x40_2000)),
+            PageTableFlags::empty()
+        );
+    }
+
+    #[test_case]
+    fn initial_stack_layout() {
+        let top = 0x1000_0000u64;
+        let args = [String::from("init"), String::from("-v")];
+        let env = [String::from("HOME=/")];
+        let (stack, sp) = initial_stack(top, &args, &env, &[(kernel_uapi::auxv::AT_PAGESZ, 4096)]);
+        assert_eq!(sp % 16, 0);
+        assert_eq!(sp + stack.len() as u64, top);
+
+        let word = |i: usize| u64::from_le_bytes(stack[i * 8..i * 8 + 8].try_into().unwrap());
+        let c_str = |addr: u64| {
+            let bytes = &stack[(addr - sp) as usize..];
+            let len = bytes.iter().position(|&b| b == 0).unwrap();
+            core::str::from_utf8(&bytes[..len]).unwrap()
+        };
+        assert_eq!(word(0), 2);
+        assert_eq!(c_str(word(1)), "init");
+        assert_eq!(c_str(word(2)), "-v");
+        assert_eq!(word(3), 0);
+        assert_eq!(c_str(word(4)), "HOME=/");
+        assert_eq!(word(5), 0);
+        assert_eq!((word(6), word(7)), (kernel_uapi::auxv::AT_PAGESZ, 4096));
+        assert_eq!((word(8), word(9)), (kernel_uapi::auxv::AT_NULL, 0));
     }
 }
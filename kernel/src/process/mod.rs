@@ -1,13 +1,18 @@
-use core::{task::{Poll, Waker}, num::NonZeroU64};
+use core::num::NonZeroU64;
 
-use alloc::vec::Vec;
+use alloc::{string::String, sync::Arc, vec::Vec};
 
 use crate::arch::cpu::this_cpu;
+use crate::file::vfs::FsError;
 
 mod exec;
+pub mod scheduler;
+pub mod signal;
 pub mod space;
+pub mod table;
 
-pub use exec::create_process_from_elf;
+pub use exec::{create_process_from_elf, replace_image};
+pub use table::wait;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ProcessId(NonZeroU64);
@@ -17,10 +22,16 @@ impl ProcessId {
     pub fn new_unique() -> Self {
         static COUNTER: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(1);
         let pid = COUNTER.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
-        let Ok(pid) = NonZeroU64::try_from(pid) else { panic!("PID overflow") };
+        let Ok(pid) = NonZeroU64::try_from(pid) else {
+            panic!("PID overflow")
+        };
         ProcessId(pid)
     }
 
+    pub fn from_u64(pid: u64) -> Option<Self> {
+        NonZeroU64::new(pid).map(ProcessId)
+    }
+
     pub fn as_u64(&self) -> u64 {
         self.0.get()
     }
@@ -39,42 +50,160 @@ impl From<ProcessId> for NonZeroU64 {
 }
 
 pub enum ProcessState {
-    Running(Waker),
+    Running,
     Runnable,
-    Killed,
+    /// The process is done and will never run again. Holds its exit code.
+    Exited(i8),
     Waiting,
+    /// Stopped by a signal until another one continues it.
+    Stopped,
 }
 
 pub struct Process {
     pub pid: ProcessId,
-    pub kernel_stack: Vec<u8>,
+    /// Syscalls and interrupts from user mode run on this stack.
+    pub kernel_stack: crate::arch::memory::KernelStack,
     pub state: ProcessState,
     pub space: crate::arch::memory::space::Space,
+    /// Extended register state, saved whenever the process leaves the CPU.
+    pub fpu: crate::arch::cpu::FpuState,
+    pub files: crate::file::fd::FileDescriptorTable,
+    /// The command line the current program was started with.
+    pub args: Vec<String>,
+    /// Its environment, as `KEY=VALUE` strings.
+    pub env: Vec<String>,
     pub context: *mut crate::arch::cpu::Context,
+    pub sched: scheduler::SchedInfo,
+    /// The kernel task a blocked process waits on, aborted to cut the wait short.
+    pub blocked_on: Option<crate::task::JoinHandle<()>>,
+}
+
+impl Process {
+    /// Makes the process return from the blocking syscall it is in with [`Interrupted`].
+    fn interrupt_block(&self) {
+        if let Some(task) = &self.blocked_on {
+            task.abort();
+        }
+    }
 }
 
 unsafe impl Send for Process {}
 
-impl core::future::Future for Process {
-    type Output = ();
-
-    fn poll(
-        self: core::pin::Pin<&mut Self>,
-        cx: &mut core::task::Context<'_>,
-    ) -> core::task::Poll<Self::Output> {
-        x86_64::instructions::interrupts::disable();
-        let cpu = this_cpu();
-        let p = self.get_mut();
-        cpu.run_process(p, cx.waker().clone());
-
-        match p.state {
-            ProcessState::Running(_) => panic!("Yielded process in `Running` state!"),
-            ProcessState::Runnable => {
-                cx.waker().wake_by_ref();
-                Poll::Pending
-            }
-            ProcessState::Waiting => Poll::Pending,
-            ProcessState::Killed => Poll::Ready(()),
-        }
+#[derive(Debug)]
+pub enum SpawnError {
+    Fs(FsError),
+    Exec(String),
+}
+
+/// Loads the program at `path` and starts it as a new process, with its standard streams on `tty`.
+pub fn spawn(
+    path: &str,
+    args: Vec<String>,
+    env: Vec<String>,
+    parent: Option<ProcessId>,
+    tty: &'static crate::tty::Tty,
+) -> Result<ProcessId, SpawnError> {
+    let elf = crate::file::vfs::read_file(path).map_err(SpawnError::Fs)?;
+    let p = create_process_from_elf(&elf, args, env, tty).map_err(SpawnError::Exec)?;
+    log::debug!(
+        "Spawned process {} from {path}: {:?}",
+        p.pid.as_u64(),
+        p.args
+    );
+    Ok(start(p, parent))
+}
+
+/// Creates a copy of `parent` that returns 0 from the syscall `parent` is making on this CPU.
+///
+/// Memory is shared with `parent` copy-on-write, and open files are shared outright. Returns
+/// `None` if we are out of memory.
+pub fn fork(parent: &mut Process) -> Option<Process> {
+    let mut kernel_stack = crate::arch::memory::KernelStack::new()?;
+    let space = parent.space.fork().ok()?;
+    let context =
+        unsafe { crate::arch::syscall::fork_stack(&parent.kernel_stack, &mut kernel_stack) };
+    let mut fpu = crate::arch::cpu::FpuState::new();
+    fpu.save();
+    Some(Process {
+        pid: ProcessId::new_unique(),
+        kernel_stack,
+        state: ProcessState::Runnable,
+        space,
+        fpu,
+        files: parent.files.clone(),
+        args: parent.args.clone(),
+        env: parent.env.clone(),
+        context,
+        sched: scheduler::SchedInfo::new(parent.sched.priority),
+        blocked_on: None,
+    })
+}
+
+/// Hands `p` to the scheduler as a child of `parent`, and returns its PID.
+///
+/// It joins its parent's process group, so it only gets the terminal if that group has it. A
+/// process without a parent starts a group of its own.
+pub fn start(p: Process, parent: Option<ProcessId>) -> ProcessId {
+    let pid = p.pid;
+    let pgid = parent.and_then(table::pgid_of).unwrap_or(pid);
+    table::register(pid, parent, pgid);
+    scheduler::add(p);
+    pid
+}
+
+/// The PID of the process whose syscall is being handled on this CPU, if any.
+pub fn current_pid() -> Option<ProcessId> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        this_cpu().current_process().map(|p| p.pid)
+    })
+}
+
+/// The error of a blocking syscall that a signal cut short.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interrupted;
+
+/// Wakes the process when the task it is blocked on finishes or is aborted, whichever it is.
+struct WakeOnDrop(core::task::Waker);
+
+impl Drop for WakeOnDrop {
+    fn drop(&mut self) {
+        self.0.wake_by_ref();
     }
 }
+
+/// Parks the process that made the current syscall until `fut` completes, and returns its output.
+///
+/// The future runs as a kernel task while the process is off the CPU. A signal may drop it
+/// before it completes, in which case this returns [`Interrupted`].
+pub fn block_current_on<F>(fut: F) -> Result<F::Output, Interrupted>
+where
+    F: core::future::Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let p = x86_64::instructions::interrupts::without_interrupts(|| {
+        this_cpu()
+            .try_take_process()
+            .expect("Tried to block outside of a process")
+    });
+    p.state = ProcessState::Waiting;
+    let wake = WakeOnDrop(scheduler::waker(p.pid));
+
+    let output = Arc::new(spin::Mutex::new(None));
+    let task_output = output.clone();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // Another core may complete the future before we are off this stack, which the scheduler
+        // copes with by not letting the process block at all
+        let mut exec = crate::task::EXECUTOR.get().unwrap().lock();
+        let name = alloc::format!("process {} blocked", p.pid.as_u64());
+        p.blocked_on = Some(exec.spawn(name, async move {
+            let _wake = wake;
+            *task_output.lock() = Some(fut.await);
+        }));
+        drop(exec);
+        this_cpu().return_from_process(p);
+    });
+    p.blocked_on = None;
+
+    let output = output.lock().take();
+    output.ok_or(Interrupted)
+}
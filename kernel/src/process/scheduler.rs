@@ -0,0 +1,516 @@
+//! Decides which process runs next.
+//!
+//! Runnable processes wait in round-robin queues, one per level, apart from the async kernel
+//! tasks of the [executor](crate::task), which always get to run first. The queues form a
+//! multi-level feedback queue: a process starts at the level of its [`Priority`], drops a level
+//! each time it uses up a whole time slice, and returns to its priority's level when it blocks.
+//! CPU-bound processes thus sink below I/O-bound ones, and every so often everything is moved back
+//! up so that nothing waits forever.
+//!
+//! Every core has queues of its own. A process stays with the core it last ran on, unless another
+//! core runs out of work and takes it over.
+//!
+//! Processes stopped by a [signal](super::signal) are set aside until they are continued.
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::Arc,
+    task::Wake,
+};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::Waker;
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::signal::{Pending, Signal, SignalError};
+use super::{table, Process, ProcessId, ProcessState};
+use crate::arch::cpu::{online_cpus, this_cpu, MAX_CORES};
+
+/// Number of run queues. The lowest one is only reached by using up time slices.
+pub const LEVELS: usize = 4;
+/// Every this many timer ticks, all runnable processes go back to the level of their priority.
+const BOOST_INTERVAL_TICKS: u32 = 500;
+
+/// Timer ticks a process may run for in one go, by level.
+static TIME_SLICES: [AtomicU32; LEVELS] = [
+    AtomicU32::new(5),
+    AtomicU32::new(10),
+    AtomicU32::new(20),
+    AtomicU32::new(40),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub fn from_u32(priority: u32) -> Option<Self> {
+        match priority {
+            kernel_uapi::syscall::PRIORITY_HIGH => Some(Priority::High),
+            kernel_uapi::syscall::PRIORITY_NORMAL => Some(Priority::Normal),
+            kernel_uapi::syscall::PRIORITY_LOW => Some(Priority::Low),
+            _ => None,
+        }
+    }
+
+    fn level(self) -> usize {
+        self as usize
+    }
+}
+
+/// Scheduling state kept in each process.
+pub struct SchedInfo {
+    pub priority: Priority,
+    /// The run queue the process goes back to.
+    level: usize,
+    /// Timer ticks left in the current time slice.
+    slice_left: u32,
+    /// The core whose queues the process goes back to.
+    cpu: usize,
+}
+
+impl SchedInfo {
+    pub fn new(priority: Priority) -> Self {
+        SchedInfo {
+            priority,
+            level: priority.level(),
+            slice_left: time_slice(priority.level()),
+            cpu: 0,
+        }
+    }
+
+    /// Moves the process to `level` with a full time slice.
+    fn reset(&mut self, level: usize) {
+        self.level = level;
+        self.slice_left = time_slice(level);
+    }
+
+    /// Counts a timer tick against the time slice. Returns whether it is used up.
+    fn tick(&mut self) -> bool {
+        self.slice_left = self.slice_left.saturating_sub(1);
+        self.slice_left == 0
+    }
+
+    /// Moves a preempted process down a level if it used up its time slice.
+    fn preempted(&mut self) {
+        if self.slice_left == 0 {
+            self.reset((self.level + 1).min(LEVELS - 1));
+        }
+    }
+}
+
+fn time_slice(level: usize) -> u32 {
+    TIME_SLICES[level].load(Ordering::Relaxed)
+}
+
+/// Parses time slices given as one tick count per level, separated by commas, like `5,10,20,40`.
+pub fn parse_time_slices(s: &str) -> Option<[u32; LEVELS]> {
+    let mut slices = [0; LEVELS];
+    let mut ticks = s.split(',');
+    for slice in &mut slices {
+        *slice = ticks.next()?.trim().parse().ok().filter(|&t| t > 0)?;
+    }
+    ticks.next().is_none().then_some(slices)
+}
+
+/// Sets the time slice of each level, in timer ticks, from the next time slice of each process
+/// on. Done at boot with the `time_slices` kernel parameter.
+pub fn set_time_slices(ticks: [u32; LEVELS]) {
+    for (slice, ticks) in TIME_SLICES.iter().zip(ticks) {
+        assert!(ticks > 0, "time slices must be at least one tick");
+        slice.store(ticks, Ordering::Relaxed);
+    }
+}
+
+struct RunQueue {
+    levels: [VecDeque<Box<Process>>; LEVELS],
+    ticks_until_boost: u32,
+}
+
+/// Run queues of each core. Only ever locked with interrupts disabled, as the timer interrupt
+/// locks them too, and never more than one at a time. [`SCHEDULER`] is locked first where both
+/// are needed.
+static RUN_QUEUES: [Mutex<RunQueue>; MAX_CORES] = [const {
+    Mutex::new(RunQueue {
+        levels: [const { VecDeque::new() }; LEVELS],
+        ticks_until_boost: BOOST_INTERVAL_TICKS,
+    })
+}; MAX_CORES];
+
+/// State shared by all cores.
+struct Scheduler {
+    /// Processes waiting for their [`waker`] to be woken.
+    blocked: BTreeMap<ProcessId, Box<Process>>,
+    /// Processes whose waker was woken before they got to block.
+    woken_early: BTreeSet<ProcessId>,
+    /// Processes stopped by a signal. Signals are delivered with this locked, so that none slips
+    /// by a process on its way here.
+    stopped: BTreeMap<ProcessId, Box<Process>>,
+    /// Nanoseconds each live process has spent running.
+    cpu_time: BTreeMap<ProcessId, u64>,
+}
+
+/// Only ever locked with interrupts disabled, as wakers may be woken from interrupt handlers.
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    blocked: BTreeMap::new(),
+    woken_early: BTreeSet::new(),
+    stopped: BTreeMap::new(),
+    cpu_time: BTreeMap::new(),
+});
+
+impl RunQueue {
+    fn enqueue(&mut self, p: Box<Process>) {
+        self.levels[p.sched.level].push_back(p);
+    }
+
+    fn pop(&mut self) -> Option<Box<Process>> {
+        self.levels.iter_mut().find_map(VecDeque::pop_front)
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(VecDeque::len).sum()
+    }
+
+    /// Moves every runnable process back to the level of its priority.
+    fn boost(&mut self) {
+        for level in 1..LEVELS {
+            let queue = core::mem::take(&mut self.levels[level]);
+            for mut p in queue {
+                p.sched.reset(p.sched.priority.level());
+                self.enqueue(p);
+            }
+        }
+    }
+}
+
+/// Puts `p` in the queues of the core it is assigned to.
+fn enqueue(p: Box<Process>) {
+    RUN_QUEUES[p.sched.cpu].lock().enqueue(p);
+}
+
+/// The online core with the fewest runnable processes.
+fn least_loaded_cpu() -> usize {
+    online_cpus()
+        .min_by_key(|&id| RUN_QUEUES[id].lock().len())
+        .unwrap_or(0)
+}
+
+/// Takes the next process for core `cpu` to run, from its own queues or, failing that, from
+/// another core's.
+fn take_next(cpu: usize) -> Option<Box<Process>> {
+    // Popped on its own, so that the lock is released before taking another core's
+    let local = RUN_QUEUES[cpu].lock().pop();
+    let mut p = local.or_else(|| {
+        online_cpus()
+            .filter(|&id| id != cpu)
+            .find_map(|id| RUN_QUEUES[id].lock().pop())
+    })?;
+    p.sched.cpu = cpu;
+    Some(p)
+}
+
+/// Makes `p` runnable. It is registered as a child of `parent` by the caller.
+pub(super) fn add(mut p: Process) {
+    without_interrupts(|| {
+        SCHEDULER.lock().cpu_time.insert(p.pid, 0);
+        p.sched.cpu = least_loaded_cpu();
+        enqueue(Box::new(p));
+    })
+}
+
+/// Whether any process is waiting to run, on any core.
+pub fn has_runnable() -> bool {
+    without_interrupts(|| online_cpus().any(|id| RUN_QUEUES[id].lock().len() > 0))
+}
+
+/// Time `pid` has spent running, up to when it last left the CPU.
+pub fn cpu_time(pid: ProcessId) -> Option<Duration> {
+    let ns = without_interrupts(|| SCHEDULER.lock().cpu_time.get(&pid).copied())?;
+    Some(Duration::from_nanos(ns))
+}
+
+/// Changes the priority of the process that is running on this CPU.
+pub fn set_current_priority(priority: Priority) {
+    without_interrupts(|| {
+        let p = this_cpu()
+            .current_process()
+            .expect("Tried to set a priority outside of a process");
+        p.sched.priority = priority;
+        p.sched.reset(priority.level());
+    })
+}
+
+/// Whether `pid` may raise its own priority. Only init may, or every process could put itself
+/// first.
+pub fn may_raise_priority(pid: ProcessId) -> bool {
+    table::is_init(pid)
+}
+
+/// Counts a timer tick against the process running on this CPU. Returns whether its time slice
+/// is used up, in which case it should be preempted.
+pub fn tick() -> bool {
+    let cpu = this_cpu();
+    let mut queue = RUN_QUEUES[cpu.id()].lock();
+    queue.ticks_until_boost -= 1;
+    if queue.ticks_until_boost == 0 {
+        queue.ticks_until_boost = BOOST_INTERVAL_TICKS;
+        queue.boost();
+    }
+    drop(queue);
+
+    let Some(p) = cpu.current_process() else {
+        return false;
+    };
+    p.sched.tick()
+}
+
+/// How a process that left the CPU for good did so.
+enum Left {
+    Exited(Box<Process>, i8, u64),
+    Stopped(ProcessId),
+}
+
+/// Runs the next process until it is preempted, blocks, stops or exits. Returns whether there was
+/// one.
+pub fn run_next() -> bool {
+    let Some(mut p) = without_interrupts(|| take_next(this_cpu().id())) else {
+        return false;
+    };
+    let left = without_interrupts(|| {
+        let start = crate::arch::time::now_ns();
+        this_cpu().run_process(&mut p);
+        let ran_for = crate::arch::time::now_ns().saturating_sub(start);
+
+        let mut scheduler = SCHEDULER.lock();
+        *scheduler.cpu_time.entry(p.pid).or_default() += ran_for;
+        match p.state {
+            ProcessState::Running => panic!("Process left the CPU in `Running` state"),
+            ProcessState::Runnable => {
+                p.sched.preempted();
+                enqueue(p);
+                None
+            }
+            ProcessState::Waiting => {
+                // Whatever it waited for may take a while, so it gets a fresh start
+                p.sched.reset(p.sched.priority.level());
+                if scheduler.woken_early.remove(&p.pid) {
+                    p.state = ProcessState::Runnable;
+                    enqueue(p);
+                } else {
+                    // A signal may have come while it was on its way here
+                    if table::with_signals(p.pid, |s| s.interrupts()) == Some(true) {
+                        p.interrupt_block();
+                    }
+                    scheduler.blocked.insert(p.pid, p);
+                }
+                None
+            }
+            ProcessState::Stopped => {
+                let pid = p.pid;
+                if table::with_signals(pid, Pending::take_resume) == Some(true) {
+                    p.state = ProcessState::Runnable;
+                    enqueue(p);
+                    None
+                } else {
+                    table::set_stopped(pid, true);
+                    scheduler.stopped.insert(pid, p);
+                    Some(Left::Stopped(pid))
+                }
+            }
+            ProcessState::Exited(code) => {
+                scheduler.woken_early.remove(&p.pid);
+                let cpu_time = scheduler.cpu_time.remove(&p.pid).unwrap_or(0);
+                Some(Left::Exited(p, code, cpu_time))
+            }
+        }
+    });
+
+    match left {
+        Some(Left::Exited(p, code, cpu_time)) => {
+            let pid = p.pid;
+            // `p` is dropped, and its memory freed, before the parent hears about the exit
+            drop(p);
+            log::debug!(
+                "Process {} exited with code {code} after {:?} of CPU time",
+                pid.as_u64(),
+                Duration::from_nanos(cpu_time)
+            );
+            leave_foreground(pid);
+            table::set_exited(pid, code);
+        }
+        Some(Left::Stopped(pid)) => {
+            log::debug!("Process {} stopped", pid.as_u64());
+            leave_foreground(pid);
+        }
+        None => {}
+    }
+    true
+}
+
+/// Gives the terminal back to the parent's process group if `pid` leads the one in the
+/// foreground.
+fn leave_foreground(pid: ProcessId) {
+    if table::pgid_of(pid) == Some(pid) {
+        let parent_group = table::parent_of(pid).and_then(table::pgid_of);
+        crate::tty::pass_foreground(pid, parent_group);
+    }
+}
+
+/// Marks `signal` pending for `pid`, and makes sure it gets to act on it: a blocked process has
+/// its syscall cut short, and a stopped one runs again.
+pub(super) fn deliver(pid: ProcessId, signal: Signal) -> Result<(), SignalError> {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        match table::with_signals(pid, |s| s.raise(signal)) {
+            None => return Err(SignalError::NoSuchProcess),
+            Some(false) => return Err(SignalError::Ignored),
+            Some(true) => {}
+        }
+        if let Some(p) = scheduler.blocked.get(&pid) {
+            if signal != Signal::Continue {
+                p.interrupt_block();
+            }
+        } else if scheduler.stopped.contains_key(&pid)
+            && table::with_signals(pid, Pending::take_resume) == Some(true)
+        {
+            let mut p = scheduler.stopped.remove(&pid).unwrap();
+            table::set_stopped(pid, false);
+            p.state = ProcessState::Runnable;
+            enqueue(p);
+        }
+        Ok(())
+    })
+}
+
+/// Makes the blocked process `pid` runnable again, or makes sure it doesn't block if it hasn't
+/// yet.
+fn wake(pid: ProcessId) {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        match scheduler.blocked.remove(&pid) {
+            Some(mut p) => {
+                p.state = ProcessState::Runnable;
+                enqueue(p);
+            }
+            None => {
+                scheduler.woken_early.insert(pid);
+            }
+        }
+    })
+}
+
+struct ProcessWaker(ProcessId);
+
+impl Wake for ProcessWaker {
+    fn wake(self: Arc<Self>) {
+        wake(self.0);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        wake(self.0);
+    }
+}
+
+/// A waker that makes `pid` runnable after it has blocked with [`ProcessState::Waiting`].
+pub fn waker(pid: ProcessId) -> Waker {
+    Waker::from(Arc::new(ProcessWaker(pid)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(priority: Priority, level: usize) -> Box<Process> {
+        let mut sched = SchedInfo::new(priority);
+        sched.reset(level);
+        Box::new(Process {
+            pid: ProcessId::new_unique(),
+            kernel_stack: crate::arch::memory::KernelStack::new().unwrap(),
+            state: ProcessState::Runnable,
+            space: crate::arch::memory::space::Space::new(),
+            fpu: crate::arch::cpu::FpuState::new(),
+            files: crate::file::fd::FileDescriptorTable::new(),
+            args: alloc::vec::Vec::new(),
+            env: alloc::vec::Vec::new(),
+            context: core::ptr::null_mut(),
+            sched,
+            blocked_on: None,
+        })
+    }
+
+    #[test_case]
+    fn demotes_after_used_up_slice() {
+        let mut sched = SchedInfo::new(Priority::Normal);
+        sched.preempted();
+        assert_eq!(sched.level, 1);
+        for _ in 1..time_slice(1) {
+            assert!(!sched.tick());
+        }
+        assert!(sched.tick());
+        sched.preempted();
+        assert_eq!((sched.level, sched.slice_left), (2, time_slice(2)));
+
+        // It can't sink below the lowest level
+        sched.reset(LEVELS - 1);
+        sched.slice_left = 1;
+        assert!(sched.tick());
+        sched.preempted();
+        assert_eq!(
+            (sched.level, sched.slice_left),
+            (LEVELS - 1, time_slice(LEVELS - 1))
+        );
+    }
+
+    #[test_case]
+    fn parses_time_slices() {
+        assert_eq!(parse_time_slices("1, 2,3,4"), Some([1, 2, 3, 4]));
+        assert_eq!(parse_time_slices("1,2,3"), None);
+        assert_eq!(parse_time_slices("1,2,3,4,5"), None);
+        assert_eq!(parse_time_slices("1,0,3,4"), None);
+        assert_eq!(parse_time_slices("1,x,3,4"), None);
+    }
+
+    #[test_case]
+    fn only_init_raises_priority() {
+        let (init, child) = (ProcessId::new_unique(), ProcessId::new_unique());
+        table::register(init, None, init);
+        table::register(child, Some(init), init);
+        assert!(may_raise_priority(init));
+        assert!(!may_raise_priority(child));
+
+        // Orphans have no parent either, but are not init
+        table::set_exited(init, 0);
+        assert_eq!(table::parent_of(child), None);
+        assert!(!may_raise_priority(child));
+        table::set_exited(child, 0);
+    }
+
+    #[test_case]
+    fn boost_restores_priority_levels() {
+        let mut queue = RunQueue {
+            levels: [const { VecDeque::new() }; LEVELS],
+            ticks_until_boost: BOOST_INTERVAL_TICKS,
+        };
+        let (low, high) = (
+            process(Priority::Low, LEVELS - 1),
+            process(Priority::High, LEVELS - 1),
+        );
+        let (low_pid, high_pid) = (low.pid, high.pid);
+        queue.enqueue(low);
+        queue.enqueue(high);
+        queue.boost();
+        assert!(queue.levels[LEVELS - 1].is_empty());
+
+        let p = queue.pop().unwrap();
+        assert_eq!((p.pid, p.sched.level), (high_pid, 0));
+        assert_eq!(p.sched.slice_left, time_slice(0));
+        let p = queue.pop().unwrap();
+        assert_eq!((p.pid, p.sched.level), (low_pid, Priority::Low.level()));
+        assert!(queue.pop().is_none());
+    }
+}
@@ -0,0 +1,168 @@
+//! Signals: notifications sent to a process by the terminal or by other processes.
+//!
+//! A signal is only marked pending when it is sent. The process acts on it the next time it is
+//! about to return to user mode, at the end of a syscall or on a timer tick, where it holds no
+//! locks and can be taken off the CPU for good. Blocking syscalls are cut short so that this
+//! happens soon.
+
+use kernel_uapi::syscall::{SIGCONT, SIGINT, SIGKILL, SIGTSTP};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{scheduler, table, ProcessId, ProcessState};
+use crate::arch::cpu::this_cpu;
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    Interrupt = SIGINT,
+    Kill = SIGKILL,
+    Continue = SIGCONT,
+    Stop = SIGTSTP,
+}
+
+impl Signal {
+    pub fn from_u32(signal: u32) -> Option<Self> {
+        match signal {
+            SIGINT => Some(Signal::Interrupt),
+            SIGKILL => Some(Signal::Kill),
+            SIGCONT => Some(Signal::Continue),
+            SIGTSTP => Some(Signal::Stop),
+            _ => None,
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+const TERMINATING: u32 = 1 << SIGINT | 1 << SIGKILL;
+
+/// What a process has to do about its pending signals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Action {
+    Exit(i8),
+    Stop,
+}
+
+/// Signals sent to a process and not acted on yet, kept in its [`table`] entry.
+pub(super) struct Pending {
+    bits: u32,
+    /// Set for processes that must not be stopped or killed, like init.
+    ignored: bool,
+}
+
+impl Pending {
+    pub(super) fn new(ignored: bool) -> Self {
+        Pending { bits: 0, ignored }
+    }
+
+    /// Marks `signal` pending. Returns `false` if the process ignores signals.
+    pub(super) fn raise(&mut self, signal: Signal) -> bool {
+        if self.ignored {
+            return false;
+        }
+        // Stopping and continuing cancel each other out
+        match signal {
+            Signal::Continue => self.bits &= !Signal::Stop.bit(),
+            Signal::Stop => self.bits &= !Signal::Continue.bit(),
+            Signal::Interrupt | Signal::Kill => {}
+        }
+        self.bits |= signal.bit();
+        true
+    }
+
+    /// Whether a blocking syscall should be cut short for these signals.
+    pub(super) fn interrupts(&self) -> bool {
+        self.bits & (TERMINATING | Signal::Stop.bit()) != 0
+    }
+
+    pub(super) fn take_action(&mut self) -> Option<Action> {
+        if self.bits & Signal::Kill.bit() != 0 {
+            Some(Action::Exit(-(SIGKILL as i8)))
+        } else if self.bits & Signal::Interrupt.bit() != 0 {
+            Some(Action::Exit(-(SIGINT as i8)))
+        } else if self.bits & Signal::Stop.bit() != 0 {
+            self.bits &= !Signal::Stop.bit();
+            Some(Action::Stop)
+        } else {
+            None
+        }
+    }
+
+    /// Whether a stopped process should run again, either to continue or to exit. Consumes a
+    /// pending [`Signal::Continue`].
+    pub(super) fn take_resume(&mut self) -> bool {
+        let resume = self.bits & (TERMINATING | Signal::Continue.bit()) != 0;
+        self.bits &= !Signal::Continue.bit();
+        resume
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignalError {
+    NoSuchProcess,
+    /// The process ignores signals.
+    Ignored,
+}
+
+/// Sends `signal` to `pid`, waking it if it is blocked or stopped so that it acts on it.
+pub fn send(pid: ProcessId, signal: Signal) -> Result<(), SignalError> {
+    scheduler::deliver(pid, signal)
+}
+
+/// Sends `signal` to every process in group `pgid`, except for those that ignore it.
+pub fn send_to_group(pgid: ProcessId, signal: Signal) {
+    for pid in table::group(pgid) {
+        // It may have exited in the meantime, which is fine
+        let _ = send(pid, signal);
+    }
+}
+
+/// Acts on the signals pending for the process running on this CPU, which must be about to
+/// return to user mode. Only returns if the process keeps running, which a stopped one does once
+/// it is continued.
+pub fn act_on_pending() {
+    without_interrupts(|| {
+        let cpu = this_cpu();
+        let Some(p) = cpu.current_process() else {
+            return;
+        };
+        let Some(action) = table::with_signals(p.pid, Pending::take_action).flatten() else {
+            return;
+        };
+        cpu.try_take_process();
+        p.state = match action {
+            Action::Exit(code) => ProcessState::Exited(code),
+            Action::Stop => ProcessState::Stopped,
+        };
+        cpu.return_from_process(p);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn stop_and_continue() {
+        let mut pending = Pending::new(false);
+        pending.raise(Signal::Continue);
+        assert!(pending.raise(Signal::Stop));
+        assert!(pending.interrupts());
+        // The continue came first, so stopping cancelled it
+        assert!(!pending.take_resume());
+        assert_eq!(pending.take_action(), Some(Action::Stop));
+        assert_eq!(pending.take_action(), None);
+
+        pending.raise(Signal::Continue);
+        assert!(!pending.interrupts());
+        assert!(pending.take_resume());
+        assert!(!pending.take_resume());
+
+        pending.raise(Signal::Stop);
+        pending.raise(Signal::Interrupt);
+        assert_eq!(pending.take_action(), Some(Action::Exit(-2)));
+        assert!(!Pending::new(true).raise(Signal::Kill));
+    }
+}
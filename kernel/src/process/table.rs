@@ -0,0 +1,195 @@
+//! Bookkeeping for process relationships, signals and exit codes, kept apart from the
+//! [`Process`] itself so it outlives the process.
+//!
+//! [`Process`]: super::Process
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+use super::signal::Pending;
+use super::ProcessId;
+
+struct Entry {
+    parent: Option<ProcessId>,
+    /// Whether the process was started without a parent, as init is. Unlike a missing `parent`,
+    /// which orphans have too, this never changes.
+    init: bool,
+    /// The process group, which the terminal treats as one job.
+    pgid: ProcessId,
+    signals: Pending,
+    /// Whether the process is stopped by a signal, which waiters hear about like an exit.
+    stopped: bool,
+    exit_code: Option<i8>,
+    /// Number of live [`ExitWaiter`]s. Orphaned entries are only removed once this hits zero.
+    watchers: usize,
+    wakers: Vec<Waker>,
+}
+
+static TABLE: Mutex<BTreeMap<ProcessId, Entry>> = Mutex::new(BTreeMap::new());
+
+/// Adds `pid` to process group `pgid`. Processes without a parent ignore signals.
+pub(super) fn register(pid: ProcessId, parent: Option<ProcessId>, pgid: ProcessId) {
+    TABLE.lock().insert(
+        pid,
+        Entry {
+            parent,
+            init: parent.is_none(),
+            pgid,
+            signals: Pending::new(parent.is_none()),
+            stopped: false,
+            exit_code: None,
+            watchers: 0,
+            wakers: Vec::new(),
+        },
+    );
+}
+
+pub fn parent_of(pid: ProcessId) -> Option<ProcessId> {
+    TABLE.lock().get(&pid)?.parent
+}
+
+pub fn is_init(pid: ProcessId) -> bool {
+    TABLE.lock().get(&pid).is_some_and(|e| e.init)
+}
+
+pub fn pgid_of(pid: ProcessId) -> Option<ProcessId> {
+    Some(TABLE.lock().get(&pid)?.pgid)
+}
+
+/// Whether `pid` is `ancestor` itself, or one of its children, grandchildren and so on.
+pub fn descends_from(pid: ProcessId, ancestor: ProcessId) -> bool {
+    let table = TABLE.lock();
+    let mut pid = Some(pid);
+    while let Some(p) = pid {
+        if p == ancestor {
+            return true;
+        }
+        pid = table.get(&p).and_then(|e| e.parent);
+    }
+    false
+}
+
+/// The live processes in group `pgid`.
+pub fn group(pgid: ProcessId) -> Vec<ProcessId> {
+    TABLE
+        .lock()
+        .iter()
+        .filter(|(_, e)| e.pgid == pgid && e.exit_code.is_none())
+        .map(|(&pid, _)| pid)
+        .collect()
+}
+
+/// Runs `f` on the signals pending for `pid`, unless it has exited.
+pub(super) fn with_signals<R>(pid: ProcessId, f: impl FnOnce(&mut Pending) -> R) -> Option<R> {
+    let mut table = TABLE.lock();
+    let entry = table.get_mut(&pid).filter(|e| e.exit_code.is_none())?;
+    Some(f(&mut entry.signals))
+}
+
+/// Records whether `pid` is stopped, waking anyone waiting for it if it is.
+pub(super) fn set_stopped(pid: ProcessId, stopped: bool) {
+    let mut table = TABLE.lock();
+    let Some(entry) = table.get_mut(&pid) else {
+        return;
+    };
+    entry.stopped = stopped;
+    if stopped {
+        for waker in entry.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Records that `pid` exited with `code` and wakes anyone waiting for it.
+pub(super) fn set_exited(pid: ProcessId, code: i8) {
+    let mut table = TABLE.lock();
+
+    // Nobody is left to wait for the children of this process
+    table.retain(|_, e| {
+        if e.parent == Some(pid) {
+            e.parent = None;
+            e.exit_code.is_none() || e.watchers > 0
+        } else {
+            true
+        }
+    });
+
+    let Some(entry) = table.get_mut(&pid) else {
+        return;
+    };
+    entry.exit_code = Some(code);
+    for waker in entry.wakers.drain(..) {
+        waker.wake();
+    }
+    if entry.parent.is_none() && entry.watchers == 0 {
+        table.remove(&pid);
+    }
+}
+
+/// Returns a future that resolves to the exit code of `pid`, or `None` if there is no such
+/// process. The entry is reaped once every waiter has seen the exit code.
+///
+/// If the process stops instead, the future resolves to [`WAIT_STOPPED`].
+///
+/// [`WAIT_STOPPED`]: kernel_uapi::syscall::WAIT_STOPPED
+pub fn wait(pid: ProcessId) -> Option<ExitWaiter> {
+    TABLE.lock().get_mut(&pid)?.watchers += 1;
+    Some(ExitWaiter { pid, done: false })
+}
+
+pub struct ExitWaiter {
+    pid: ProcessId,
+    done: bool,
+}
+
+impl ExitWaiter {
+    /// Drops this waiter's claim on the entry, removing it if nobody else needs it.
+    fn release(&mut self, table: &mut BTreeMap<ProcessId, Entry>) {
+        self.done = true;
+        let Some(entry) = table.get_mut(&self.pid) else {
+            return;
+        };
+        entry.watchers -= 1;
+        if entry.watchers == 0 && entry.exit_code.is_some() {
+            table.remove(&self.pid);
+        }
+    }
+}
+
+impl Future for ExitWaiter {
+    type Output = i8;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<i8> {
+        let this = self.get_mut();
+        let mut table = TABLE.lock();
+        let entry = table
+            .get_mut(&this.pid)
+            .expect("process entry reaped while being waited on");
+        match entry.exit_code {
+            Some(code) => {
+                this.release(&mut table);
+                Poll::Ready(code)
+            }
+            None if entry.stopped => {
+                this.release(&mut table);
+                Poll::Ready(kernel_uapi::syscall::WAIT_STOPPED)
+            }
+            None => {
+                entry.wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for ExitWaiter {
+    fn drop(&mut self) {
+        if !self.done {
+            self.release(&mut TABLE.lock());
+        }
+    }
+}
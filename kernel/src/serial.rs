@@ -1,10 +1,19 @@
+use conquer_once::spin::OnceCell;
 use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
 use uart_16550::SerialPort;
 use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3F8;
+/// Line status register, whose lowest bit says whether a received byte is waiting.
+const COM1_LINE_STATUS: u16 = COM1 + 5;
 
 lazy_static::lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
@@ -27,4 +36,54 @@ macro_rules! serial_print {
 macro_rules! serial_println {
     () => ($crate::serial_print!("\n"));
     ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
-}
\ No newline at end of file
+}
+
+static INPUT_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+/// Bytes received while the input queue was full, which [`route_input`] reports. Logging from
+/// the interrupt handler would allocate, and could deadlock on the console the log goes to.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Takes the bytes COM1 has received off its hands. Called from its interrupt handler.
+pub(crate) fn receive_pending() {
+    let mut status = Port::<u8>::new(COM1_LINE_STATUS);
+    let mut data = Port::<u8>::new(COM1);
+    while unsafe { status.read() } & 1 != 0 {
+        let byte = unsafe { data.read() };
+        match INPUT_QUEUE.try_get() {
+            Ok(queue) if queue.push(byte).is_ok() => WAKER.wake(),
+            Ok(_) => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+            // Nobody listens yet
+            Err(_) => {}
+        }
+    }
+}
+
+/// Feeds what is typed on the serial port to its terminal.
+pub async fn route_input() {
+    let queue = INPUT_QUEUE.get_or_init(|| ArrayQueue::new(1000));
+    loop {
+        let byte = core::future::poll_fn(|cx| {
+            if let Ok(byte) = queue.pop() {
+                return core::task::Poll::Ready(byte);
+            }
+            WAKER.register(cx.waker());
+            match queue.pop() {
+                Ok(byte) => {
+                    WAKER.take();
+                    core::task::Poll::Ready(byte)
+                }
+                Err(crossbeam_queue::PopError) => core::task::Poll::Pending,
+            }
+        })
+        .await;
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!("Serial input queue full; dropped {dropped} bytes");
+        }
+        // Terminals send UTF-8, but anything past ASCII is taken as Latin-1 for simplicity
+        crate::tty::TTYS[crate::tty::SERIAL_TTY].receive(char::from(byte));
+    }
+}
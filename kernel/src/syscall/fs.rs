@@ -0,0 +1,136 @@
+use core2::io::{ErrorKind, SeekFrom};
+use kernel_uapi::syscall::{
+    DirEntry, FileKind, SyscallErrorCode, O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY, SEEK_CUR,
+    SEEK_END, SEEK_SET,
+};
+
+use super::{user, with_current_process};
+use crate::file::{
+    fd::FileDescriptor,
+    vfs::{FileType, FsError},
+};
+
+pub(super) fn fs_error_code(e: FsError) -> SyscallErrorCode {
+    match e {
+        FsError::NotFound => SyscallErrorCode::NotFound,
+        FsError::NotADirectory => SyscallErrorCode::NotADirectory,
+        FsError::IsADirectory => SyscallErrorCode::IsADirectory,
+        FsError::ReadOnly => SyscallErrorCode::PermissionDenied,
+        FsError::InvalidPath | FsError::AlreadyMounted => SyscallErrorCode::InvalidArgumentError,
+    }
+}
+
+/// Most bytes a single `read` or `write` moves, so user buffers need not be copied in one go.
+const MAX_IO_LEN: usize = 64 * 1024;
+
+fn io_error_code(e: core2::io::Error) -> SyscallErrorCode {
+    match e.kind() {
+        ErrorKind::NotFound => SyscallErrorCode::NotFound,
+        ErrorKind::PermissionDenied => SyscallErrorCode::PermissionDenied,
+        ErrorKind::InvalidInput => SyscallErrorCode::InvalidArgumentError,
+        ErrorKind::Interrupted => SyscallErrorCode::Interrupted,
+        _ => SyscallErrorCode::IoError,
+    }
+}
+
+pub(super) fn get_fd(fd: u32) -> Result<FileDescriptor, SyscallErrorCode> {
+    with_current_process(|p| p.files.get(fd).cloned()).ok_or(SyscallErrorCode::BadFileDescriptor)
+}
+
+pub fn open(path: *const u8, path_len: usize, flags: u32) -> Result<u32, SyscallErrorCode> {
+    let path = user::read_str(path, path_len)?;
+    let (readable, writable) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(SyscallErrorCode::InvalidArgumentError),
+    };
+
+    let file = crate::file::open(&path, writable).map_err(fs_error_code)?;
+    let desc = FileDescriptor {
+        file,
+        readable,
+        writable,
+    };
+    with_current_process(|p| p.files.insert(desc)).ok_or(SyscallErrorCode::TooManyOpenFiles)
+}
+
+pub fn read(fd: u32, buf: *mut u8, len: usize) -> Result<usize, SyscallErrorCode> {
+    let desc = get_fd(fd)?;
+    if !desc.readable {
+        return Err(SyscallErrorCode::BadFileDescriptor);
+    }
+    // Checked before reading, so that input isn't consumed for nothing. Only what can be read is
+    // checked, as checking maps the pages in
+    let len = len.min(MAX_IO_LEN);
+    user::check(buf, len, true)?;
+    let mut kbuf = alloc::vec![0; len];
+    let tty = desc.file.lock().tty();
+    let n = match tty {
+        // Terminal reads block for as long as it takes someone to type, so the file is not kept
+        // locked meanwhile
+        Some(tty) => tty.read_current(&mut kbuf)?,
+        None => desc.file.lock().read(&mut kbuf).map_err(io_error_code)?,
+    };
+    user::copy_to_user(buf, &kbuf[..n])?;
+    Ok(n)
+}
+
+pub fn write(fd: u32, buf: *const u8, len: usize) -> Result<usize, SyscallErrorCode> {
+    let desc = get_fd(fd)?;
+    if !desc.writable {
+        return Err(SyscallErrorCode::BadFileDescriptor);
+    }
+    let mut kbuf = alloc::vec![0; len.min(MAX_IO_LEN)];
+    user::copy_from_user(buf, &mut kbuf)?;
+    let n = desc.file.lock().write(&kbuf).map_err(io_error_code)?;
+    Ok(n)
+}
+
+pub fn close(fd: u32) -> Result<(), SyscallErrorCode> {
+    with_current_process(|p| p.files.remove(fd))
+        .map(drop)
+        .ok_or(SyscallErrorCode::BadFileDescriptor)
+}
+
+pub fn lseek(fd: u32, offset: i64, whence: u32) -> Result<u64, SyscallErrorCode> {
+    let pos = match whence {
+        SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return Err(SyscallErrorCode::InvalidArgumentError),
+    };
+    let desc = get_fd(fd)?;
+    let n = desc.file.lock().seek(pos).map_err(io_error_code)?;
+    Ok(n)
+}
+
+pub fn read_dir(
+    fd: u32,
+    index: u64,
+    name: *mut u8,
+    name_cap: usize,
+) -> Result<DirEntry, SyscallErrorCode> {
+    let desc = get_fd(fd)?;
+    if !desc.readable {
+        return Err(SyscallErrorCode::BadFileDescriptor);
+    }
+    let entry = desc.file.lock().read_dir(index).map_err(fs_error_code)?;
+    let Some(entry) = entry else {
+        return Ok(DirEntry {
+            name_len: 0,
+            kind: FileKind::File,
+        });
+    };
+    if entry.name.len() > name_cap {
+        return Err(SyscallErrorCode::InvalidArgumentError);
+    }
+    user::copy_to_user(name, entry.name.as_bytes())?;
+    Ok(DirEntry {
+        name_len: entry.name.len(),
+        kind: match entry.file_type {
+            FileType::File => FileKind::File,
+            FileType::Directory => FileKind::Directory,
+        },
+    })
+}
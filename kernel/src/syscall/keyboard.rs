@@ -0,0 +1,28 @@
+use kernel_uapi::syscall::{SyscallErrorCode, KBD_MODE_LINE, KBD_MODE_RAW};
+
+use super::with_current_process;
+use crate::task::keyboard::{self, KeyboardMode};
+
+pub fn get_kbd_code() -> Result<u8, SyscallErrorCode> {
+    // Scancodes never arrive in line mode, so don't wait for one
+    if keyboard::mode() != KeyboardMode::Raw {
+        return Err(SyscallErrorCode::InvalidArgumentError);
+    }
+    let pid = with_current_process(|p| p.pid);
+    let scancode = crate::process::block_current_on(keyboard::read_scancode(pid))?;
+    Ok(scancode)
+}
+
+pub fn set_kbd_mode(mode: u32) -> Result<(), SyscallErrorCode> {
+    let mode = match mode {
+        KBD_MODE_LINE => KeyboardMode::Line,
+        KBD_MODE_RAW => KeyboardMode::Raw,
+        _ => return Err(SyscallErrorCode::InvalidArgumentError),
+    };
+    let pid = with_current_process(|p| p.pid);
+    if keyboard::set_mode(pid, mode) {
+        Ok(())
+    } else {
+        Err(SyscallErrorCode::PermissionDenied)
+    }
+}
@@ -0,0 +1,46 @@
+use kernel_uapi::syscall::{SyscallErrorCode, PROT_EXEC, PROT_READ, PROT_WRITE};
+use x86_64::structures::paging::PageTableFlags;
+
+use super::with_current_process;
+use crate::arch::memory::space::{user_page_flags, MapError};
+
+fn map_error_code(e: MapError) -> SyscallErrorCode {
+    match e {
+        MapError::OutOfMemory => SyscallErrorCode::OutOfMemory,
+        MapError::InvalidRange => SyscallErrorCode::InvalidArgumentError,
+    }
+}
+
+fn prot_flags(prot: u32) -> Result<PageTableFlags, SyscallErrorCode> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SyscallErrorCode::InvalidArgumentError);
+    }
+    Ok(user_page_flags(
+        prot & PROT_READ != 0,
+        prot & PROT_WRITE != 0,
+        prot & PROT_EXEC != 0,
+    ))
+}
+
+pub fn brk(addr: *mut u8) -> Result<*mut u8, SyscallErrorCode> {
+    with_current_process(|p| p.space.brk(addr as u64))
+        .map(|end| end as *mut u8)
+        .map_err(map_error_code)
+}
+
+pub fn mmap(len: usize, prot: u32) -> Result<*mut u8, SyscallErrorCode> {
+    let flags = prot_flags(prot)?;
+    with_current_process(|p| p.space.map_anonymous(len, flags))
+        .map(|addr| addr as *mut u8)
+        .map_err(map_error_code)
+}
+
+pub fn munmap(addr: *mut u8, len: usize) -> Result<(), SyscallErrorCode> {
+    with_current_process(|p| p.space.unmap_anonymous(addr as u64, len)).map_err(map_error_code)
+}
+
+pub fn mprotect(addr: *mut u8, len: usize, prot: u32) -> Result<(), SyscallErrorCode> {
+    let flags = prot_flags(prot)?;
+    with_current_process(|p| p.space.protect_anonymous(addr as u64, len, flags))
+        .map_err(map_error_code)
+}
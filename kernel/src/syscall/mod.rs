@@ -1,9 +1,63 @@
-use crate::process::ProcessState;
+use crate::process::{Interrupted, Process, ProcessState};
 
 use kernel_uapi::syscall::{Syscall, SyscallErrorCode, SyscallResult, SyscallResultInner};
 use log::info;
 
-pub extern "C" fn syscall_handler(op: &mut Syscall) -> SyscallResult {
+mod fs;
+mod keyboard;
+mod memory;
+mod process;
+mod tty;
+mod user;
+
+impl From<Interrupted> for SyscallErrorCode {
+    fn from(_: Interrupted) -> Self {
+        SyscallErrorCode::Interrupted
+    }
+}
+
+/// Runs `f` on the process that made the current syscall.
+fn with_current_process<R>(f: impl FnOnce(&mut Process) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let p = crate::arch::cpu::this_cpu()
+            .current_process()
+            .expect("syscall not within a process");
+        f(p)
+    })
+}
+
+/// Sleeps on a timer for `duration_ns`. The timer interrupt is armed for the deadline, so this
+/// needs neither to wait for a timer tick nor to spin.
+fn nanosleep(duration_ns: u64) -> Result<(), Interrupted> {
+    let duration = core::time::Duration::from_nanos(duration_ns);
+    crate::process::block_current_on(crate::task::timer::sleep(duration))
+}
+
+/// Handles the syscall described at `op`, and writes its result to `out`. Both pointers come
+/// straight from user space, and are checked before use.
+pub extern "C" fn syscall_handler(op: *const Syscall, out: *mut SyscallResult) {
+    let result = read_syscall(op).map_or_else(|e| Err(e).into(), |mut op| dispatch(&mut op));
+    if user::write(out, result).is_err() {
+        log::debug!("Dropped a syscall result with a bad output pointer");
+    }
+    crate::process::signal::act_on_pending();
+}
+
+/// Completes the `fork` syscall in the child, which runs here first, on its own stack.
+pub extern "C" fn fork_child_result(out: *mut SyscallResult) {
+    let result = Ok(SyscallResultInner { fork: 0 }).into();
+    if user::write(out, result).is_err() {
+        log::debug!("Dropped a syscall result with a bad output pointer");
+    }
+}
+
+fn read_syscall(op: *const Syscall) -> Result<Syscall, SyscallErrorCode> {
+    let mut bytes = [0; core::mem::size_of::<Syscall>()];
+    user::copy_from_user(op.cast(), &mut bytes)?;
+    Syscall::from_bytes(bytes).ok_or(SyscallErrorCode::InvalidArgumentError)
+}
+
+fn dispatch(op: &mut Syscall) -> SyscallResult {
     log::trace!("Syscall: {:?}", op);
     match op {
         Syscall::ping {} => {
@@ -20,44 +74,106 @@ pub extern "C" fn syscall_handler(op: &mut Syscall) -> SyscallResult {
                 Err(SyscallErrorCode::InvalidArgumentError).into()
             }
         }
-        Syscall::get_kbd_code { .. } => {
-            unimplemented!()
-        }
-        Syscall::sleep_ms { .. } => {
-            let p = x86_64::instructions::interrupts::without_interrupts(|| {
-                let cpu = crate::arch::cpu::this_cpu();
-                cpu.try_take_process()
-                    .expect("`sleep_ms` syscall not within a process")
-            });
-            let p_state = core::mem::replace(&mut p.state, ProcessState::Waiting);
-            let waker = match p_state {
-                ProcessState::Running(w) => w,
-                _ => unreachable!(),
-            };
-            {
-                let mut exec = crate::task::EXECUTOR.get().unwrap().lock();
-                exec.spawn(async {
-                    crate::task::timer::wait_n_ticks(100).await;
-                    waker.wake();
-                });
-            };
-
-            x86_64::instructions::interrupts::without_interrupts(|| {
-                let cpu = crate::arch::cpu::this_cpu();
-                cpu.return_from_process(p);
-            });
-
-            Ok(SyscallResultInner { sleep_ms: () }).into()
+        Syscall::get_kbd_code {} => keyboard::get_kbd_code()
+            .map(|code| SyscallResultInner { get_kbd_code: code })
+            .into(),
+        Syscall::set_kbd_mode { mode } => keyboard::set_kbd_mode(*mode)
+            .map(|()| SyscallResultInner { set_kbd_mode: () })
+            .into(),
+        Syscall::sleep_ms { duration_ms } => {
+            let duration = core::time::Duration::from_millis(u64::from(*duration_ms));
+            crate::process::block_current_on(crate::task::timer::sleep(duration))
+                .map(|()| SyscallResultInner { sleep_ms: () })
+                .map_err(SyscallErrorCode::from)
+                .into()
         }
-        Syscall::exit { .. } => x86_64::instructions::interrupts::without_interrupts(|| {
+        Syscall::nanosleep { duration_ns } => nanosleep(*duration_ns)
+            .map(|()| SyscallResultInner { nanosleep: () })
+            .map_err(SyscallErrorCode::from)
+            .into(),
+        Syscall::exit { code } => x86_64::instructions::interrupts::without_interrupts(|| {
             let cpu = crate::arch::cpu::this_cpu();
             let p = cpu
                 .try_take_process()
                 .expect("`exit` syscall not within a process");
-            p.state = ProcessState::Killed;
+            p.state = ProcessState::Exited(*code);
             cpu.return_from_process(p);
 
             panic!("Tried to run a killed process")
         }),
+        Syscall::open {
+            path,
+            path_len,
+            flags,
+        } => fs::open(*path, *path_len, *flags)
+            .map(|fd| SyscallResultInner { open: fd })
+            .into(),
+        Syscall::read { fd, buf, len } => fs::read(*fd, *buf, *len)
+            .map(|n| SyscallResultInner { read: n })
+            .into(),
+        Syscall::write { fd, buf, len } => fs::write(*fd, *buf, *len)
+            .map(|n| SyscallResultInner { write: n })
+            .into(),
+        Syscall::close { fd } => fs::close(*fd)
+            .map(|()| SyscallResultInner { close: () })
+            .into(),
+        Syscall::lseek { fd, offset, whence } => fs::lseek(*fd, *offset, *whence)
+            .map(|pos| SyscallResultInner { lseek: pos })
+            .into(),
+        Syscall::read_dir {
+            fd,
+            index,
+            name,
+            name_cap,
+        } => fs::read_dir(*fd, *index, *name, *name_cap)
+            .map(|entry| SyscallResultInner { read_dir: entry })
+            .into(),
+        Syscall::spawn {
+            path,
+            path_len,
+            argv,
+            envp,
+        } => process::spawn(*path, *path_len, *argv, *envp)
+            .map(|pid| SyscallResultInner { spawn: pid })
+            .into(),
+        Syscall::exec {
+            path,
+            path_len,
+            argv,
+            envp,
+        } => Err(process::exec(*path, *path_len, *argv, *envp)).into(),
+        Syscall::wait { pid } => process::wait(*pid)
+            .map(|code| SyscallResultInner { wait: code })
+            .into(),
+        Syscall::brk { addr } => memory::brk(*addr)
+            .map(|end| SyscallResultInner { brk: end })
+            .into(),
+        Syscall::mmap { len, prot } => memory::mmap(*len, *prot)
+            .map(|addr| SyscallResultInner { mmap: addr })
+            .into(),
+        Syscall::munmap { addr, len } => memory::munmap(*addr, *len)
+            .map(|()| SyscallResultInner { munmap: () })
+            .into(),
+        Syscall::mprotect { addr, len, prot } => memory::mprotect(*addr, *len, *prot)
+            .map(|()| SyscallResultInner { mprotect: () })
+            .into(),
+        Syscall::fork {} => process::fork()
+            .map(|pid| SyscallResultInner { fork: pid })
+            .into(),
+        Syscall::set_priority { priority } => process::set_priority(*priority)
+            .map(|()| SyscallResultInner { set_priority: () })
+            .into(),
+        Syscall::tcgetattr { fd, termios } => tty::tcgetattr(*fd, *termios)
+            .map(|()| SyscallResultInner { tcgetattr: () })
+            .into(),
+        Syscall::tcsetattr { fd, termios } => tty::tcsetattr(*fd, *termios)
+            .map(|()| SyscallResultInner { tcsetattr: () })
+            .into(),
+        Syscall::tcsetpgrp { fd, pgid } => tty::tcsetpgrp(*fd, *pgid)
+            .map(|()| SyscallResultInner { tcsetpgrp: () })
+            .into(),
+        Syscall::kill { pid, signal } => process::kill(*pid, *signal)
+            .map(|()| SyscallResultInner { kill: () })
+            .into(),
     }
 }
@@ -0,0 +1,142 @@
+use alloc::{string::String, vec::Vec};
+use kernel_uapi::syscall::SyscallErrorCode;
+
+use super::{user, with_current_process};
+use crate::process::scheduler::{self, Priority};
+use crate::process::signal::{self, Signal, SignalError};
+use crate::process::{table, ProcessId, ProcessState, SpawnError};
+
+/// Most strings `argv` or `envp` may hold
+const MAX_ARGS: usize = 256;
+const MAX_ARG_LEN: usize = 4096;
+
+/// Copies a null-terminated array of NUL-terminated strings, like `argv` or `envp`, out of user
+/// memory.
+fn read_strings(array: *const *const u8) -> Result<Vec<String>, SyscallErrorCode> {
+    let mut strings = Vec::new();
+    if array.is_null() {
+        return Ok(strings);
+    }
+    loop {
+        let ptr = unsafe { user::read(array.wrapping_add(strings.len()))? };
+        if ptr.is_null() {
+            return Ok(strings);
+        }
+        if strings.len() == MAX_ARGS {
+            return Err(SyscallErrorCode::InvalidArgumentError);
+        }
+        strings.push(user::read_c_str(ptr, MAX_ARG_LEN)?);
+    }
+}
+
+fn spawn_error_code(e: SpawnError) -> SyscallErrorCode {
+    match e {
+        SpawnError::Fs(e) => super::fs::fs_error_code(e),
+        SpawnError::Exec(msg) => {
+            log::debug!("exec failed: {msg}");
+            SyscallErrorCode::InvalidExecutable
+        }
+    }
+}
+
+pub fn spawn(
+    path: *const u8,
+    path_len: usize,
+    argv: *const *const u8,
+    envp: *const *const u8,
+) -> Result<u64, SyscallErrorCode> {
+    let path = user::read_str(path, path_len)?;
+    let args = read_strings(argv)?;
+    let env = read_strings(envp)?;
+    let parent = with_current_process(|p| p.pid);
+    // Children run on the terminal of their parent's standard input, if it has one
+    let tty = super::tty::get_tty(0).unwrap_or(&crate::tty::TTYS[crate::tty::SERIAL_TTY]);
+    let pid =
+        crate::process::spawn(&path, args, env, Some(parent), tty).map_err(spawn_error_code)?;
+    Ok(pid.as_u64())
+}
+
+pub fn fork() -> Result<u64, SyscallErrorCode> {
+    let (child, parent) = with_current_process(|p| (crate::process::fork(p), p.pid));
+    let child = child.ok_or(SyscallErrorCode::OutOfMemory)?;
+    log::debug!(
+        "Process {} forked into {}",
+        parent.as_u64(),
+        child.pid.as_u64()
+    );
+    Ok(crate::process::start(child, Some(parent)).as_u64())
+}
+
+pub fn set_priority(priority: u32) -> Result<(), SyscallErrorCode> {
+    let priority = Priority::from_u32(priority).ok_or(SyscallErrorCode::InvalidArgumentError)?;
+    let (pid, current) = with_current_process(|p| (p.pid, p.sched.priority));
+    if priority < current && !scheduler::may_raise_priority(pid) {
+        return Err(SyscallErrorCode::PermissionDenied);
+    }
+    scheduler::set_current_priority(priority);
+    Ok(())
+}
+
+pub fn exec(
+    path: *const u8,
+    path_len: usize,
+    argv: *const *const u8,
+    envp: *const *const u8,
+) -> SyscallErrorCode {
+    let result = (|| {
+        let path = user::read_str(path, path_len)?;
+        let args = read_strings(argv)?;
+        let env = read_strings(envp)?;
+        crate::file::vfs::read_file(&path)
+            .map(|elf| (elf, args, env))
+            .map_err(super::fs::fs_error_code)
+    })();
+    let (elf, args, env) = match result {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+
+    // A preemption between replacing the image and leaving would save over the new context
+    x86_64::instructions::interrupts::without_interrupts(move || {
+        let cpu = crate::arch::cpu::this_cpu();
+        let p = cpu
+            .current_process()
+            .expect("`exec` syscall not within a process");
+        let old_stack = match crate::process::replace_image(p, &elf, args, env) {
+            Ok(stack) => stack,
+            Err(e) => return spawn_error_code(SpawnError::Exec(e)),
+        };
+        // Nothing on this stack is dropped once we leave it
+        drop(elf);
+        log::debug!("Process {} exec'd {:?}", p.pid.as_u64(), p.args);
+        cpu.try_take_process();
+        p.state = ProcessState::Runnable;
+        // We are still running on the old kernel stack
+        cpu.leave_process(old_stack)
+    })
+}
+
+pub fn wait(pid: u64) -> Result<i8, SyscallErrorCode> {
+    let pid = ProcessId::from_u64(pid).ok_or(SyscallErrorCode::NoSuchProcess)?;
+    let current = with_current_process(|p| p.pid);
+    if table::parent_of(pid) != Some(current) {
+        return Err(SyscallErrorCode::NoSuchProcess);
+    }
+    let exited = crate::process::wait(pid).ok_or(SyscallErrorCode::NoSuchProcess)?;
+    Ok(crate::process::block_current_on(exited)?)
+}
+
+pub fn kill(pid: u64, signal: u32) -> Result<(), SyscallErrorCode> {
+    let pid = ProcessId::from_u64(pid).ok_or(SyscallErrorCode::NoSuchProcess)?;
+    let signal = Signal::from_u32(signal).ok_or(SyscallErrorCode::InvalidArgumentError)?;
+    let pgid = table::pgid_of(pid).ok_or(SyscallErrorCode::NoSuchProcess)?;
+    let current = with_current_process(|p| p.pid);
+    // Processes may only signal their own descendants and process group
+    if !table::descends_from(pid, current) && table::pgid_of(current) != Some(pgid) {
+        return Err(SyscallErrorCode::PermissionDenied);
+    }
+    signal::send(pid, signal).map_err(|e| match e {
+        SignalError::NoSuchProcess => SyscallErrorCode::NoSuchProcess,
+        SignalError::Ignored => SyscallErrorCode::PermissionDenied,
+    })
+}
@@ -0,0 +1,39 @@
+use kernel_uapi::syscall::{SyscallErrorCode, Termios};
+
+use super::{fs::get_fd, user, with_current_process};
+use crate::process::{table, ProcessId};
+use crate::tty::Tty;
+
+pub(super) fn get_tty(fd: u32) -> Result<&'static Tty, SyscallErrorCode> {
+    get_fd(fd)?
+        .file
+        .lock()
+        .tty()
+        .ok_or(SyscallErrorCode::NotATerminal)
+}
+
+pub fn tcgetattr(fd: u32, termios: *mut Termios) -> Result<(), SyscallErrorCode> {
+    let tty = get_tty(fd)?;
+    user::write(termios, tty.termios())
+}
+
+pub fn tcsetattr(fd: u32, termios: *const Termios) -> Result<(), SyscallErrorCode> {
+    let tty = get_tty(fd)?;
+    // Every bit pattern is a valid `Termios`
+    let termios = unsafe { user::read(termios)? };
+    tty.set_termios(termios);
+    Ok(())
+}
+
+pub fn tcsetpgrp(fd: u32, pgid: u64) -> Result<(), SyscallErrorCode> {
+    let tty = get_tty(fd)?;
+    let pgid = ProcessId::from_u64(pgid).ok_or(SyscallErrorCode::NoSuchProcess)?;
+    if !tty.is_foreground(with_current_process(|p| p.pid)) {
+        return Err(SyscallErrorCode::PermissionDenied);
+    }
+    if table::group(pgid).is_empty() {
+        return Err(SyscallErrorCode::NoSuchProcess);
+    }
+    tty.set_foreground(Some(pgid));
+    Ok(())
+}
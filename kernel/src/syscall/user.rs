@@ -0,0 +1,79 @@
+//! Access to the memory of the process making a syscall. Every pointer a process hands us is
+//! checked against its address space before we touch it.
+
+use alloc::{string::String, vec::Vec};
+use kernel_uapi::syscall::SyscallErrorCode;
+
+use super::with_current_process;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Fails with `BadAddress` unless `len` bytes at `ptr` are mapped for the current process, and
+/// writable if `write` is set. Pages the process has not touched yet are mapped in.
+pub fn check(ptr: *const u8, len: usize, write: bool) -> Result<(), SyscallErrorCode> {
+    let ok = with_current_process(|p| p.space.make_user_accessible(ptr as u64, len, write));
+    if ok {
+        Ok(())
+    } else {
+        Err(SyscallErrorCode::BadAddress)
+    }
+}
+
+pub fn copy_from_user(src: *const u8, dst: &mut [u8]) -> Result<(), SyscallErrorCode> {
+    check(src, dst.len(), false)?;
+    unsafe { core::ptr::copy_nonoverlapping(src, dst.as_mut_ptr(), dst.len()) };
+    Ok(())
+}
+
+pub fn copy_to_user(dst: *mut u8, src: &[u8]) -> Result<(), SyscallErrorCode> {
+    check(dst, src.len(), true)?;
+    unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len()) };
+    Ok(())
+}
+
+/// Reads a `T` out of user memory.
+///
+/// # Safety
+/// Any bit pattern must be a valid `T`.
+pub unsafe fn read<T: Copy>(src: *const T) -> Result<T, SyscallErrorCode> {
+    check(src.cast(), core::mem::size_of::<T>(), false)?;
+    Ok(src.read_unaligned())
+}
+
+pub fn write<T>(dst: *mut T, value: T) -> Result<(), SyscallErrorCode> {
+    check(dst.cast(), core::mem::size_of::<T>(), true)?;
+    unsafe { dst.write_unaligned(value) };
+    Ok(())
+}
+
+/// Copies `len` bytes of UTF-8 out of user memory.
+pub fn read_str(ptr: *const u8, len: usize) -> Result<String, SyscallErrorCode> {
+    // Don't trust `len` with an allocation before it is known to be sensible
+    check(ptr, len, false)?;
+    let mut bytes = alloc::vec![0; len];
+    copy_from_user(ptr, &mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| SyscallErrorCode::InvalidArgumentError)
+}
+
+/// Copies a NUL-terminated UTF-8 string of at most `max_len` bytes out of user memory.
+pub fn read_c_str(ptr: *const u8, max_len: usize) -> Result<String, SyscallErrorCode> {
+    let mut bytes = Vec::new();
+    let mut addr = ptr as u64;
+    loop {
+        // Check a page at a time, as the string may end right before an unmapped page
+        let chunk_len = (PAGE_SIZE - addr % PAGE_SIZE) as usize;
+        let chunk_len = chunk_len.min(max_len + 1 - bytes.len());
+        let start = bytes.len();
+        bytes.resize(start + chunk_len, 0);
+        copy_from_user(addr as *const u8, &mut bytes[start..])?;
+
+        if let Some(nul) = bytes[start..].iter().position(|&b| b == 0) {
+            bytes.truncate(start + nul);
+            return String::from_utf8(bytes).map_err(|_| SyscallErrorCode::InvalidArgumentError);
+        }
+        if bytes.len() > max_len {
+            return Err(SyscallErrorCode::InvalidArgumentError);
+        }
+        addr += chunk_len as u64;
+    }
+}
@@ -1,48 +1,225 @@
 use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc};
-use core::task::Waker;
+use alloc::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
+use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Room the task queue starts out with.
+const INITIAL_QUEUE_CAPACITY: usize = 100;
+
+/// The tasks that are ready to be polled.
+///
+/// Wakers may be woken from interrupt handlers, which must not allocate, as they could deadlock on
+/// the heap. So pushing never does: a task is in the queue at most once, which its `queued` flag
+/// sees to, and [`Executor::spawn`] grows the queue before it could run out of room.
+pub(super) struct TaskQueue {
+    /// Only written to with interrupts disabled, as interrupt handlers read it.
+    queue: RwLock<ArrayQueue<TaskId>>,
+}
+
+impl TaskQueue {
+    fn new() -> Self {
+        TaskQueue {
+            queue: RwLock::new(ArrayQueue::new(INITIAL_QUEUE_CAPACITY)),
+        }
+    }
+
+    /// Queues task `id`, unless its `queued` flag says it already is.
+    pub(super) fn wake(&self, id: TaskId, queued: &AtomicBool) {
+        if !queued.swap(true, Ordering::AcqRel) {
+            self.push(id);
+        }
+    }
+
+    /// Queues task `id`, which must have its `queued` flag set.
+    pub(super) fn push(&self, id: TaskId) {
+        if self.queue.read().push(id).is_err() {
+            panic!("Task queue has no room, though it is grown with every task");
+        }
+    }
+
+    pub(super) fn pop(&self) -> Option<TaskId> {
+        self.queue.read().pop().ok()
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.queue.read().is_empty()
+    }
+
+    /// Makes room for at least `len` tasks.
+    fn reserve(&self, len: usize) {
+        let capacity = self.queue.read().capacity();
+        if len <= capacity {
+            return;
+        }
+        let grown = ArrayQueue::new(len.max(2 * capacity));
+        let old = without_interrupts(|| {
+            let mut queue = self.queue.write();
+            while let Ok(id) = queue.pop() {
+                let _ = grown.push(id);
+            }
+            core::mem::replace(&mut *queue, grown)
+        });
+        drop(old);
+    }
+}
 
 pub struct Executor {
     pub(super) tasks: BTreeMap<TaskId, Task>,
-    pub(super) task_queue: Arc<ArrayQueue<TaskId>>,
+    pub(super) task_queue: Arc<TaskQueue>,
     pub(super) waker_cache: BTreeMap<TaskId, Waker>,
+    /// Tasks being polled right now, on any core. They are out of `tasks` meanwhile.
+    pub(super) running: BTreeSet<TaskId>,
+    /// Running tasks that were woken while being polled, to be queued again afterwards.
+    pub(super) woken_while_running: BTreeSet<TaskId>,
+    /// Finished tasks whose id is still in the queue, where it takes up room until popped.
+    pub(super) finished_but_queued: usize,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: Arc::new(TaskQueue::new()),
             waker_cache: BTreeMap::new(),
+            running: BTreeSet::new(),
+            woken_while_running: BTreeSet::new(),
+            finished_but_queued: 0,
         }
     }
 
-    pub fn spawn(&mut self, f: impl core::future::Future<Output = ()> + Send + 'static) {
-        let task = Task::new(f);
-        let id = task.id;
+    /// Runs `f` as a task called `name`. The task keeps running if the returned handle is
+    /// dropped.
+    pub fn spawn<F>(&mut self, name: impl Into<Cow<'static, str>>, f: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(JoinState {
+            result: None,
+            waker: None,
+        }));
+        let aborted = Arc::new(AtomicBool::new(false));
+        let task_state = state.clone();
+        let task = Task::new(name, aborted.clone(), async move {
+            let output = f.await;
+            JoinState::finish(&task_state, Ok(output));
+        });
+        let (id, queued) = (task.id, task.queued.clone());
+        log::trace!("Spawned task {:?} ({id:?})", task.name);
+        // Every id the queue may hold, this one's included
+        let len = self.tasks.len() + self.running.len() + self.finished_but_queued + 1;
+        self.task_queue.reserve(len);
         if self.tasks.insert(task.id, task).is_some() {
             panic!("Tried to spawn two tasks with same ID")
         }
-        self.task_queue.push(id).expect("queue full");
+        self.task_queue.push(id);
+        JoinHandle {
+            id,
+            state,
+            aborted,
+            queued,
+            task_queue: self.task_queue.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was stopped with [`JoinHandle::abort`] before it finished.
+    Aborted,
+}
+
+struct JoinState<T> {
+    /// Set once, by whichever of finishing and aborting comes first.
+    result: Option<Result<T, JoinError>>,
+    /// Woken when the result is set.
+    waker: Option<Waker>,
+}
+
+impl<T> JoinState<T> {
+    fn finish(state: &Mutex<Self>, result: Result<T, JoinError>) {
+        let mut state = state.lock();
+        if state.result.is_none() {
+            state.result = Some(result);
+        }
+        let waker = state.waker.take();
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Awaits the output of a task, or cancels it.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    /// Shared with the task, which sets the result when it finishes.
+    state: Arc<Mutex<JoinState<T>>>,
+    aborted: Arc<AtomicBool>,
+    queued: Arc<AtomicBool>,
+    task_queue: Arc<TaskQueue>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Stops the task before it is polled again, dropping its future. Awaiting the handle then
+    /// gives [`JoinError::Aborted`], unless the task had already finished.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+        JoinState::finish(&self.state, Err(JoinError::Aborted));
+        // Wake it so that it gets dropped right away
+        self.task_queue.wake(self.id, &self.queued);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().result.is_some()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
     }
 }
 
 pub(super) struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    queued: Arc<AtomicBool>,
+    task_queue: Arc<TaskQueue>,
 }
 
 impl TaskWaker {
-    pub fn new_as_waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    pub fn new_as_waker(
+        task_id: TaskId,
+        queued: Arc<AtomicBool>,
+        task_queue: Arc<TaskQueue>,
+    ) -> Waker {
         Waker::from(Arc::new(TaskWaker {
             task_id,
+            queued,
             task_queue,
         }))
     }
 
     pub fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task queue full");
+        self.task_queue.wake(self.task_id, &self.queued);
     }
 }
 
@@ -57,3 +234,45 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn abort_before_finishing() {
+        let mut exec = Executor::new();
+        let mut handle = exec.spawn("never finishes", core::future::pending::<()>());
+        let id = exec.task_queue.pop().unwrap();
+        // As the executor does before polling it
+        exec.tasks[&id].queued.store(false, Ordering::Release);
+        assert!(!handle.is_finished());
+
+        handle.abort();
+        assert!(exec.tasks[&id].is_aborted());
+        // Queued again, to be dropped, but only once
+        handle.abort();
+        assert_eq!(exec.task_queue.pop(), Some(id));
+        assert_eq!(exec.task_queue.pop(), None);
+        let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+        assert_eq!(
+            Pin::new(&mut handle).poll(&mut cx),
+            Poll::Ready(Err(JoinError::Aborted))
+        );
+    }
+
+    #[test_case]
+    fn queue_grows_with_tasks() {
+        let mut exec = Executor::new();
+        let n = 3 * INITIAL_QUEUE_CAPACITY;
+        for _ in 0..n {
+            exec.spawn("pending", core::future::pending::<()>());
+        }
+        let mut popped = 0;
+        while let Some(id) = exec.task_queue.pop() {
+            assert!(exec.tasks.contains_key(&id));
+            popped += 1;
+        }
+        assert_eq!(popped, n);
+    }
+}
@@ -1,6 +1,13 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use conquer_once::spin::OnceCell;
+use core::future::Future;
+use core::task::Waker;
 use crossbeam_queue::ArrayQueue;
 use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use crate::process::ProcessId;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
@@ -55,22 +62,159 @@ impl futures_util::stream::Stream for ScancodeStream {
     }
 }
 
-pub async fn print_keypresses() {
-    use crate::print;
+/// How keyboard input is delivered to the foreground process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyboardMode {
+    /// Keys are decoded and typed on the [terminal](crate::tty::active) of the active virtual
+    /// terminal.
+    Line,
+    /// Reads see raw scancodes, one at a time, without echo.
+    Raw,
+}
+
+/// Most scancodes we are willing to hold for a process that is not reading.
+const MAX_BUFFERED: usize = 4096;
+
+struct Input {
+    mode: KeyboardMode,
+    scancodes: VecDeque<u8>,
+    readers: Vec<Waker>,
+}
+
+static INPUT: Mutex<Input> = Mutex::new(Input {
+    mode: KeyboardMode::Line,
+    scancodes: VecDeque::new(),
+    readers: Vec::new(),
+});
+
+/// Locks the input state. Processes can be preempted in syscalls, so the lock must never be held
+/// with interrupts enabled.
+fn with_input<R>(f: impl FnOnce(&mut Input) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut INPUT.lock()))
+}
+
+impl Input {
+    fn wake_readers(&mut self) {
+        for waker in self.readers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+pub fn mode() -> KeyboardMode {
+    with_input(|input| input.mode)
+}
+
+/// Switches back to line mode, discarding pending scancodes. Done whenever the foreground
+/// changes.
+pub fn reset_mode() {
+    with_input(|input| {
+        input.mode = KeyboardMode::Line;
+        input.scancodes.clear();
+        input.wake_readers();
+    })
+}
+
+/// Changes how input is delivered to `pid`. Returns `false` if `pid` is not in the foreground of
+/// the active terminal.
+pub fn set_mode(pid: ProcessId, mode: KeyboardMode) -> bool {
+    if !crate::tty::active().is_foreground(pid) {
+        return false;
+    }
+    with_input(|input| {
+        if input.mode != mode {
+            input.mode = mode;
+            input.scancodes.clear();
+            input.wake_readers();
+        }
+    });
+    true
+}
+
+/// Resolves to the next scancode once `pid` is in the foreground of the active terminal and the
+/// keyboard is in raw mode.
+pub fn read_scancode(pid: ProcessId) -> impl Future<Output = u8> + Send {
+    core::future::poll_fn(move |cx| {
+        let foreground = crate::tty::active().is_foreground(pid);
+        with_input(|input| {
+            if foreground && input.mode == KeyboardMode::Raw {
+                if let Some(scancode) = input.scancodes.pop_front() {
+                    return Poll::Ready(scancode);
+                }
+            }
+            input.readers.push(cx.waker().clone());
+            Poll::Pending
+        })
+    })
+}
+
+/// The virtual terminal Alt + `code` switches to, if any.
+fn vt_for_key(code: pc_keyboard::KeyCode) -> Option<usize> {
+    use pc_keyboard::KeyCode;
+    let vt = match code {
+        KeyCode::F1 => 0,
+        KeyCode::F2 => 1,
+        KeyCode::F3 => 2,
+        KeyCode::F4 => 3,
+        KeyCode::F5 => 4,
+        KeyCode::F6 => 5,
+        _ => return None,
+    };
+    (vt < crate::video::vt::COUNT).then_some(vt)
+}
+
+/// Feeds keyboard input to the foreground process of the active terminal, according to the
+/// current [`KeyboardMode`]. Alt+F1 to Alt+F6 switch virtual terminals, whatever the mode.
+pub async fn route_input() {
     use futures_util::StreamExt;
-    use pc_keyboard::{layouts::Us104Key, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+    use pc_keyboard::{
+        layouts::Us104Key, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1,
+    };
 
     let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(Us104Key, ScancodeSet1, HandleControl::Ignore);
+    // Control combinations come out as control characters, for the terminal to act on
+    let mut keyboard = Keyboard::new(Us104Key, ScancodeSet1, HandleControl::MapLettersToUnicode);
+    // The decoder doesn't track Alt. Both keys are tracked, as either may be let go first
+    let (mut alt_left, mut alt_right) = (false, false);
 
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(c) => print!("{}", c),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
+        // Always decode, so modifier state stays correct across mode switches
+        let key = match keyboard.add_byte(scancode) {
+            Ok(Some(key_event)) => {
+                match (key_event.code, key_event.state) {
+                    (KeyCode::AltLeft, state) => alt_left = state == KeyState::Down,
+                    (KeyCode::AltRight, state) => alt_right = state == KeyState::Down,
+                    (code, KeyState::Down) if alt_left || alt_right => {
+                        if let Some(vt) = vt_for_key(code) {
+                            if crate::video::vt::switch_to(vt) {
+                                reset_mode();
+                            }
+                            continue;
+                        }
+                    }
+                    _ => {}
+                }
+                keyboard.process_keyevent(key_event)
+            }
+            _ => None,
+        };
+        let typed = with_input(|input| match input.mode {
+            KeyboardMode::Raw => {
+                if input.scancodes.len() < MAX_BUFFERED {
+                    input.scancodes.push_back(scancode);
+                    input.wake_readers();
+                } else {
+                    log::warn!("Keyboard buffer full; dropping scancode");
                 }
+                None
             }
+            KeyboardMode::Line => match key {
+                Some(DecodedKey::Unicode(c)) => Some(c),
+                _ => None,
+            },
+        });
+        if let Some(c) = typed {
+            crate::tty::active().receive(c);
         }
     }
 }
@@ -1,13 +1,15 @@
-use alloc::boxed::Box;
+use alloc::{borrow::Cow, boxed::Box, sync::Arc};
 use conquer_once::spin::OnceCell;
 use core::{
     future::Future,
     pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
     task::{Context, Poll},
 };
 use spin::Mutex;
 
 use executor::Executor;
+pub use executor::{JoinError, JoinHandle};
 
 use self::executor::TaskWaker;
 
@@ -18,8 +20,13 @@ pub mod timer;
 
 pub static EXECUTOR: OnceCell<Mutex<Executor>> = OnceCell::uninit();
 
+/// Sets up the executor, with the task that fires [timers](timer) already in it.
 pub fn init_executor() {
-    EXECUTOR.init_once(|| Mutex::new(Executor::new()))
+    EXECUTOR.init_once(|| {
+        let mut exec = Executor::new();
+        exec.spawn("timers", timer::fire_expired());
+        Mutex::new(exec)
+    })
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
@@ -35,17 +42,34 @@ impl TaskId {
 
 pub struct Task {
     id: TaskId,
+    name: Cow<'static, str>,
+    /// Set when the task is to be dropped instead of polled again.
+    aborted: Arc<AtomicBool>,
+    /// Set while its id is in the executor's queue, so that it is only ever in there once. New
+    /// tasks start out queued, and finished ones stay that way.
+    queued: Arc<AtomicBool>,
     future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Self {
+    pub fn new(
+        name: impl Into<Cow<'static, str>>,
+        aborted: Arc<AtomicBool>,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) -> Self {
         Task {
             id: TaskId::new(),
+            name: name.into(),
+            aborted,
+            queued: Arc::new(AtomicBool::new(true)),
             future: Box::pin(future),
         }
     }
 
+    fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
     fn poll(&mut self, cx: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(cx)
     }
@@ -58,11 +82,15 @@ fn run_ready_tasks() {
         let mut exec = exec.lock();
 
         let t = loop {
-            if let Ok(task_id) = exec.task_queue.pop() {
+            if let Some(task_id) = exec.task_queue.pop() {
                 if exec.tasks.contains_key(&task_id) {
                     break Some(task_id);
+                } else if exec.running.contains(&task_id) {
+                    // Another core is polling it, and will have to poll it again
+                    exec.woken_while_running.insert(task_id);
                 } else {
-                    continue; // The task no longer exists for whatever reason
+                    // The task has finished since it was woken
+                    exec.finished_but_queued -= 1;
                 };
             } else {
                 break None;
@@ -70,13 +98,16 @@ fn run_ready_tasks() {
         };
 
         if let Some(task_id) = t {
-            let task_queue = exec.task_queue.clone();
+            let task = exec.tasks.remove(&task_id).unwrap();
+            // Acquires what its waker saw to before waking it, and lets it be woken again
+            task.queued.swap(false, Ordering::AcqRel);
+            let (queued, task_queue) = (task.queued.clone(), exec.task_queue.clone());
             let waker = exec
                 .waker_cache
                 .entry(task_id)
-                .or_insert_with(move || TaskWaker::new_as_waker(task_id, task_queue))
+                .or_insert_with(move || TaskWaker::new_as_waker(task_id, queued, task_queue))
                 .clone();
-            let task = exec.tasks.remove(&task_id).unwrap();
+            exec.running.insert(task_id);
 
             Some((task_id, task, waker))
         } else {
@@ -85,28 +116,48 @@ fn run_ready_tasks() {
     };
 
     while let Some((task_id, mut task, waker)) = pop_task() {
-        log::trace!("Running task {task_id:?}");
         let mut cx = Context::from_waker(&waker);
-        match task.poll(&mut cx) {
+        let poll = if task.is_aborted() {
+            log::debug!("Dropping aborted task {:?} ({task_id:?})", task.name);
+            Poll::Ready(())
+        } else {
+            log::trace!("Running task {:?} ({task_id:?})", task.name);
+            task.poll(&mut cx)
+        };
+        match poll {
             Poll::Ready(()) => {
                 let mut exec = exec.lock();
-                exec.tasks.remove(&task_id);
+                exec.running.remove(&task_id);
+                // Never queued again, so that stale wakers can't take up room in the queue
+                let queued = task.queued.swap(true, Ordering::AcqRel);
+                if !exec.woken_while_running.remove(&task_id) && queued {
+                    exec.finished_but_queued += 1;
+                }
                 exec.waker_cache.remove(&task_id);
+                drop(exec);
+                log::trace!("Task {:?} ({task_id:?}) finished", task.name);
             }
             Poll::Pending => {
                 // Put that thing back where it came from, or so help me!
                 let mut exec = exec.lock();
                 exec.tasks.insert(task_id, task);
+                exec.running.remove(&task_id);
+                if exec.woken_while_running.remove(&task_id) {
+                    exec.task_queue.push(task_id);
+                }
             }
         }
     }
 }
 
-/// Let the executor take control of this CPU core.
+/// Let the executor and the process scheduler take control of this CPU core. Kernel tasks run
+/// whenever they are ready, in between processes.
 pub fn run() -> ! {
     loop {
         run_ready_tasks();
-        sleep_if_idle();
+        if !crate::process::scheduler::run_next() {
+            sleep_if_idle();
+        }
     }
 }
 
@@ -116,7 +167,7 @@ fn sleep_if_idle() {
         let exec = EXECUTOR.get().unwrap().lock();
         exec.task_queue.is_empty()
     };
-    if queue_is_empty {
+    if queue_is_empty && !crate::process::scheduler::has_runnable() {
         x86_64::instructions::interrupts::enable_and_hlt();
     } else {
         x86_64::instructions::interrupts::enable();
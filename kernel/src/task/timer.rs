@@ -1,44 +1,221 @@
+//! Timers for kernel async code, with deadlines on the [`now_ns`] clock.
+//!
+//! Pending timers wait in a queue ordered by deadline. The timer interrupt is armed for the
+//! earliest one and checks for expired ones, which the [`fire_expired`] task then pops off and
+//! wakes. A timer that is dropped before it fires is taken out of the queue again.
+
+use alloc::collections::BTreeMap;
 use core::future::Future;
 use core::pin::Pin;
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_util::task::AtomicWaker;
 use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::arch::time::{now_ns, wake_by};
 
-static TICKS: AtomicU64 = AtomicU64::new(0);
-static WAKERS: Mutex<[Option<(Waker, u64)>; 128]> = Mutex::new([const { None }; 128]);
+/// Tells timers with the same deadline apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct TimerId(u64);
 
+impl TimerId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Wakers of pending timers, by deadline. Only ever locked with interrupts disabled, as the timer
+/// interrupt locks it too.
+static TIMERS: Mutex<BTreeMap<(u64, TimerId), Waker>> = Mutex::new(BTreeMap::new());
+/// The [`fire_expired`] task.
+static EXPIRED: AtomicWaker = AtomicWaker::new();
+
+/// Has [`fire_expired`] wake the timers whose deadline has passed. Called from the timer
+/// interrupt, which must not allocate or free memory, as it could deadlock on the heap. Taking
+/// timers out of the queue and dropping their wakers both may.
 pub(crate) fn tick_timer() {
-    let t = TICKS.fetch_add(1, Ordering::Relaxed);
-    for entry in WAKERS.lock().iter_mut().filter(|w| w.is_some()) {
-        let (waker, target) = entry.take().unwrap();
-        if t >= target {
-            waker.wake();
-        } else {
-            entry.replace((waker, target));
+    match first_deadline() {
+        Some(deadline) if deadline <= now_ns() => EXPIRED.wake(),
+        // It may have been armed on another core, which could be busy
+        Some(deadline) => wake_by(deadline),
+        None => {}
+    }
+}
+
+/// The deadline of the timer to fire first.
+fn first_deadline() -> Option<u64> {
+    without_interrupts(|| {
+        let timers = TIMERS.lock();
+        timers.first_key_value().map(|(&(deadline, _), _)| deadline)
+    })
+}
+
+/// Wakes the timers that have expired by `now`.
+fn wake_expired(now: u64) {
+    loop {
+        // The lock isn't held while waking, as who knows what a waker does
+        let expired = without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            match timers.first_key_value() {
+                Some((&(deadline, _), _)) if deadline <= now => timers.pop_first(),
+                _ => None,
+            }
+        });
+        match expired {
+            Some((_, waker)) => waker.wake(),
+            None => break,
         }
     }
 }
 
-pub fn wait_n_ticks(n: u64) -> impl Future<Output = ()> + Send {
-    TimerWaiter(TICKS.load(Ordering::Relaxed) + n)
+/// Wakes expired timers whenever the timer interrupt finds some, forever. The executor runs this
+/// from the start.
+pub(super) async fn fire_expired() {
+    core::future::poll_fn(|cx| {
+        EXPIRED.register(cx.waker());
+        wake_expired(now_ns());
+        if let Some(deadline) = first_deadline() {
+            wake_by(deadline);
+        }
+        Poll::Pending
+    })
+    .await
 }
 
-struct TimerWaiter(u64);
+/// Waits for at least `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    let duration_ns = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+    sleep_until(now_ns().saturating_add(duration_ns))
+}
 
-impl Future for TimerWaiter {
+/// Waits until [`now_ns`] reaches `deadline_ns`. The timer interrupt is armed for it, so it is
+/// usually late by microseconds, unless every core is busy running processes.
+pub fn sleep_until(deadline_ns: u64) -> Sleep {
+    Sleep {
+        deadline_ns,
+        registered: None,
+    }
+}
+
+/// Future returned by [`sleep`] and [`sleep_until`].
+pub struct Sleep {
+    deadline_ns: u64,
+    /// Set while the waker is in [`TIMERS`].
+    registered: Option<TimerId>,
+}
+
+impl Sleep {
+    fn deregister(&mut self) {
+        if let Some(id) = self.registered.take() {
+            without_interrupts(|| TIMERS.lock().remove(&(self.deadline_ns, id)));
+        }
+    }
+}
+
+impl Future for Sleep {
     type Output = ();
 
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if now_ns() >= self.deadline_ns {
+            self.deregister();
+            return Poll::Ready(());
+        }
+        let id = *self.registered.get_or_insert_with(TimerId::new);
+        let waker = cx.waker().clone();
+        without_interrupts(|| TIMERS.lock().insert((self.deadline_ns, id), waker));
+        wake_by(self.deadline_ns);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.deregister();
+    }
+}
+
+/// The error of a [`timeout`] whose time ran out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Runs `fut` for up to `duration`, and drops it if it isn't done by then.
+pub fn timeout<F: Future>(fut: F, duration: Duration) -> Timeout<F> {
+    Timeout {
+        fut,
+        sleep: sleep(duration),
+    }
+}
+
+/// Future returned by [`timeout`].
+pub struct Timeout<F> {
+    fut: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if TICKS.load(Ordering::Relaxed) >= self.0 {
-            Poll::Ready(())
-        } else {
-            WAKERS
-                .lock()
-                .iter_mut()
-                .find(|w| w.is_none())
-                .expect("Out of timer slots")
-                .replace((cx.waker().clone(), self.0));
-            Poll::Pending
+        // `fut` is never moved out of `self`, and `Sleep` is `Unpin`
+        let this = unsafe { self.get_unchecked_mut() };
+        let fut = unsafe { Pin::new_unchecked(&mut this.fut) };
+        if let Poll::Ready(output) = fut.poll(cx) {
+            return Poll::Ready(Ok(output));
         }
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{sync::Arc, task::Wake};
+    use core::sync::atomic::AtomicBool;
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    struct FlagWaker(AtomicBool);
+
+    impl Wake for FlagWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test_case]
+    fn dropped_sleep_is_cancelled() {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        let pending = || without_interrupts(|| TIMERS.lock().len());
+        let before = pending();
+
+        let mut sleep = sleep(Duration::from_secs(60));
+        assert_eq!(Pin::new(&mut sleep).poll(&mut cx), Poll::Pending);
+        // Polling again only replaces the waker
+        assert_eq!(Pin::new(&mut sleep).poll(&mut cx), Poll::Pending);
+        assert_eq!(pending(), before + 1);
+        drop(sleep);
+        assert_eq!(pending(), before);
+
+        // Expired timers are only taken out of the queue outside of the timer interrupt
+        let woken = Arc::new(FlagWaker(AtomicBool::new(false)));
+        let id = TimerId::new();
+        without_interrupts(|| TIMERS.lock().insert((0, id), Waker::from(woken.clone())));
+        tick_timer();
+        assert!(without_interrupts(|| TIMERS.lock().contains_key(&(0, id))));
+        wake_expired(now_ns());
+        assert!(woken.0.load(Ordering::Relaxed));
+        assert_eq!(pending(), before);
+
+        let mut elapsed = timeout(core::future::pending::<()>(), Duration::ZERO);
+        let elapsed = unsafe { Pin::new_unchecked(&mut elapsed) };
+        assert_eq!(elapsed.poll(&mut cx), Poll::Ready(Err(Elapsed)));
     }
 }
@@ -0,0 +1,365 @@
+//! The terminals processes read their standard input from and write their output to, one for
+//! each [virtual terminal](crate::video::vt).
+//!
+//! Keyboard input goes to the terminal of the active virtual terminal, and output, echo included,
+//! to its console. The first terminal is also connected to the COM1 serial port, both ways. What
+//! happens in between follows each terminal's [`Termios`]: in canonical mode, input is edited a
+//! line at a time and reads see whole lines, while otherwise reads see bytes as they arrive.
+//! Ctrl-C and Ctrl-Z send signals to the foreground process group, which is the only one allowed
+//! to read.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::task::{Poll, Waker};
+use kernel_uapi::syscall::{
+    Termios, ECHO, ICANON, ICRNL, ISIG, NCCS, VEOF, VERASE, VINTR, VKILL, VSUSP,
+};
+use spin::Mutex;
+
+use crate::process::signal::{self, Signal};
+use crate::process::{table, Interrupted, ProcessId};
+use crate::video::vt;
+
+/// Most bytes of input we are willing to hold for processes that are not reading.
+const MAX_BUFFERED: usize = 4096;
+
+pub const DEFAULT_TERMIOS: Termios = Termios {
+    iflag: ICRNL,
+    lflag: ISIG | ICANON | ECHO,
+    cc: {
+        let mut cc = [0; NCCS];
+        cc[VINTR] = 0x03; // Ctrl-C
+        cc[VSUSP] = 0x1A; // Ctrl-Z
+        cc[VERASE] = 0x7F;
+        cc[VKILL] = 0x15; // Ctrl-U
+        cc[VEOF] = 0x04; // Ctrl-D
+        cc
+    },
+};
+
+/// The terminals of the virtual terminals, in order.
+pub static TTYS: [Tty; vt::COUNT] = [
+    Tty::new(0),
+    Tty::new(1),
+    Tty::new(2),
+    Tty::new(3),
+    Tty::new(4),
+    Tty::new(5),
+];
+/// The terminal also connected to COM1, and which init runs on.
+pub const SERIAL_TTY: usize = 0;
+
+/// The terminal of the virtual terminal on screen.
+pub fn active() -> &'static Tty {
+    &TTYS[vt::active()]
+}
+
+pub struct Tty {
+    vt: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// The virtual terminal output goes to.
+    vt: usize,
+    termios: Termios,
+    /// The process group allowed to read, which signals from the keyboard go to.
+    foreground: Option<ProcessId>,
+    /// The line being edited in canonical mode, not yet visible to readers.
+    line: Vec<u8>,
+    /// Input ready to be read.
+    input: VecDeque<u8>,
+    /// Set by `VEOF` on an empty line, for the next read to return nothing.
+    eof: bool,
+    readers: Vec<Waker>,
+}
+
+impl Tty {
+    const fn new(vt: usize) -> Self {
+        Tty {
+            vt,
+            state: Mutex::new(State::new(vt)),
+        }
+    }
+
+    /// Locks the terminal state. Processes can be preempted in syscalls, so the lock must never be
+    /// held with interrupts enabled.
+    fn with_state<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        x86_64::instructions::interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    pub fn termios(&self) -> Termios {
+        self.with_state(|state| state.termios)
+    }
+
+    /// Changes the settings. Input typed so far is kept, and a line half edited in canonical mode
+    /// becomes readable when leaving it.
+    pub fn set_termios(&self, termios: Termios) {
+        self.with_state(|state| {
+            if termios.lflag & ICANON == 0 {
+                let line = core::mem::take(&mut state.line);
+                state.input.extend(line);
+            }
+            state.termios = termios;
+            state.wake_readers();
+        })
+    }
+
+    pub fn foreground(&self) -> Option<ProcessId> {
+        self.with_state(|state| state.foreground)
+    }
+
+    /// Whether `pid` is in the foreground process group.
+    pub fn is_foreground(&self, pid: ProcessId) -> bool {
+        let pgid = table::pgid_of(pid);
+        pgid.is_some() && self.foreground() == pgid
+    }
+
+    /// Gives the terminal to process group `pgid`, and the keyboard back to it if a process had
+    /// it in raw mode.
+    pub fn set_foreground(&self, pgid: Option<ProcessId>) {
+        self.with_state(|state| {
+            state.foreground = pgid;
+            state.wake_readers();
+        });
+        if vt::active() == self.vt {
+            crate::task::keyboard::reset_mode();
+        }
+    }
+
+    /// Processes a character typed on the terminal.
+    pub fn receive(&self, c: char) {
+        let (signal, foreground) = self.with_state(|state| (state.receive(c), state.foreground));
+        if let (Some(signal), Some(pgid)) = (signal, foreground) {
+            signal::send_to_group(pgid, signal);
+        }
+    }
+
+    /// Resolves to up to `max` bytes of input once `pid` is in the foreground process group. In
+    /// canonical mode, a read never goes past the end of a line, and returns nothing at end of
+    /// file.
+    pub fn read(&'static self, pid: ProcessId, max: usize) -> impl Future<Output = Vec<u8>> + Send {
+        let pgid = table::pgid_of(pid);
+        core::future::poll_fn(move |cx| {
+            self.with_state(|state| {
+                if pgid.is_some() && state.foreground == pgid {
+                    if let Some(read) = state.read(max) {
+                        return Poll::Ready(read);
+                    }
+                }
+                state.readers.push(cx.waker().clone());
+                Poll::Pending
+            })
+        })
+    }
+
+    /// Reads into `buf` on behalf of the process making the current syscall, blocking it until it
+    /// is in the foreground and there is input.
+    pub fn read_current(&'static self, buf: &mut [u8]) -> Result<usize, Interrupted> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pid = crate::process::current_pid().expect("Terminal read outside of a process");
+        let read = crate::process::block_current_on(self.read(pid, buf.len()))?;
+        buf[..read.len()].copy_from_slice(&read);
+        Ok(read.len())
+    }
+
+    pub fn write(&self, buf: &[u8]) {
+        output(self.vt, &alloc::string::String::from_utf8_lossy(buf));
+    }
+}
+
+impl State {
+    const fn new(vt: usize) -> Self {
+        State {
+            vt,
+            termios: DEFAULT_TERMIOS,
+            foreground: None,
+            line: Vec::new(),
+            input: VecDeque::new(),
+            eof: false,
+            readers: Vec::new(),
+        }
+    }
+
+    fn wake_readers(&mut self) {
+        for waker in self.readers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Whether `c` is the special character `index` of [`Termios::cc`].
+    fn is_special(&self, c: char, index: usize) -> bool {
+        let special = self.termios.cc[index];
+        special != 0 && c == char::from(special)
+    }
+
+    fn echoes(&self) -> bool {
+        self.termios.lflag & ECHO != 0
+    }
+
+    fn echo(&self, s: &str) {
+        if self.echoes() {
+            output(self.vt, s);
+        }
+    }
+
+    /// Applies the line discipline to `c`, and returns the signal it generates, if any.
+    fn receive(&mut self, mut c: char) -> Option<Signal> {
+        if c == '\r' && self.termios.iflag & ICRNL != 0 {
+            c = '\n';
+        }
+        if self.termios.lflag & ISIG != 0 {
+            let signal = if self.is_special(c, VINTR) {
+                Some((Signal::Interrupt, "^C\n"))
+            } else if self.is_special(c, VSUSP) {
+                Some((Signal::Stop, "^Z\n"))
+            } else {
+                None
+            };
+            if let Some((signal, echo)) = signal {
+                self.line.clear();
+                self.input.clear();
+                self.echo(echo);
+                return Some(signal);
+            }
+        }
+        if self.termios.lflag & ICANON != 0 {
+            self.edit_line(c);
+        } else {
+            let mut bytes = [0; 4];
+            let s = c.encode_utf8(&mut bytes);
+            if self.input.len() + s.len() > MAX_BUFFERED {
+                log::warn!("Terminal buffer full; dropping input");
+                return None;
+            }
+            self.input.extend(s.as_bytes());
+            self.echo(s);
+            self.wake_readers();
+        }
+        None
+    }
+
+    fn edit_line(&mut self, c: char) {
+        if c == '\n' {
+            self.line.push(b'\n');
+            self.echo("\n");
+            self.finish_line();
+        } else if self.is_special(c, VEOF) {
+            if self.line.is_empty() {
+                self.eof = true;
+            }
+            self.finish_line();
+        } else if self.is_special(c, VERASE) || c == '\x08' {
+            self.erase_char();
+        } else if self.is_special(c, VKILL) {
+            while self.erase_char() {}
+        } else if !c.is_control() && self.line.len() + c.len_utf8() < MAX_BUFFERED {
+            let mut bytes = [0; 4];
+            let s = c.encode_utf8(&mut bytes);
+            self.line.extend_from_slice(s.as_bytes());
+            self.echo(s);
+        }
+    }
+
+    /// Makes the line being edited readable.
+    fn finish_line(&mut self) {
+        if self.input.len() + self.line.len() <= MAX_BUFFERED {
+            self.input.extend(self.line.drain(..));
+        } else {
+            log::warn!("Terminal buffer full; dropping a line of input");
+            self.line.clear();
+        }
+        self.wake_readers();
+    }
+
+    /// Removes the last character from the line being edited. Returns whether there was one.
+    fn erase_char(&mut self) -> bool {
+        // Remove a whole UTF-8 sequence
+        let mut erased = false;
+        while let Some(b) = self.line.pop() {
+            erased = true;
+            if b & 0xC0 != 0x80 {
+                break;
+            }
+        }
+        if erased && self.echoes() {
+            echo_backspace(self.vt);
+        }
+        erased
+    }
+
+    /// Takes up to `max` bytes of input, or returns `None` if there is nothing to read yet.
+    fn read(&mut self, max: usize) -> Option<Vec<u8>> {
+        if self.input.is_empty() {
+            // End of file only counts in canonical mode, and after what came before it
+            let eof = core::mem::take(&mut self.eof);
+            return (eof && self.termios.lflag & ICANON != 0).then(Vec::new);
+        }
+        let len = if self.termios.lflag & ICANON != 0 {
+            match self.input.iter().position(|&b| b == b'\n') {
+                Some(newline) => newline + 1,
+                None => self.input.len(),
+            }
+        } else {
+            self.input.len()
+        };
+        Some(self.input.drain(..len.min(max)).collect())
+    }
+}
+
+/// Writes `s` to the console of virtual terminal `vt`, and to COM1 if that is its terminal too.
+fn output(vt: usize, s: &str) {
+    use core::fmt::Write;
+    vt::with_console(vt, |console| console.write_str(s));
+    if vt == SERIAL_TTY {
+        crate::serial_print!("{}", s);
+    }
+}
+
+fn echo_backspace(vt: usize) {
+    vt::with_console(vt, |console| console.backspace());
+    if vt == SERIAL_TTY {
+        crate::serial_print!("\x08 \x08");
+    }
+}
+
+/// Hands every terminal process group `from` has to `to`.
+pub fn pass_foreground(from: ProcessId, to: Option<ProcessId>) {
+    for tty in &TTYS {
+        if tty.foreground() == Some(from) {
+            tty.set_foreground(to);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn line_editing() {
+        let mut state = State::new(SERIAL_TTY);
+        for c in "ab\x08\x08\x08cé\x7Fd".chars() {
+            state.receive(c);
+        }
+        assert_eq!(state.read(usize::MAX), None);
+        state.receive('\r');
+        assert_eq!(state.read(usize::MAX).as_deref(), Some(&b"cd\n"[..]));
+        assert!(state.line.is_empty());
+
+        for c in "xy\x15z\x04".chars() {
+            state.receive(c);
+        }
+        assert_eq!(state.read(usize::MAX).as_deref(), Some(&b"z"[..]));
+        state.receive('\x04');
+        assert_eq!(state.read(usize::MAX), Some(Vec::new()));
+
+        state.termios.lflag &= !ICANON;
+        state.receive('q');
+        assert_eq!(state.read(usize::MAX).as_deref(), Some(&b"q"[..]));
+        assert_eq!(state.receive('\x03'), Some(Signal::Interrupt));
+    }
+}
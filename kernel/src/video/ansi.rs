@@ -0,0 +1,155 @@
+//! A parser for the subset of VT100/ANSI escape sequences the console understands.
+
+/// Most numeric parameters a control sequence may have; any more are dropped.
+const MAX_PARAMS: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    /// A character to draw.
+    Print(char),
+    /// A C0 control character, like `\n` or `\x08`.
+    Control(char),
+    /// A complete control sequence.
+    Csi(Csi),
+}
+
+/// A control sequence, `ESC [`, its parameters and a final character.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    /// Whether the parameters started with `?`, as in `ESC [ ? 25 h`.
+    pub private: bool,
+    /// The final character, which says what to do.
+    pub action: char,
+}
+
+impl Csi {
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+
+    /// Parameter `i`, or `default` if it is missing or zero.
+    pub fn param(&self, i: usize, default: u16) -> u16 {
+        match self.params().get(i) {
+            Some(&p) if p != 0 => p,
+            _ => default,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+pub struct Parser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    private: bool,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Parser {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            len: 0,
+            private: false,
+        }
+    }
+
+    /// Feeds `c` to the parser, returning what to do once it makes up a complete action.
+    /// Unsupported sequences are swallowed.
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match (self.state, c) {
+            (_, '\x1b') => {
+                self.state = State::Escape;
+                None
+            }
+            // Control characters take effect even in the middle of a sequence
+            (_, '\0'..='\x1f' | '\x7f') => Some(Action::Control(c)),
+            (State::Ground, _) => Some(Action::Print(c)),
+            (State::Escape, '[') => {
+                self.state = State::Csi;
+                self.params = [0; MAX_PARAMS];
+                self.len = 0;
+                self.private = false;
+                None
+            }
+            (State::Escape, _) => {
+                self.state = State::Ground;
+                None
+            }
+            (State::Csi, '0'..='9') => {
+                if self.len == 0 {
+                    self.len = 1;
+                }
+                let param = &mut self.params[self.len - 1];
+                let digit = c as u16 - '0' as u16;
+                *param = param.saturating_mul(10).saturating_add(digit);
+                None
+            }
+            (State::Csi, ';') => {
+                if self.len == 0 {
+                    self.len = 1;
+                }
+                self.len = (self.len + 1).min(MAX_PARAMS);
+                None
+            }
+            (State::Csi, '?') => {
+                self.private = true;
+                None
+            }
+            // Intermediate characters, which no sequence we support has
+            (State::Csi, ' '..='/') => None,
+            (State::Csi, '@'..='~') => {
+                self.state = State::Ground;
+                Some(Action::Csi(Csi {
+                    params: self.params,
+                    len: self.len,
+                    private: self.private,
+                    action: c,
+                }))
+            }
+            (State::Csi, _) => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn control_sequences() {
+        let mut parser = Parser::new();
+        let actions: Vec<Action> = "a\x1b[1;32mb\x1b[?25l\x1b[;5H\r\x1b]"
+            .chars()
+            .filter_map(|c| parser.advance(c))
+            .collect();
+        assert_eq!(actions.len(), 6);
+        assert_eq!(actions[0], Action::Print('a'));
+        let Action::Csi(sgr) = &actions[1] else {
+            panic!("Expected SGR, got {:?}", actions[1]);
+        };
+        assert_eq!((sgr.action, sgr.params()), ('m', &[1, 32][..]));
+        assert_eq!(actions[2], Action::Print('b'));
+        let Action::Csi(hide) = &actions[3] else {
+            panic!("Expected cursor hiding, got {:?}", actions[3]);
+        };
+        assert!(hide.private && hide.action == 'l' && hide.params() == [25]);
+        let Action::Csi(position) = &actions[4] else {
+            panic!("Expected cursor position, got {:?}", actions[4]);
+        };
+        assert_eq!((position.param(0, 1), position.param(1, 1)), (1, 5));
+        assert_eq!(actions[5], Action::Control('\r'));
+    }
+}
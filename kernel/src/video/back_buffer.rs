@@ -0,0 +1,149 @@
+//! Double buffering, to draw in normal RAM rather than video memory.
+//!
+//! Only the areas that changed are copied to video memory, a row at a time, on
+//! [`Framebuffer::flush`]. Video memory is slow to write to bit by bit and very slow to read from,
+//! which scrolling has to do.
+
+use alloc::vec::Vec;
+
+use super::framebuffer::{blit_into, FramebufferInfo, GfxRectangle, Rect};
+use super::Framebuffer;
+
+/// Most separate dirty areas tracked before they are all merged into one.
+const MAX_DIRTY: usize = 8;
+
+/// A framebuffer drawing to a copy of `F` in RAM, which only shows on `F` once flushed.
+pub struct BackBuffer<F: Framebuffer> {
+    front: F,
+    info: FramebufferInfo,
+    back: Vec<u8>,
+    /// Areas changed since the last flush. Ones that touch are merged.
+    dirty: Vec<Rect>,
+}
+
+impl<F: Framebuffer> BackBuffer<F> {
+    /// Puts a back buffer in front of `front`, starting out with what it shows. Hands `front` back
+    /// if there isn't enough memory for it.
+    pub fn new(mut front: F) -> Result<Self, F> {
+        let info = front.info();
+        let mut back = Vec::new();
+        if back.try_reserve_exact(front.get_mut().len()).is_err() {
+            return Err(front);
+        }
+        back.extend_from_slice(front.get_mut());
+        Ok(BackBuffer {
+            front,
+            info,
+            back,
+            dirty: Vec::with_capacity(MAX_DIRTY),
+        })
+    }
+
+    fn damage(&mut self, area: Rect) {
+        let mut area = area.clipped(self.info.width, self.info.height);
+        if area.is_empty() {
+            return;
+        }
+        // Growing the area may make it touch ones it didn't before, so start over on every merge
+        let mut i = 0;
+        while i < self.dirty.len() {
+            if self.dirty[i].touches(&area) {
+                area = area.union(&self.dirty.swap_remove(i));
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+        if self.dirty.len() == MAX_DIRTY {
+            area = self
+                .dirty
+                .drain(..)
+                .fold(area, |area, other| area.union(&other));
+        }
+        self.dirty.push(area);
+    }
+}
+
+impl<F: Framebuffer> Framebuffer for BackBuffer<F> {
+    fn info(&self) -> FramebufferInfo {
+        self.info.clone()
+    }
+
+    fn get_mut(&mut self) -> &mut [u8] {
+        &mut self.back
+    }
+
+    fn mark_dirty(&mut self, area: Rect) {
+        self.damage(area);
+    }
+
+    fn flush(&mut self) {
+        let front = self.front.get_mut();
+        let (stride, bytes_per_pixel) = (self.info.stride, self.info.bytes_per_pixel);
+        for area in self.dirty.drain(..) {
+            let start = area.x as usize * bytes_per_pixel;
+            let end = area.right() as usize * bytes_per_pixel;
+            for y in area.y as usize..area.bottom() as usize {
+                let row = y * stride;
+                front[row + start..row + end].copy_from_slice(&self.back[row + start..row + end]);
+            }
+        }
+        self.front.flush();
+    }
+
+    fn blit(&mut self, rect: &GfxRectangle, coords: (i32, i32)) {
+        if let Some(area) = blit_into(&self.info, &mut self.back, rect, coords) {
+            self.damage(area);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::framebuffer::{Pixel, PixelFormat};
+
+    struct MemoryFramebuffer(Vec<u8>);
+
+    impl Framebuffer for MemoryFramebuffer {
+        fn info(&self) -> FramebufferInfo {
+            FramebufferInfo {
+                format: PixelFormat::RGBA,
+                bytes_per_pixel: 4,
+                width: 8,
+                height: 8,
+                stride: 32,
+                buffer_len: 256,
+            }
+        }
+
+        fn get_mut(&mut self) -> &mut [u8] {
+            &mut self.0
+        }
+    }
+
+    #[test_case]
+    fn flushes_dirty_areas() {
+        let mut fb = BackBuffer::new(MemoryFramebuffer(alloc::vec![0; 256]))
+            .ok()
+            .unwrap();
+        let white = GfxRectangle::with(2, 2, |_, _| Pixel::new_rgb(255, 255, 255));
+        fb.blit(&white, (1, 1));
+        fb.blit(&white, (3, 1));
+        fb.blit(&white, (7, 7));
+        assert_eq!(fb.dirty, [Rect::new(1, 1, 4, 2), Rect::new(7, 7, 1, 1)]);
+        // Changes through `get_mut` only show once marked
+        fb.get_mut()[0] = 1;
+        assert!(fb.front.0.iter().all(|&b| b == 0));
+
+        fb.flush();
+        assert!(fb.dirty.is_empty());
+        assert_eq!(fb.front.0[0], 0);
+        assert_eq!(fb.front.0[32 + 4..32 + 20], [255, 255, 255, 0].repeat(4));
+        assert_eq!(fb.front.0[7 * 32 + 28], 255);
+        fb.mark_dirty(Rect::new(0, 0, 100, 1));
+        assert_eq!(fb.dirty, [Rect::new(0, 0, 8, 1)]);
+        fb.flush();
+        assert_eq!(fb.front.0[0], 1);
+    }
+}
@@ -1,124 +1,512 @@
+//! A text console on a framebuffer, which understands the common VT100/ANSI escape sequences.
+//!
+//! The text is kept in a buffer of its own, so the framebuffer can be taken away from the console
+//! and given back, redrawn, later on.
+
+use super::ansi::{Action, Csi, Parser};
 use super::font;
-use super::framebuffer::{GfxRectangle, Pixel};
+use super::framebuffer::{GfxRectangle, Pixel, Rect};
 use super::Framebuffer;
-use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+const DEFAULT_FG: u32 = 0xffffffff;
+const DEFAULT_BG: u32 = 0x000000ff;
+const TAB_WIDTH: usize = 8;
+/// Pixel rows at the bottom of a cell the cursor covers.
+const CURSOR_HEIGHT: usize = 2;
+
+/// The 16 basic colors, in RGBA. The last 8 are the bright versions of the first.
+const PALETTE: [u32; 16] = [
+    0x000000ff, 0xaa0000ff, 0x00aa00ff, 0xaa5500ff, 0x0000aaff, 0xaa00aaff, 0x00aaaaff, 0xaaaaaaff,
+    0x555555ff, 0xff5555ff, 0x55ff55ff, 0xffff55ff, 0x5555ffff, 0xff55ffff, 0x55ffffff, 0xffffffff,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Color {
+    Default,
+    /// One of the 256 xterm colors.
+    Indexed(u8),
+    /// RGBA.
+    Rgb(u32),
+}
 
-pub static CONSOLE: spin::Mutex<Option<Console<Box<dyn Framebuffer + Send>>>> =
-    spin::Mutex::new(None);
+impl Color {
+    fn to_rgba(self, default: u32, bold: bool) -> u32 {
+        match self {
+            Color::Default => default,
+            Color::Indexed(i @ 0..=7) if bold => PALETTE[i as usize + 8],
+            Color::Indexed(i @ 0..=15) => PALETTE[i as usize],
+            Color::Indexed(i @ 16..=231) => {
+                let level = |n: u8| if n == 0 { 0 } else { 55 + n as u32 * 40 };
+                let i = i - 16;
+                level(i / 36) << 24 | level(i / 6 % 6) << 16 | level(i % 6) << 8 | 0xff
+            }
+            Color::Indexed(i) => {
+                let gray = 8 + (i - 232) as u32 * 10;
+                gray << 24 | gray << 16 | gray << 8 | 0xff
+            }
+            Color::Rgb(rgba) => rgba,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Attributes {
+    fg: Color,
+    bg: Color,
+    /// Shown as the bright versions of the basic colors.
+    bold: bool,
+    reverse: bool,
+}
+
+impl Attributes {
+    const DEFAULT: Self = Attributes {
+        fg: Color::Default,
+        bg: Color::Default,
+        bold: false,
+        reverse: false,
+    };
+
+    /// Foreground and background, in RGBA.
+    fn colors(&self) -> (u32, u32) {
+        let fg = self.fg.to_rgba(DEFAULT_FG, self.bold);
+        let bg = self.bg.to_rgba(DEFAULT_BG, false);
+        if self.reverse {
+            (bg, fg)
+        } else {
+            (fg, bg)
+        }
+    }
+}
+
+/// A character cell of the text buffer, with its colors resolved.
+#[derive(Clone, Copy)]
+struct Cell {
+    glyph: usize,
+    fg: u32,
+    bg: u32,
+}
 
 pub struct Console<F: Framebuffer> {
-    fb: F,
+    /// Where the text is drawn, unless the console is in the background.
+    fb: Option<F>,
+    /// The text on screen, row by row.
+    cells: Vec<Cell>,
+    /// Reused to draw every glyph.
+    glyph: GfxRectangle,
     rows: usize,
     columns: usize,
     cursor: Cursor,
+    parser: Parser,
+    /// Whether the cursor should be shown, as set by `ESC [ ? 25 h/l`.
+    cursor_visible: bool,
+    /// Whether the cursor is drawn on the framebuffer right now.
+    cursor_drawn: bool,
 }
 
 struct Cursor {
     row: usize,
     col: usize,
-    fg_color: u32,
-    bg_color: u32,
+    /// Set after writing to the last column. The next character goes on a new line, but the line
+    /// only breaks once it arrives, so that the bottom right cell can be written without
+    /// scrolling.
+    wrap_pending: bool,
+    attrs: Attributes,
 }
 
 impl<F: Framebuffer> Console<F> {
     pub fn new(fb: F) -> Self {
         let (width, height) = (fb.info().width as usize, fb.info().height as usize);
+        let mut console = Self::detached(height / font::FONT.height(), width / font::FONT.width());
+        console.fb = Some(fb);
+        console
+    }
+
+    /// Creates a console of `rows` by `columns` cells with no framebuffer to draw on yet.
+    pub fn detached(rows: usize, columns: usize) -> Self {
+        let blank = Cell {
+            glyph: font::FONT.char_to_glyph(' '),
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+        };
         Console {
-            rows: height / font::FONT.height(),
-            columns: width / font::FONT.width(),
+            fb: None,
+            cells: alloc::vec![blank; rows * columns],
+            glyph: GfxRectangle::blank(font::FONT.width() as u32, font::FONT.height() as u32),
+            rows,
+            columns,
             cursor: Cursor {
                 row: 0,
                 col: 0,
-                fg_color: 0xffffffff,
-                bg_color: 0x000000ff,
+                wrap_pending: false,
+                attrs: Attributes::DEFAULT,
             },
-            fb,
+            parser: Parser::new(),
+            cursor_visible: true,
+            cursor_drawn: false,
         }
     }
 
-    pub fn get_framebuffer(&self) -> &F {
-        &self.fb
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn get_framebuffer(&self) -> Option<&F> {
+        self.fb.as_ref()
+    }
+
+    pub fn get_framebuffer_mut(&mut self) -> Option<&mut F> {
+        self.fb.as_mut()
     }
 
-    pub fn get_framebuffer_mut(&mut self) -> &mut F {
-        &mut self.fb
+    /// Starts drawing on `fb`, redrawing all of the text.
+    pub fn attach(&mut self, fb: F) {
+        self.fb = Some(fb);
+        self.cursor_drawn = false;
+        for row in 0..self.rows {
+            for col in 0..self.columns {
+                self.draw_cell(row, col);
+            }
+        }
+        if self.cursor_visible {
+            self.toggle_cursor();
+        }
+    }
+
+    /// Stops drawing, and hands back the framebuffer. The text is kept for [`Console::attach`].
+    pub fn detach(&mut self) -> Option<F> {
+        if self.cursor_drawn {
+            self.toggle_cursor();
+        }
+        self.fb.take()
+    }
+
+    /// Makes what was drawn visible, for framebuffers that need [`Framebuffer::flush`]ing.
+    pub fn flush(&mut self) {
+        if let Some(fb) = self.fb.as_mut() {
+            fb.flush();
+        }
+    }
+
+    /// Runs `f` with the cursor taken off the screen, so that it doesn't get in the way of drawing.
+    fn with_cursor_hidden<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        if self.cursor_drawn {
+            self.toggle_cursor();
+        }
+        let result = f(self);
+        if self.cursor_visible {
+            self.toggle_cursor();
+        }
+        result
+    }
+
+    /// Draws or erases the cursor, by inverting the bottom of its cell. This works whatever the
+    /// pixel format is.
+    fn toggle_cursor(&mut self) {
+        let Some(fb) = self.fb.as_mut() else {
+            return;
+        };
+        if self.rows == 0 || self.columns == 0 {
+            return;
+        }
+        let info = fb.info();
+        let x_start = self.cursor.col * font::FONT.width() * info.bytes_per_pixel;
+        let x_end = x_start + font::FONT.width() * info.bytes_per_pixel;
+        let y_end = (self.cursor.row + 1) * font::FONT.height();
+        let buf = fb.get_mut();
+        for y in y_end - CURSOR_HEIGHT..y_end {
+            for byte in &mut buf[y * info.stride + x_start..y * info.stride + x_end] {
+                *byte = !*byte;
+            }
+        }
+        fb.mark_dirty(Rect::new(
+            (self.cursor.col * font::FONT.width()) as u32,
+            (y_end - CURSOR_HEIGHT) as u32,
+            font::FONT.width() as u32,
+            CURSOR_HEIGHT as u32,
+        ));
+        self.cursor_drawn = !self.cursor_drawn;
     }
 
     pub fn write_glyph(&mut self, gid: usize) {
-        let (bytes_per_row, bitmap) = match font::FONT.get_glyph_bitmap(gid) {
-            Ok(g) => g,
-            _ => return,
+        self.with_cursor_hidden(|console| console.put_glyph(gid));
+    }
+
+    fn put_glyph(&mut self, gid: usize) {
+        if font::FONT.get_glyph_bitmap(gid).is_err() {
+            return;
+        }
+        if self.cursor.wrap_pending {
+            self.line_feed();
+        }
+        let (fg, bg) = self.cursor.attrs.colors();
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        self.cells[row * self.columns + col] = Cell { glyph: gid, fg, bg };
+        self.draw_cell(row, col);
+        if self.cursor.col + 1 >= self.columns {
+            self.cursor.wrap_pending = true;
+        } else {
+            self.cursor.col += 1;
+        }
+    }
+
+    fn draw_cell(&mut self, row: usize, col: usize) {
+        let Some(fb) = self.fb.as_mut() else {
+            return;
         };
-        let gx = self.cursor.col * font::FONT.width();
-        let gy = self.cursor.row * font::FONT.height();
+        let Cell { glyph, fg, bg } = self.cells[row * self.columns + col];
+        let Ok((bytes_per_row, bitmap)) = font::FONT.get_glyph_bitmap(glyph) else {
+            return;
+        };
+        let (fg, bg) = (Pixel::from_u32_rgba(fg), Pixel::from_u32_rgba(bg));
+        for y in 0..font::FONT.height() {
+            for x in 0..font::FONT.width() {
+                let set = bitmap[y * bytes_per_row + x / 8] & (1 << (7 - (x % 8))) != 0;
+                self.glyph[(x as u32, y as u32)] = if set { fg } else { bg };
+            }
+        }
+        let gx = col * font::FONT.width();
+        let gy = row * font::FONT.height();
+        fb.blit(&self.glyph, (gx as i32, gy as i32));
+    }
+
+    /// A blank cell in the current background color.
+    fn blank(&self) -> Cell {
+        let (fg, bg) = self.cursor.attrs.colors();
+        Cell {
+            glyph: font::FONT.char_to_glyph(' '),
+            fg,
+            bg,
+        }
+    }
+
+    pub fn newline(&mut self) {
+        self.with_cursor_hidden(Self::line_feed);
+    }
+
+    /// Moves to the start of the next line, scrolling if there is none.
+    fn line_feed(&mut self) {
+        self.cursor.col = 0;
+        self.cursor.wrap_pending = false;
+        if self.cursor.row + 1 >= self.rows {
+            self.move_text_up();
+        } else {
+            self.cursor.row += 1;
+        }
+    }
+
+    /// Moves the cursor back one cell and blanks it, staying on the current row.
+    pub fn backspace(&mut self) {
+        self.with_cursor_hidden(|console| {
+            if console.cursor.wrap_pending {
+                console.cursor.wrap_pending = false;
+            } else if console.cursor.col == 0 {
+                return;
+            } else {
+                console.cursor.col -= 1;
+            }
+            console.erase(
+                console.cursor.row,
+                console.cursor.col..console.cursor.col + 1,
+            );
+        });
+    }
+
+    /// Moves all text up a line, and blanks the last line.
+    pub fn scroll_text_up(&mut self) {
+        self.with_cursor_hidden(Self::move_text_up);
+    }
+
+    /// Like [`scroll_text_up`](Self::scroll_text_up), but leaves the cursor to the caller.
+    fn move_text_up(&mut self) {
+        if self.rows == 0 {
+            return;
+        }
+        self.cells
+            .copy_within(self.columns..self.rows * self.columns, 0);
+        if let Some(fb) = self.fb.as_mut() {
+            let info = fb.info();
+            let line_bytes = font::FONT.height() * info.stride;
+            let text_bytes = self.rows * line_bytes;
+            fb.get_mut().copy_within(line_bytes..text_bytes, 0);
+            let text_height = self.rows * font::FONT.height();
+            fb.mark_dirty(Rect::new(0, 0, info.width, text_height as u32));
+        }
+        self.erase_rows(self.rows - 1..self.rows);
+    }
+
+    /// Fills cells `cols` of `row` with the background color.
+    fn erase(&mut self, row: usize, cols: core::ops::Range<usize>) {
+        let cols = cols.start.min(self.columns)..cols.end.min(self.columns);
+        if cols.is_empty() {
+            return;
+        }
+        let blank = self.blank();
+        self.cells[row * self.columns..][cols.clone()].fill(blank);
+        let Some(fb) = self.fb.as_mut() else {
+            return;
+        };
+        let bg = Pixel::from_u32_rgba(blank.bg);
         let rect = GfxRectangle::with(
-            font::FONT.width() as u32,
+            (cols.len() * font::FONT.width()) as u32,
             font::FONT.height() as u32,
-            |x, y| {
-                let x = x as usize;
-                let y = y as usize;
-                if bitmap[y * bytes_per_row + x / 8] & (1 << (7 - (x % 8))) != 0 {
-                    Pixel::from_u32_rgba(self.cursor.fg_color)
-                } else {
-                    Pixel::from_u32_rgba(self.cursor.bg_color)
-                }
-            },
+            |_, _| bg,
         );
-        self.fb.blit(&rect, (gx as i32, gy as i32));
-        self.cursor.col += 1;
-        if self.cursor.col >= self.columns {
-            self.newline()
+        let x = cols.start * font::FONT.width();
+        let y = row * font::FONT.height();
+        fb.blit(&rect, (x as i32, y as i32));
+    }
+
+    fn erase_rows(&mut self, rows: core::ops::Range<usize>) {
+        let rows = rows.start.min(self.rows)..rows.end.min(self.rows);
+        if rows.is_empty() {
+            return;
         }
+        let blank = self.blank();
+        self.cells[rows.start * self.columns..rows.end * self.columns].fill(blank);
+        let Some(fb) = self.fb.as_mut() else {
+            return;
+        };
+        let bg = Pixel::from_u32_rgba(blank.bg);
+        let rect = GfxRectangle::with(
+            (self.columns * font::FONT.width()) as u32,
+            (rows.len() * font::FONT.height()) as u32,
+            |_, _| bg,
+        );
+        fb.blit(&rect, (0, (rows.start * font::FONT.height()) as i32));
     }
 
-    pub fn newline(&mut self) {
-        self.cursor.col = 0;
-        self.cursor.row += 1;
-        if self.cursor.row >= self.rows {
-            self.scroll_down()
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.cursor.row = row.min(self.rows.saturating_sub(1));
+        self.cursor.col = col.min(self.columns.saturating_sub(1));
+        self.cursor.wrap_pending = false;
+    }
+
+    fn put_char(&mut self, c: char) {
+        match self.parser.advance(c) {
+            Some(Action::Print(c)) => self.put_glyph(font::FONT.char_to_glyph(c)),
+            Some(Action::Control(c)) => self.control(c),
+            Some(Action::Csi(csi)) => self.control_sequence(&csi),
+            None => {}
         }
     }
 
-    pub fn scroll_down(&mut self) {
-        // self.cursor.row -= 1;
-        // let mut screen = self.screen.lock();
-        // let width = screen.width();
-        // let height = screen.height();
-        // screen.draw_rect_with(0, 0, width, height, |x, y, screen| {
-        //     screen
-        //         .get_pixel(x, y + font::FONT.height())
-        //         .unwrap_or(self.cursor.bg_color)
-        // });
+    fn control(&mut self, c: char) {
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        match c {
+            '\n' => self.line_feed(),
+            '\r' => self.move_to(row, 0),
+            '\t' => self.move_to(row, (col / TAB_WIDTH + 1) * TAB_WIDTH),
+            '\x08' => self.move_to(row, col.saturating_sub(1)),
+            _ => {}
+        }
     }
-}
 
-impl<F: Framebuffer> core::fmt::Write for Console<F> {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let mut lines = s.split('\n');
-        // Print the first line without a newline()
-        if let Some(line) = lines.next() {
-            for glyph in font::FONT.str_to_glyphs(line) {
-                self.write_glyph(glyph);
+    fn control_sequence(&mut self, csi: &Csi) {
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        let n = csi.param(0, 1) as usize;
+        match (csi.private, csi.action) {
+            (false, 'A') => self.move_to(row.saturating_sub(n), col),
+            (false, 'B') => self.move_to(row + n, col),
+            (false, 'C') => self.move_to(row, col + n),
+            (false, 'D') => self.move_to(row, col.saturating_sub(n)),
+            (false, 'E') => self.move_to(row + n, 0),
+            (false, 'F') => self.move_to(row.saturating_sub(n), 0),
+            (false, 'G') => self.move_to(row, n - 1),
+            (false, 'd') => self.move_to(n - 1, col),
+            (false, 'H' | 'f') => self.move_to(n - 1, csi.param(1, 1) as usize - 1),
+            (false, 'J') => match csi.param(0, 0) {
+                0 => {
+                    self.erase(row, col..self.columns);
+                    self.erase_rows(row + 1..self.rows);
+                }
+                1 => {
+                    self.erase_rows(0..row);
+                    self.erase(row, 0..col + 1);
+                }
+                _ => self.erase_rows(0..self.rows),
+            },
+            (false, 'K') => match csi.param(0, 0) {
+                0 => self.erase(row, col..self.columns),
+                1 => self.erase(row, 0..col + 1),
+                _ => self.erase(row, 0..self.columns),
+            },
+            (false, 'm') => self.select_graphic_rendition(csi),
+            (true, 'h' | 'l') if csi.params() == [25] => {
+                self.cursor_visible = csi.action == 'h';
             }
+            _ => log::trace!("Unsupported control sequence {csi:?}"),
         }
-        // Every line afterwards is preceded by a newline()
-        for line in lines {
-            self.newline();
-            for glyph in font::FONT.str_to_glyphs(line) {
-                self.write_glyph(glyph);
+    }
+
+    /// Handles `ESC [ ... m`, which sets colors and other attributes.
+    fn select_graphic_rendition(&mut self, csi: &Csi) {
+        let attrs = &mut self.cursor.attrs;
+        let mut params = csi.params().iter().copied();
+        if csi.params().is_empty() {
+            *attrs = Attributes::DEFAULT;
+        }
+        while let Some(p) = params.next() {
+            match p {
+                0 => *attrs = Attributes::DEFAULT,
+                1 => attrs.bold = true,
+                22 => attrs.bold = false,
+                7 => attrs.reverse = true,
+                27 => attrs.reverse = false,
+                30..=37 => attrs.fg = Color::Indexed((p - 30) as u8),
+                39 => attrs.fg = Color::Default,
+                40..=47 => attrs.bg = Color::Indexed((p - 40) as u8),
+                49 => attrs.bg = Color::Default,
+                90..=97 => attrs.fg = Color::Indexed((p - 90 + 8) as u8),
+                100..=107 => attrs.bg = Color::Indexed((p - 100 + 8) as u8),
+                38 | 48 => {
+                    let color = match params.next() {
+                        Some(5) => params.next().map(|i| Color::Indexed(i as u8)),
+                        Some(2) => {
+                            let mut channel = || params.next().map(|c| c.min(255) as u32);
+                            match (channel(), channel(), channel()) {
+                                (Some(r), Some(g), Some(b)) => {
+                                    Some(Color::Rgb(r << 24 | g << 16 | b << 8 | 0xff))
+                                }
+                                _ => None,
+                            }
+                        }
+                        _ => None,
+                    };
+                    if let Some(color) = color {
+                        if p == 38 {
+                            attrs.fg = color;
+                        } else {
+                            attrs.bg = color;
+                        }
+                    }
+                }
+                _ => {}
             }
         }
+    }
+}
+
+impl<F: Framebuffer> core::fmt::Write for Console<F> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.with_cursor_hidden(|console| {
+            for c in s.chars() {
+                console.put_char(c);
+            }
+        });
         Ok(())
     }
 }
 
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        if let Some(console) = CONSOLE.lock().as_mut() {
-            use core::fmt::Write;
-            console.write_fmt(args).unwrap();
-        }
+    use core::fmt::Write;
+    super::vt::with_console(super::vt::LOG_VT, |console| {
+        console.write_fmt(args).unwrap()
     });
 }
 
@@ -132,3 +520,64 @@ macro_rules! println {
     () => ($crate::print!("\n"));
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::framebuffer::{FramebufferInfo, PixelFormat};
+    use core::fmt::Write;
+    use font::FONT;
+
+    /// Two rows of four cells, in RGBA.
+    struct MemoryFramebuffer(Vec<u8>);
+
+    fn stride() -> usize {
+        4 * FONT.width() * 4
+    }
+
+    impl Framebuffer for MemoryFramebuffer {
+        fn info(&self) -> FramebufferInfo {
+            FramebufferInfo {
+                format: PixelFormat::RGBA,
+                bytes_per_pixel: 4,
+                width: 4 * FONT.width() as u32,
+                height: 2 * FONT.height() as u32,
+                stride: stride(),
+                buffer_len: self.0.len(),
+            }
+        }
+
+        fn get_mut(&mut self) -> &mut [u8] {
+            &mut self.0
+        }
+    }
+
+    #[test_case]
+    fn redraws_when_attached() {
+        let stride = stride();
+        let mut console = Console::detached(2, 4);
+        write!(console, "ab").unwrap();
+        console.attach(MemoryFramebuffer(
+            alloc::vec![0; stride * 2 * FONT.height()],
+        ));
+
+        // The text written while detached is drawn, white on black
+        let buf = console.get_framebuffer_mut().unwrap().get_mut();
+        let (bytes_per_row, bitmap) = FONT.get_glyph_bitmap(FONT.char_to_glyph('b')).unwrap();
+        for y in 0..FONT.height() {
+            for x in 0..FONT.width() {
+                let set = bitmap[y * bytes_per_row + x / 8] & (1 << (7 - x % 8)) != 0;
+                let pixel = y * stride + (FONT.width() + x) * 4;
+                assert_eq!(buf[pixel], if set { 0xFF } else { 0 });
+            }
+        }
+
+        // So is the cursor after it, which goes away with the framebuffer
+        let cursor = (FONT.height() - 1) * stride + 2 * FONT.width() * 4;
+        assert_eq!(buf[cursor], 0xFF);
+        let fb = console.detach().unwrap();
+        assert_eq!(fb.0[cursor], 0);
+        assert!(console.get_framebuffer().is_none());
+        assert!(!console.cursor_drawn);
+    }
+}
@@ -247,17 +247,18 @@ impl Font {
         map
     }
 
-    pub fn str_to_glyphs<'a>(&self, s: &'a str) -> impl Iterator<Item = usize> + 'a {
-        let has_unicode_table = self.has_unicode_table();
-        s.chars().map(move |c| {
-            if has_unicode_table {
-                GLYPH_MAP.get(&c).copied().unwrap_or(0x91)
-            } else if c.is_ascii() {
-                c as usize
-            } else {
-                0x91
-            }
-        })
+    pub fn str_to_glyphs<'a>(&'a self, s: &'a str) -> impl Iterator<Item = usize> + 'a {
+        s.chars().map(move |c| self.char_to_glyph(c))
+    }
+
+    pub fn char_to_glyph(&self, c: char) -> usize {
+        if self.has_unicode_table() {
+            GLYPH_MAP.get(&c).copied().unwrap_or(0x91)
+        } else if c.is_ascii() {
+            c as usize
+        } else {
+            0x91
+        }
     }
 
     /// On a successful query, this returns a tuple with the (bytes per row, bitmap) of the glyph.
@@ -13,10 +13,18 @@ pub trait Framebuffer {
     /// Returns a mutable slice directly into framebuffer memory.
     fn get_mut(&mut self) -> &mut [u8];
 
+    /// Records that `area` was changed through [`Framebuffer::get_mut`], for framebuffers that
+    /// only show changes once flushed. [`Framebuffer::blit`] does this by itself.
+    fn mark_dirty(&mut self, _area: Rect) {}
+
+    /// Makes everything drawn so far visible. Framebuffers drawing straight to video memory have
+    /// nothing to do.
+    fn flush(&mut self) {}
+
     /// Draws a rectangle directly to the framebuffer with top left corner at coords (x,y).
     /// This is the preferred way to draw on framebuffers, since `GfxRectangle`s have a consistent
     /// format, and the implementor can take advantage of faster algorithms, if available. A default
-    /// implementation is provided, as long as the pixel channels are byte-wide and byte-aligned.
+    /// implementation is provided for any [`PixelFormat`] with pixels of 1 to 4 bytes.
     ///
     /// # Requirements
     /// The following rules must be followed by anything implementing this trait:
@@ -30,50 +38,78 @@ pub trait Framebuffer {
     /// Implementations may implement blending if they want, but it is not necessary.
     fn blit(&mut self, rect: &GfxRectangle, coords: (i32, i32)) {
         let info = self.info();
-        let buf = self.get_mut();
-
-        let xstart = coords.0.max(0); // xstart >= c.0
-        let ystart = coords.1.max(0);
-        let xoff = (xstart - coords.0) as u32; // xstart - c.0 >= 0
-        let yoff = (ystart - coords.1) as u32;
-        let xend = (coords.0 + rect.width as i32).min(info.width as i32);
-        let yend = (coords.1 + rect.height as i32).min(info.height as i32);
-
-        // Stop early if the drawn rectangle is completely off-screen
-        if xend <= xstart || yend <= ystart {
-            return;
-        }
+        blit_into(&info, self.get_mut(), rect, coords);
+    }
+}
 
-        // Compute pixel format information
-        let fmt = info.format;
-        if fmt.red_width_bits != 8 || fmt.blue_width_bits != 8 || fmt.green_width_bits != 8 {
-            log::warn!("Framebuffer pixel format has non byte-sized channels");
-            return;
-        }
-        if fmt.red_shift_bits % 8 != 0
-            || fmt.blue_shift_bits % 8 != 0
-            || fmt.green_shift_bits % 8 != 0
-        {
-            log::warn!("Framebuffer pixel format has non byte-aligned channels");
-            return;
-        }
-        let r_off = (fmt.red_shift_bits / 8) as usize;
-        let g_off = (fmt.green_shift_bits / 8) as usize;
-        let b_off = (fmt.blue_shift_bits / 8) as usize;
-
-        for fby in ystart..yend {
-            let ry = (fby - ystart) as u32 + yoff;
-            for fbx in xstart..xend {
-                let rx = (fbx - xstart) as u32 + xoff;
-                let pix = rect[(rx, ry)];
-                if pix.a == 0 {
-                    continue;
-                }
-                let fb_off = fby as usize * info.stride + fbx as usize * info.bytes_per_pixel;
-                buf[fb_off + r_off] = pix.r;
-                buf[fb_off + g_off] = pix.g;
-                buf[fb_off + b_off] = pix.b;
+/// Draws `rect` into `buf`, laid out as described by `info`, following the rules of
+/// [`Framebuffer::blit`]. Returns the area drawn over, if any.
+pub(super) fn blit_into(
+    info: &FramebufferInfo,
+    buf: &mut [u8],
+    rect: &GfxRectangle,
+    coords: (i32, i32),
+) -> Option<Rect> {
+    let xstart = coords.0.max(0); // xstart >= c.0
+    let ystart = coords.1.max(0);
+    let xoff = (xstart - coords.0) as u32; // xstart - c.0 >= 0
+    let yoff = (ystart - coords.1) as u32;
+    let xend = (coords.0 + rect.width as i32).min(info.width as i32);
+    let yend = (coords.1 + rect.height as i32).min(info.height as i32);
+
+    // Stop early if the drawn rectangle is completely off-screen
+    if xend <= xstart || yend <= ystart {
+        return None;
+    }
+
+    let fmt = info.format;
+    if !(1..=4).contains(&info.bytes_per_pixel) || !fmt.fits(info.bytes_per_pixel) {
+        log::warn!(
+            "Unsupported framebuffer pixel format {fmt:?} with {} bytes per pixel",
+            info.bytes_per_pixel
+        );
+        return None;
+    }
+    let area = Rect::new(
+        xstart as u32,
+        ystart as u32,
+        (xend - xstart) as u32,
+        (yend - ystart) as u32,
+    );
+    match info.bytes_per_pixel {
+        4 => blit_packed::<4>(info, buf, rect, area, (xoff, yoff)),
+        3 => blit_packed::<3>(info, buf, rect, area, (xoff, yoff)),
+        2 => blit_packed::<2>(info, buf, rect, area, (xoff, yoff)),
+        _ => blit_packed::<1>(info, buf, rect, area, (xoff, yoff)),
+    }
+    Some(area)
+}
+
+/// Draws the part of `rect` from `offset` on over `area` of `buf`, whose pixels are `BYTES` long.
+/// Knowing the size at compile time makes writing a pixel a single store.
+fn blit_packed<const BYTES: usize>(
+    info: &FramebufferInfo,
+    buf: &mut [u8],
+    rect: &GfxRectangle,
+    area: Rect,
+    offset: (u32, u32),
+) {
+    // Most rectangles have few colors, like the two of a glyph, so only pack when it changes
+    let mut last = None;
+    let mut packed = [0; BYTES];
+    for y in 0..area.height {
+        let start = (area.y + y) as usize * info.stride + area.x as usize * BYTES;
+        let row = &mut buf[start..start + area.width as usize * BYTES];
+        for (x, dest) in row.chunks_exact_mut(BYTES).enumerate() {
+            let pix = rect[(offset.0 + x as u32, offset.1 + y)];
+            if pix.a == 0 {
+                continue;
+            }
+            if last != Some(pix) {
+                packed.copy_from_slice(&info.format.pack(pix).to_le_bytes()[..BYTES]);
+                last = Some(pix);
             }
+            dest.copy_from_slice(&packed);
         }
     }
 }
@@ -106,6 +142,12 @@ impl<F: Framebuffer + ?Sized> Framebuffer for Box<F> {
     fn get_mut(&mut self) -> &mut [u8] {
         self.as_mut().get_mut()
     }
+    fn mark_dirty(&mut self, area: Rect) {
+        self.as_mut().mark_dirty(area)
+    }
+    fn flush(&mut self) {
+        self.as_mut().flush()
+    }
     fn blit(&mut self, rect: &GfxRectangle, coords: (i32, i32)) {
         self.as_mut().blit(rect, coords)
     }
@@ -118,6 +160,12 @@ impl<F: Framebuffer + ?Sized> Framebuffer for &mut F {
     fn get_mut(&mut self) -> &mut [u8] {
         (**self).get_mut()
     }
+    fn mark_dirty(&mut self, area: Rect) {
+        (**self).mark_dirty(area)
+    }
+    fn flush(&mut self) {
+        (**self).flush()
+    }
     fn blit(&mut self, rect: &GfxRectangle, coords: (i32, i32)) {
         (**self).blit(rect, coords)
     }
@@ -160,6 +208,105 @@ impl PixelFormat {
         green_width_bits: 8,
         blue_width_bits: 8,
     };
+
+    /// 16-bit color with 5 bits of red and blue and 6 of green.
+    pub const RGB565: Self = PixelFormat {
+        red_shift_bits: 11,
+        green_shift_bits: 5,
+        blue_shift_bits: 0,
+        red_width_bits: 5,
+        green_width_bits: 6,
+        blue_width_bits: 5,
+    };
+
+    /// Packs `pixel` into the low bits of a `u32`, to be stored little-endian. The channels are
+    /// scaled to their width and alpha is dropped.
+    pub fn pack(&self, pixel: Pixel) -> u32 {
+        scale_channel(pixel.r, self.red_width_bits) << self.red_shift_bits
+            | scale_channel(pixel.g, self.green_width_bits) << self.green_shift_bits
+            | scale_channel(pixel.b, self.blue_width_bits) << self.blue_shift_bits
+    }
+
+    /// Whether [`PixelFormat::pack`] supports this format, for pixels of `bytes` bytes.
+    fn fits(&self, bytes: usize) -> bool {
+        [
+            (self.red_shift_bits, self.red_width_bits),
+            (self.green_shift_bits, self.green_width_bits),
+            (self.blue_shift_bits, self.blue_width_bits),
+        ]
+        .iter()
+        .all(|&(shift, width)| width <= 16 && shift as usize + width as usize <= bytes * 8)
+    }
+}
+
+/// Scales an 8-bit channel to `width` bits, at most 16.
+fn scale_channel(value: u8, width: u8) -> u32 {
+    let value = value as u32;
+    if width <= 8 {
+        value >> (8 - width)
+    } else {
+        // Fill the new low bits with the high ones, so that full intensity stays full
+        value << (width - 8) | value >> (16 - width)
+    }
+}
+
+/// An area of a framebuffer, in pixels.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub const fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn right(&self) -> u32 {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> u32 {
+        self.y + self.height
+    }
+
+    /// The smallest rectangle containing both `self` and `other`.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Rect::new(x, y, right - x, bottom - y)
+    }
+
+    /// Whether `self` and `other` overlap or share an edge.
+    pub fn touches(&self, other: &Rect) -> bool {
+        self.x <= other.right()
+            && other.x <= self.right()
+            && self.y <= other.bottom()
+            && other.y <= self.bottom()
+    }
+
+    /// The part of `self` within a `width` by `height` area at the origin.
+    pub fn clipped(&self, width: u32, height: u32) -> Rect {
+        let (x, y) = (self.x.min(width), self.y.min(height));
+        Rect::new(
+            x,
+            y,
+            self.right().min(width) - x,
+            self.bottom().min(height) - y,
+        )
+    }
 }
 
 /// A standardized graphics rectangle. Backed by a `Vec<Pixel>`.
@@ -278,3 +425,45 @@ impl Display for Pixel {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn pixel_formats() {
+        let orange = Pixel::new_rgb(0xFF, 0x80, 0x08);
+        assert_eq!(PixelFormat::RGBA.pack(orange), 0x0880FF);
+        assert_eq!(
+            PixelFormat::RGB565.pack(orange),
+            0b11111 << 11 | 0b100000 << 5 | 0b00001
+        );
+        let deep = PixelFormat {
+            red_width_bits: 10,
+            ..PixelFormat::RGBA
+        };
+        assert_eq!(deep.pack(Pixel::new_rgb(0xFF, 0, 0)), 0x3FF);
+
+        // A 3 by 2 RGB565 framebuffer, with a padded stride
+        let info = FramebufferInfo {
+            format: PixelFormat::RGB565,
+            bytes_per_pixel: 2,
+            width: 3,
+            height: 2,
+            stride: 8,
+            buffer_len: 16,
+        };
+        let mut buf = [0; 16];
+        let rect = GfxRectangle::with(2, 2, |x, _| {
+            if x == 0 {
+                Pixel::new_rgba(0, 0, 0, 0)
+            } else {
+                orange
+            }
+        });
+        let drawn = blit_into(&info, &mut buf, &rect, (1, 1));
+        assert_eq!(drawn, Some(Rect::new(1, 1, 2, 1)));
+        assert_eq!(buf[8..], [0, 0, 0, 0, 0x01, 0xFC, 0, 0]);
+        assert!(buf[..8].iter().all(|&b| b == 0));
+    }
+}
@@ -1,5 +1,8 @@
 pub use self::framebuffer::Framebuffer;
 
+pub mod ansi;
+pub mod back_buffer;
 pub mod console;
 pub mod font;
 pub mod framebuffer;
+pub mod vt;
@@ -0,0 +1,95 @@
+//! Virtual terminals: several [`Console`]s sharing the framebuffer, of which only the active one
+//! is shown. The others keep their text, and are redrawn when switched to with Alt+F1 to Alt+F6.
+//!
+//! Each has a [terminal](crate::tty) of its own. Kernel messages printed with
+//! [`print!`](crate::print) go to the last one, so they stay out of the way of programs.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::console::Console;
+use super::Framebuffer;
+
+pub const COUNT: usize = 6;
+/// Where kernel messages go.
+pub const LOG_VT: usize = COUNT - 1;
+
+type VtConsole = Console<Box<dyn Framebuffer + Send>>;
+
+struct Vts {
+    consoles: Vec<VtConsole>,
+    /// The one holding the framebuffer.
+    active: usize,
+}
+
+/// Only ever locked with interrupts disabled, as anything may print.
+static VTS: Mutex<Option<Vts>> = Mutex::new(None);
+
+/// Sets up the virtual terminals, with `first`, which must be drawing on the framebuffer, as the
+/// first and active one. The others get the same size.
+pub fn init(first: VtConsole) {
+    assert!(
+        first.get_framebuffer().is_some(),
+        "The first virtual terminal needs the framebuffer"
+    );
+    let (rows, columns) = (first.rows(), first.columns());
+    let mut consoles = Vec::with_capacity(COUNT);
+    consoles.push(first);
+    consoles.resize_with(COUNT, || Console::detached(rows, columns));
+    without_interrupts(|| {
+        *VTS.lock() = Some(Vts {
+            consoles,
+            active: 0,
+        })
+    });
+}
+
+/// Runs `f` on the console of virtual terminal `vt`, if they are set up, then shows what it drew.
+pub fn with_console<R>(vt: usize, f: impl FnOnce(&mut VtConsole) -> R) -> Option<R> {
+    without_interrupts(|| {
+        VTS.lock().as_mut().map(|vts| {
+            let console = &mut vts.consoles[vt];
+            let result = f(console);
+            console.flush();
+            result
+        })
+    })
+}
+
+/// The virtual terminal on screen, which keyboard input goes to.
+pub fn active() -> usize {
+    without_interrupts(|| VTS.lock().as_ref().map_or(0, |vts| vts.active))
+}
+
+/// Shows virtual terminal `vt`. Returns whether it wasn't shown already.
+///
+/// Never panics, for the panic handler to show its message: if the active console lost the
+/// framebuffer, which a panic in the middle of a switch can do, nothing is switched.
+pub fn switch_to(vt: usize) -> bool {
+    without_interrupts(|| {
+        let mut vts = VTS.lock();
+        let Some(vts) = vts.as_mut() else {
+            return false;
+        };
+        if vt == vts.active {
+            return false;
+        }
+        let Some(fb) = vts.consoles[vts.active].detach() else {
+            return false;
+        };
+        vts.consoles[vt].attach(fb);
+        vts.consoles[vt].flush();
+        vts.active = vt;
+        true
+    })
+}
+
+/// Releases the lock on the virtual terminals, for the panic handler to print.
+///
+/// # Safety
+/// Nothing else may be using them.
+pub unsafe fn force_unlock() {
+    VTS.force_unlock();
+}
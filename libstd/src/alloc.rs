@@ -0,0 +1,143 @@
+//! The global allocator. Small blocks come from size-class free lists carved out of the `brk`
+//! heap, and anything larger than a size class gets its own pages from `mmap`.
+
+use core::{
+    cell::UnsafeCell,
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use kernel_uapi::syscall::{self, PROT_READ, PROT_WRITE};
+
+use crate::io;
+
+pub use alloc_crate::alloc::*;
+
+const PAGE_SIZE: usize = 4096;
+/// Block sizes of the size classes, 16 to 2048 bytes.
+const CLASSES: usize = 8;
+const MIN_BLOCK: usize = 16;
+const MAX_BLOCK: usize = MIN_BLOCK << (CLASSES - 1);
+/// How much the heap grows by at a time.
+const HEAP_GROWTH: usize = 16 * PAGE_SIZE;
+
+/// A free block, linked through its first bytes.
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+struct Heap {
+    free: [Option<NonNull<FreeBlock>>; CLASSES],
+    /// Unused part of the heap, from which new blocks are carved.
+    next: usize,
+    end: usize,
+}
+
+struct SystemAllocator {
+    locked: AtomicBool,
+    heap: UnsafeCell<Heap>,
+}
+
+unsafe impl Sync for SystemAllocator {}
+
+#[global_allocator]
+static ALLOCATOR: SystemAllocator = SystemAllocator {
+    locked: AtomicBool::new(false),
+    heap: UnsafeCell::new(Heap {
+        free: [None; CLASSES],
+        next: 0,
+        end: 0,
+    }),
+};
+
+/// The size class for blocks of `layout`, if it is small enough for one. Blocks are aligned to
+/// their size.
+fn class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_BLOCK);
+    if size > MAX_BLOCK {
+        return None;
+    }
+    Some(size.next_power_of_two().trailing_zeros() as usize - MIN_BLOCK.trailing_zeros() as usize)
+}
+
+impl Heap {
+    fn alloc(&mut self, class: usize) -> *mut u8 {
+        if let Some(block) = self.free[class] {
+            self.free[class] = unsafe { block.as_ref().next };
+            return block.as_ptr().cast();
+        }
+
+        let size = MIN_BLOCK << class;
+        let start = self.next.next_multiple_of(size);
+        if start + size > self.end && !self.grow(size) {
+            return null_mut();
+        }
+        // Growing may have moved the heap if it was never set up
+        let start = self.next.next_multiple_of(size);
+        self.next = start + size;
+        start as *mut u8
+    }
+
+    /// Extends the heap by at least `min` bytes, plus alignment.
+    fn grow(&mut self, min: usize) -> bool {
+        if self.end == 0 {
+            let Ok(start) = io::syscall(|out| syscall::brk(null_mut(), out)) else {
+                return false;
+            };
+            self.next = start as usize;
+            self.end = start as usize;
+        }
+        let new_end = self.end + (2 * min).max(HEAP_GROWTH);
+        match io::syscall(|out| syscall::brk(new_end as *mut u8, out)) {
+            Ok(end) if end as usize >= new_end => {
+                self.end = new_end;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn dealloc(&mut self, ptr: *mut u8, class: usize) {
+        let mut block = NonNull::new(ptr.cast::<FreeBlock>()).unwrap();
+        unsafe { block.as_mut().next = self.free[class] };
+        self.free[class] = Some(block);
+    }
+}
+
+impl SystemAllocator {
+    fn with_heap<R>(&self, f: impl FnOnce(&mut Heap) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        let result = f(unsafe { &mut *self.heap.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+unsafe impl GlobalAlloc for SystemAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(class) = class_of(layout) {
+            return self.with_heap(|heap| heap.alloc(class));
+        }
+        // `mmap` only promises page alignment
+        if layout.align() > PAGE_SIZE {
+            return null_mut();
+        }
+        io::syscall(|out| syscall::mmap(layout.size(), PROT_READ | PROT_WRITE, out))
+            .unwrap_or(null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match class_of(layout) {
+            Some(class) => self.with_heap(|heap| heap.dealloc(ptr, class)),
+            None => {
+                io::syscall(|out| syscall::munmap(ptr, layout.size(), out)).ok();
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! The arguments and environment the kernel placed on the stack when the program started.
+
+use core::{
+    ffi::CStr,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(null_mut());
+static ENVP: AtomicPtr<*const u8> = AtomicPtr::new(null_mut());
+
+/// Records where the arguments are, for [`args`].
+///
+/// # Safety
+/// `argv` must point to `argc` NUL-terminated strings that live for the rest of the program.
+pub(crate) unsafe fn init_args(argc: usize, argv: *const *const u8) {
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv.cast_mut(), Ordering::Relaxed);
+}
+
+/// Records where the environment is, for [`vars`].
+///
+/// # Safety
+/// `envp` must be a null-terminated array of NUL-terminated strings that live for the rest of
+/// the program.
+pub(crate) unsafe fn init_env(envp: *const *const u8) {
+    ENVP.store(envp.cast_mut(), Ordering::Relaxed);
+}
+
+/// The null-terminated environment array, to hand on to child processes.
+pub(crate) fn envp() -> *const *const u8 {
+    ENVP.load(Ordering::Relaxed)
+}
+
+/// # Safety
+/// `ptr` must point to a NUL-terminated string that lives for the rest of the program.
+unsafe fn c_str(ptr: *const u8) -> &'static str {
+    // The kernel only passes on valid UTF-8
+    CStr::from_ptr(ptr.cast()).to_str().unwrap_or_default()
+}
+
+/// Iterator over the arguments of the program, starting with its name. Returned by [`args`].
+pub struct Args {
+    next: usize,
+    end: usize,
+}
+
+/// The arguments the program was started with.
+pub fn args() -> Args {
+    let end = if ARGV.load(Ordering::Relaxed).is_null() {
+        0
+    } else {
+        ARGC.load(Ordering::Relaxed)
+    };
+    Args { next: 0, end }
+}
+
+impl Iterator for Args {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        if self.next == self.end {
+            return None;
+        }
+        let arg = unsafe { c_str(*ARGV.load(Ordering::Relaxed).add(self.next)) };
+        self.next += 1;
+        Some(arg)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end - self.next;
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for Args {}
+
+/// Iterator over the environment variables of the program. Returned by [`vars`].
+pub struct Vars {
+    next: *const *const u8,
+}
+
+/// The environment variables of the program, as `(key, value)` pairs.
+pub fn vars() -> Vars {
+    Vars { next: envp() }
+}
+
+impl Iterator for Vars {
+    type Item = (&'static str, &'static str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.next.is_null() {
+                return None;
+            }
+            let entry = unsafe { *self.next };
+            if entry.is_null() {
+                self.next = core::ptr::null();
+                return None;
+            }
+            self.next = unsafe { self.next.add(1) };
+            // Skip anything that is not `KEY=VALUE`
+            if let Some(var) = unsafe { c_str(entry) }.split_once('=') {
+                return Some(var);
+            }
+        }
+    }
+}
+
+/// The value of the environment variable `key`, if it is set.
+pub fn var(key: &str) -> Option<&'static str> {
+    vars().find(|&(k, _)| k == key).map(|(_, v)| v)
+}
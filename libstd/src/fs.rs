@@ -0,0 +1,134 @@
+use kernel_uapi::syscall::{
+    self, FileKind, NAME_MAX, O_RDONLY, O_RDWR, O_WRONLY, SEEK_CUR, SEEK_END, SEEK_SET,
+};
+
+use crate::io::{self, SeekFrom};
+
+/// An open file descriptor. Closed when dropped.
+pub struct File {
+    fd: u32,
+}
+
+impl File {
+    /// Opens a file for reading.
+    pub fn open(path: &str) -> io::Result<File> {
+        Self::open_with(path, O_RDONLY)
+    }
+
+    /// Opens a file for writing.
+    pub fn create(path: &str) -> io::Result<File> {
+        Self::open_with(path, O_WRONLY)
+    }
+
+    /// Opens a file for reading and writing.
+    pub fn open_rw(path: &str) -> io::Result<File> {
+        Self::open_with(path, O_RDWR)
+    }
+
+    fn open_with(path: &str, flags: u32) -> io::Result<File> {
+        let fd = io::syscall(|out| syscall::open(path.as_ptr(), path.len(), flags, out))?;
+        Ok(File { fd })
+    }
+
+    pub fn fd(&self) -> u32 {
+        self.fd
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::read_fd(self.fd, buf)
+    }
+
+    /// Reads until `buf` is full or the end of the file is reached.
+    pub fn read_exact_or_eof(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut total = 0;
+        while total < buf.len() {
+            match self.read(&mut buf[total..])? {
+                0 => break,
+                n => total += n,
+            }
+        }
+        Ok(total)
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::write_fd(self.fd, buf)
+    }
+
+    pub fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(n) => (n as i64, SEEK_SET),
+            SeekFrom::Current(n) => (n, SEEK_CUR),
+            SeekFrom::End(n) => (n, SEEK_END),
+        };
+        io::syscall(|out| syscall::lseek(self.fd, offset, whence, out))
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        syscall::close(self.fd, None);
+    }
+}
+
+/// Returns an iterator over the entries of the directory at `path`.
+pub fn read_dir(path: &str) -> io::Result<ReadDir> {
+    Ok(ReadDir {
+        dir: File::open(path)?,
+        index: 0,
+        done: false,
+    })
+}
+
+pub struct ReadDir {
+    dir: File,
+    index: u64,
+    done: bool,
+}
+
+impl Iterator for ReadDir {
+    type Item = io::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut name = [0u8; NAME_MAX];
+        let result = io::syscall(|out| {
+            syscall::read_dir(self.dir.fd, self.index, name.as_mut_ptr(), name.len(), out)
+        });
+        match result {
+            Ok(entry) if entry.name_len == 0 => {
+                self.done = true;
+                None
+            }
+            Ok(entry) => {
+                self.index += 1;
+                Some(Ok(DirEntry {
+                    name,
+                    name_len: entry.name_len,
+                    kind: entry.kind,
+                }))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+pub struct DirEntry {
+    name: [u8; NAME_MAX],
+    name_len: usize,
+    kind: FileKind,
+}
+
+impl DirEntry {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("<invalid utf-8>")
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Directory
+    }
+}
@@ -0,0 +1,131 @@
+use core::mem::MaybeUninit;
+
+use kernel_uapi::syscall::{self, SyscallErrorCode};
+
+pub type Result<T> = core::result::Result<T, SyscallErrorCode>;
+
+/// Calls one of the `kernel_uapi` syscall wrappers and collects its output.
+pub(crate) fn syscall<T>(
+    f: impl FnOnce(Option<&mut MaybeUninit<T>>) -> SyscallErrorCode,
+) -> Result<T> {
+    let mut out = MaybeUninit::uninit();
+    match f(Some(&mut out)) {
+        SyscallErrorCode::Ok => Ok(unsafe { out.assume_init() }),
+        e => Err(e),
+    }
+}
+
+pub(crate) fn read_fd(fd: u32, buf: &mut [u8]) -> Result<usize> {
+    syscall(|out| syscall::read(fd, buf.as_mut_ptr(), buf.len(), out))
+}
+
+pub(crate) fn write_fd(fd: u32, buf: &[u8]) -> Result<usize> {
+    syscall(|out| syscall::write(fd, buf.as_ptr(), buf.len(), out))
+}
+
+pub(crate) fn write_all_fd(fd: u32, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        let n = write_fd(fd, buf)?;
+        buf = &buf[n..];
+    }
+    Ok(())
+}
+
+/// Position to seek to with [`crate::fs::File::seek`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+pub struct Stdin;
+pub struct Stdout;
+pub struct Stderr;
+
+pub fn stdin() -> Stdin {
+    Stdin
+}
+
+pub fn stdout() -> Stdout {
+    Stdout
+}
+
+pub fn stderr() -> Stderr {
+    Stderr
+}
+
+pub use kernel_uapi::syscall::{
+    Termios, ECHO, ICANON, ICRNL, ISIG, VEOF, VERASE, VINTR, VKILL, VSUSP,
+};
+
+impl Stdin {
+    /// Blocks until there is input, and reads it. If the terminal is in canonical mode, that is
+    /// up to the end of a typed line.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        read_fd(syscall::STDIN_FILENO, buf)
+    }
+
+    /// The settings of the terminal we read from.
+    pub fn termios(&self) -> Result<Termios> {
+        let mut termios = MaybeUninit::uninit();
+        match syscall::tcgetattr(syscall::STDIN_FILENO, termios.as_mut_ptr(), None) {
+            SyscallErrorCode::Ok => Ok(unsafe { termios.assume_init() }),
+            e => Err(e),
+        }
+    }
+
+    /// Changes the settings of the terminal we read from, like turning off `ICANON` to read
+    /// keys as they are typed.
+    pub fn set_termios(&mut self, termios: &Termios) -> Result<()> {
+        syscall(|out| syscall::tcsetattr(syscall::STDIN_FILENO, termios, out))
+    }
+
+    /// Gives the terminal to process group `pgid`, which then gets to read and receives the
+    /// signals typed. Only the group that has it may do this.
+    pub fn set_foreground(&mut self, pgid: u64) -> Result<()> {
+        syscall(|out| syscall::tcsetpgrp(syscall::STDIN_FILENO, pgid, out))
+    }
+
+    /// Switches the keyboard to raw scancodes, read with [`Stdin::read_scancode`], or back to
+    /// lines read with [`Stdin::read`].
+    pub fn set_raw_mode(&mut self, raw: bool) -> Result<()> {
+        let mode = if raw {
+            syscall::KBD_MODE_RAW
+        } else {
+            syscall::KBD_MODE_LINE
+        };
+        syscall(|out| syscall::set_kbd_mode(mode, out))
+    }
+
+    /// Blocks until a key is pressed or released, and returns its scancode.
+    // `extern "C"` functions don't implement `FnOnce`, so the closure is needed
+    #[allow(clippy::redundant_closure)]
+    pub fn read_scancode(&mut self) -> Result<u8> {
+        syscall(|out| syscall::get_kbd_code(out))
+    }
+}
+
+impl Stdout {
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        write_fd(syscall::STDOUT_FILENO, buf)
+    }
+}
+
+impl Stderr {
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        write_fd(syscall::STDERR_FILENO, buf)
+    }
+}
+
+impl core::fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_all_fd(syscall::STDOUT_FILENO, s.as_bytes()).map_err(|_| core::fmt::Error)
+    }
+}
+
+impl core::fmt::Write for Stderr {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_all_fd(syscall::STDERR_FILENO, s.as_bytes()).map_err(|_| core::fmt::Error)
+    }
+}
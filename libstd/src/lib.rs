@@ -2,9 +2,20 @@
 #![feature(c_size_t)]
 #![allow(internal_features)]
 #![feature(lang_items)]
+#![feature(naked_functions)]
 
+extern crate alloc as alloc_crate;
+
+pub use alloc_crate::{borrow, boxed, collections, format, rc, string, vec};
 pub use core::*;
 
+pub mod alloc;
+pub mod env;
+pub mod fs;
+pub mod io;
+pub mod process;
+pub mod thread;
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     loop {
@@ -12,8 +23,27 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
     }
 }
 
+/// Entry point. The kernel starts us with `argc`, `argv`, `envp` and the auxiliary vector on the
+/// stack, which is 16-byte aligned rather than set up for a call.
+#[naked]
 #[no_mangle]
 unsafe extern "C" fn _start() -> ! {
+    core::arch::asm!(
+        "mov rdi, rsp",
+        "call {start}",
+        "ud2",
+        start = sym start,
+        options(noreturn)
+    )
+}
+
+unsafe extern "C" fn start(sp: *const usize) -> ! {
+    let argc = *sp;
+    let argv = sp.add(1).cast::<*const u8>();
+    let envp = argv.add(argc + 1);
+    env::init_args(argc, argv);
+    env::init_env(envp);
+
     let exit_code = main() as i8;
     loop {
         kernel_uapi::syscall::exit(exit_code, None);
@@ -21,6 +51,7 @@ unsafe extern "C" fn _start() -> ! {
 }
 
 extern "C" {
+    // Our target has `main-needs-argc-argv` off, so the arguments never make it through here
     fn main() -> i32;
 }
 
@@ -29,11 +60,3 @@ fn lang_start<T>(main: fn() -> T, _argc: isize, _argv: *const *const u8, _: u8)
     main();
     0
 }
-
-pub mod process {
-    pub fn abort() -> ! {
-        loop {
-            kernel_uapi::syscall::exit(0, None);
-        }
-    }
-}
@@ -0,0 +1,120 @@
+use kernel_uapi::syscall::{self, SyscallErrorCode};
+
+use crate::{env, io};
+
+const MAX_ARGS: usize = 64;
+const ARGV_BYTES: usize = 4096;
+
+/// Builds a null-terminated array of NUL-terminated strings in fixed-size buffers, and hands
+/// it to `f`.
+fn with_argv<T>(args: &[&str], f: impl FnOnce(*const *const u8) -> io::Result<T>) -> io::Result<T> {
+    if args.len() > MAX_ARGS {
+        return Err(SyscallErrorCode::InvalidArgumentError);
+    }
+    let mut strings = [0u8; ARGV_BYTES];
+    let mut offsets = [0usize; MAX_ARGS];
+    let mut used = 0;
+    for (i, arg) in args.iter().enumerate() {
+        let end = used + arg.len() + 1;
+        if end > ARGV_BYTES || arg.as_bytes().contains(&0) {
+            return Err(SyscallErrorCode::InvalidArgumentError);
+        }
+        strings[used..end - 1].copy_from_slice(arg.as_bytes());
+        offsets[i] = used;
+        used = end;
+    }
+
+    let mut ptrs = [core::ptr::null::<u8>(); MAX_ARGS + 1];
+    for (ptr, offset) in ptrs.iter_mut().zip(&offsets[..args.len()]) {
+        *ptr = strings[*offset..].as_ptr();
+    }
+    f(ptrs.as_ptr())
+}
+
+/// A process started with [`spawn`].
+pub struct Child {
+    pid: u64,
+}
+
+impl Child {
+    pub fn id(&self) -> u64 {
+        self.pid
+    }
+
+    /// Blocks until the child exits and returns its exit code. If it is stopped instead, returns
+    /// [`WAIT_STOPPED`] and keeps the child around to be waited for again.
+    pub fn wait(&self) -> io::Result<i8> {
+        io::syscall(|out| syscall::wait(self.pid, out))
+    }
+
+    /// Sends `signal`, one of the `SIG*` constants, to the child.
+    pub fn kill(&self, signal: u32) -> io::Result<()> {
+        kill(self.pid, signal)
+    }
+}
+
+pub use kernel_uapi::syscall::{SIGCONT, SIGINT, SIGKILL, SIGTSTP, WAIT_STOPPED};
+
+/// Sends `signal`, one of the `SIG*` constants, to process `pid`.
+pub fn kill(pid: u64, signal: u32) -> io::Result<()> {
+    io::syscall(|out| syscall::kill(pid, signal, out))
+}
+
+/// Starts the program at `path` as a child process, with the same environment as ours.
+pub fn spawn(path: &str, args: &[&str]) -> io::Result<Child> {
+    let pid = with_argv(args, |argv| {
+        io::syscall(|out| syscall::spawn(path.as_ptr(), path.len(), argv, env::envp(), out))
+    })?;
+    Ok(Child { pid })
+}
+
+/// Starts the program at `path` as a child process, with `env` as its environment. Each entry
+/// has the form `KEY=VALUE`.
+pub fn spawn_with_env(path: &str, args: &[&str], env: &[&str]) -> io::Result<Child> {
+    let pid = with_argv(args, |argv| {
+        with_argv(env, |envp| {
+            io::syscall(|out| syscall::spawn(path.as_ptr(), path.len(), argv, envp, out))
+        })
+    })?;
+    Ok(Child { pid })
+}
+
+/// Replaces the current program with the one at `path`, keeping our environment. Only returns
+/// on failure.
+pub fn exec(path: &str, args: &[&str]) -> SyscallErrorCode {
+    let result = with_argv(args, |argv| {
+        io::syscall(|out| syscall::exec(path.as_ptr(), path.len(), argv, env::envp(), out))
+    });
+    match result {
+        Ok(()) => unreachable!("`exec` returned without an error"),
+        Err(e) => e,
+    }
+}
+
+/// Creates a copy of the current process that continues from here, sharing our open files.
+/// Returns the child in the parent, and `None` in the child.
+// `extern "C" fn`s don't implement `FnOnce`, so the closure is needed
+#[allow(clippy::redundant_closure)]
+pub fn fork() -> io::Result<Option<Child>> {
+    let pid = io::syscall(|out| syscall::fork(out))?;
+    Ok((pid != 0).then_some(Child { pid }))
+}
+
+pub use kernel_uapi::syscall::{PRIORITY_HIGH, PRIORITY_LOW, PRIORITY_NORMAL};
+
+/// Sets the scheduling priority of the current process to one of the `PRIORITY_*` levels.
+pub fn set_priority(priority: u32) -> io::Result<()> {
+    io::syscall(|out| syscall::set_priority(priority, out))
+}
+
+pub fn exit(code: i8) -> ! {
+    loop {
+        syscall::exit(code, None);
+    }
+}
+
+pub fn abort() -> ! {
+    loop {
+        syscall::exit(0, None);
+    }
+}
@@ -0,0 +1,7 @@
+use core::time::Duration;
+
+/// Puts the current process to sleep for at least `dur`.
+pub fn sleep(dur: Duration) {
+    let ns = u64::try_from(dur.as_nanos()).unwrap_or(u64::MAX);
+    kernel_uapi::syscall::nanosleep(ns, None);
+}